@@ -73,8 +73,9 @@ void main() {
   // which the mobile device is trying to proxy traffic. Assuming it is
   // running the `socks-forwarder`, the managed machine should already be:
   // - Advertising `machineToManage` as a readable characteristic
-  // - Adveristing a writable characteristic (encrypted) to which the mobile
-  //   device will need to write its `mobileDevice` value
+  // - Adveristing a writable characteristic to which the mobile device will
+  //   need to write its `mobileDevice` value (the write is only encrypted,
+  //   pairing first, if the `socks-forwarder` security level requires it)
   // - Ready to establish an L2CAP connection for SOCKS forwarding once a value
   //   is written to the above characteristic
   //
@@ -0,0 +1,470 @@
+//! Defines what the peripheral advertises: the advertising parameters and a compact record of the
+//! machine part ID and state of the SOCKS forwarder, from which mobile devices can pick out the
+//! right machine while scanning (without connecting to read its characteristics.)
+//!
+//! Advertising follows a power-saving schedule: fast for a window after start, a lost bridge or a
+//! wake request, then slow, and (optionally) not at all once idle for long enough.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use bluer::adv::{Advertisement, Type};
+use log::info;
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+use tokio::time::Duration;
+use uuid::Uuid;
+
+use crate::config::Values;
+use crate::env::{
+    ADVERTISING_FAST_WINDOW_ENV_VAR, ADVERTISING_INTERVAL_ENV_VAR,
+    ADVERTISING_MANUFACTURER_DATA_ENV_VAR, ADVERTISING_QUIET_AFTER_ENV_VAR,
+    ADVERTISING_SERVICE_DATA_ENV_VAR, ADVERTISING_SLOW_INTERVAL_ENV_VAR,
+    ADVERTISING_TX_POWER_ENV_VAR,
+};
+use crate::status::State;
+
+/// Company identifier under which the record is advertised as manufacturer data (0xFFFF is
+/// reserved by the Bluetooth SIG for testing and internal use.)
+const MANUFACTURER_ID: u16 = 0xFFFF;
+
+/// Number of bytes of the SHA-256 of the machine part ID included in the record.
+const MACHINE_PART_ID_HASH_LEN: usize = 8;
+
+/// Shortest advertising interval allowed by the Bluetooth specification.
+const MIN_ADVERTISING_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Default advertising interval range.
+const DEFAULT_ADVERTISING_INTERVAL: (Duration, Duration) =
+    (Duration::from_millis(20), Duration::from_millis(100));
+
+/// Default slow advertising interval range.
+const DEFAULT_SLOW_ADVERTISING_INTERVAL: (Duration, Duration) =
+    (Duration::from_millis(1000), Duration::from_millis(1500));
+
+/// Default length of the fast advertising window.
+const DEFAULT_FAST_WINDOW: Duration = Duration::from_secs(60);
+
+/// How fast (if at all) to advertise.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Advertising at the (fast) advertising interval.
+    Fast,
+    /// Advertising at the slow advertising interval.
+    Slow,
+    /// Not advertising.
+    Quiet,
+}
+
+/// How the peripheral advertises.
+#[derive(Clone, Debug)]
+pub struct AdvertisingConfig {
+    /// Interval range used during the fast window.
+    pub min_interval: Duration,
+    pub max_interval: Duration,
+    /// Interval range used after the fast window.
+    pub slow_min_interval: Duration,
+    pub slow_max_interval: Duration,
+    /// How long to advertise fast after start, a lost bridge or a wake request.
+    pub fast_window: Duration,
+    /// How long after start, a lost bridge or a wake request to stop advertising if no bridge is
+    /// active (never if unset.)
+    pub quiet_after: Option<Duration>,
+    /// Requested TX power in dBm (left to the adapter if unset.)
+    pub tx_power: Option<i16>,
+    /// Whether to include the record as manufacturer data.
+    pub manufacturer_data: bool,
+    /// Whether to include the record as service data of the Viam service.
+    pub service_data: bool,
+}
+
+impl AdvertisingConfig {
+    /// Reads the advertising configuration from `ADVERTISING_INTERVAL_ENV_VAR` and
+    /// `ADVERTISING_SLOW_INTERVAL_ENV_VAR` ("<min>-<max>" in milliseconds; default to "20-100" and
+    /// "1000-1500"), `ADVERTISING_FAST_WINDOW_ENV_VAR` and `ADVERTISING_QUIET_AFTER_ENV_VAR`
+    /// (seconds; default to 60 and never), `ADVERTISING_TX_POWER_ENV_VAR` (dBm),
+    /// `ADVERTISING_MANUFACTURER_DATA_ENV_VAR` and `ADVERTISING_SERVICE_DATA_ENV_VAR` (both
+    /// default to "false", as the record does not fit in a legacy advertisement.)
+    pub fn from_values(values: &Values) -> Result<Self> {
+        let (min_interval, max_interval) = match values.var(ADVERTISING_INTERVAL_ENV_VAR) {
+            Ok(interval) => parse_interval(ADVERTISING_INTERVAL_ENV_VAR, &interval)?,
+            Err(_) => DEFAULT_ADVERTISING_INTERVAL,
+        };
+        let (slow_min_interval, slow_max_interval) =
+            match values.var(ADVERTISING_SLOW_INTERVAL_ENV_VAR) {
+                Ok(interval) => parse_interval(ADVERTISING_SLOW_INTERVAL_ENV_VAR, &interval)?,
+                Err(_) => DEFAULT_SLOW_ADVERTISING_INTERVAL,
+            };
+        let fast_window = values
+            .secs(ADVERTISING_FAST_WINDOW_ENV_VAR)?
+            .unwrap_or(DEFAULT_FAST_WINDOW);
+        let quiet_after = values.secs(ADVERTISING_QUIET_AFTER_ENV_VAR)?;
+        if quiet_after.is_some_and(|quiet_after| quiet_after < fast_window) {
+            return Err(anyhow!(
+                "{ADVERTISING_QUIET_AFTER_ENV_VAR} must be no less than {ADVERTISING_FAST_WINDOW_ENV_VAR}"
+            ));
+        }
+        let tx_power = values.integer(ADVERTISING_TX_POWER_ENV_VAR, -127..=20)?;
+        Ok(AdvertisingConfig {
+            min_interval,
+            max_interval,
+            slow_min_interval,
+            slow_max_interval,
+            fast_window,
+            quiet_after,
+            tx_power,
+            manufacturer_data: values.flag(ADVERTISING_MANUFACTURER_DATA_ENV_VAR, false)?,
+            service_data: values.flag(ADVERTISING_SERVICE_DATA_ENV_VAR, false)?,
+        })
+    }
+
+    /// Logs the advertising configuration.
+    pub fn log(&self) {
+        info!(
+            "Advertising every {:?}-{:?} for {:?}, then every {:?}-{:?}{} (TX power {}), record in manufacturer data: {}, in service data: {}",
+            self.min_interval,
+            self.max_interval,
+            self.fast_window,
+            self.slow_min_interval,
+            self.slow_max_interval,
+            self.quiet_after
+                .map(|quiet_after| format!(" until idle for {quiet_after:?}"))
+                .unwrap_or_default(),
+            self.tx_power
+                .map(|dbm| format!("{dbm} dBm"))
+                .unwrap_or_else(|| "adapter default".to_string()),
+            self.manufacturer_data,
+            self.service_data
+        );
+    }
+
+    /// Returns whether advertisements carry the record.
+    pub fn carries_record(&self) -> bool {
+        self.manufacturer_data || self.service_data
+    }
+
+    /// Returns the phase to advertise in with the SOCKS forwarder in `state`, `elapsed` after
+    /// start, a lost bridge or a wake request, and how long until the phase may change (if it
+    /// will.) Advertising never goes quiet while bridged, so that standby mobile devices can
+    /// still register.
+    pub fn phase(&self, state: State, elapsed: Duration) -> (Phase, Option<Duration>) {
+        if elapsed < self.fast_window {
+            return (Phase::Fast, Some(self.fast_window - elapsed));
+        }
+        match self.quiet_after {
+            Some(_) if state == State::Bridged => (Phase::Slow, None),
+            Some(quiet_after) if elapsed < quiet_after => {
+                (Phase::Slow, Some(quiet_after - elapsed))
+            }
+            Some(_) => (Phase::Quiet, None),
+            None => (Phase::Slow, None),
+        }
+    }
+
+    /// Returns an advertisement of `advertised_ble_name` and `svc_uuid` in `phase` carrying the
+    /// record of `machine_part_id` and `state` as configured, or `None` if `phase` is quiet.
+    pub fn advertisement(
+        &self,
+        phase: Phase,
+        advertised_ble_name: &str,
+        svc_uuid: Uuid,
+        machine_part_id: &str,
+        state: State,
+    ) -> Option<Advertisement> {
+        let (min_interval, max_interval) = match phase {
+            Phase::Fast => (self.min_interval, self.max_interval),
+            Phase::Slow => (self.slow_min_interval, self.slow_max_interval),
+            Phase::Quiet => return None,
+        };
+        let record = record(machine_part_id, state);
+        let mut le_advertisement = Advertisement {
+            advertisement_type: Type::Peripheral,
+            service_uuids: vec![svc_uuid].into_iter().collect(),
+            discoverable: Some(true),
+            min_interval: Some(min_interval),
+            max_interval: Some(max_interval),
+            tx_power: self.tx_power,
+            local_name: Some(advertised_ble_name.to_string()),
+            ..Default::default()
+        };
+        if self.manufacturer_data {
+            le_advertisement.manufacturer_data =
+                BTreeMap::from([(MANUFACTURER_ID, record.clone())]);
+        }
+        if self.service_data {
+            le_advertisement.service_data = BTreeMap::from([(svc_uuid, record)]);
+        }
+        Some(le_advertisement)
+    }
+}
+
+/// Wakes advertising up from its slow or quiet phase, restarting the fast window. Cheap to clone;
+/// clones wake the same advertisements.
+#[derive(Clone, Debug)]
+pub struct WakeHandle(Arc<watch::Sender<()>>);
+
+impl Default for WakeHandle {
+    fn default() -> Self {
+        WakeHandle(Arc::new(watch::Sender::new(())))
+    }
+}
+
+impl WakeHandle {
+    /// Restarts the fast window of every advertisement.
+    pub fn wake(&self) {
+        info!("Waking up advertising");
+        self.0.send_replace(());
+    }
+
+    /// Returns a receiver that is notified of every wake request.
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.0.subscribe()
+    }
+}
+
+/// Returns the record advertised for `machine_part_id` and `state`: the state code (see
+/// `State::code`) followed by the first `MACHINE_PART_ID_HASH_LEN` bytes of the SHA-256 of the
+/// machine part ID.
+pub fn record(machine_part_id: &str, state: State) -> Vec<u8> {
+    let machine_part_id_hash = Sha256::digest(machine_part_id.as_bytes());
+    let mut record = vec![state.code()];
+    record.extend_from_slice(&machine_part_id_hash[..MACHINE_PART_ID_HASH_LEN]);
+    record
+}
+
+/// Parses an advertising interval range ("<min>-<max>" in milliseconds) read from `env_var`.
+fn parse_interval(env_var: &str, interval: &str) -> Result<(Duration, Duration)> {
+    let invalid = || {
+        anyhow!(
+            "invalid value \"{interval}\" for {env_var}; expected \"<min>-<max>\" in milliseconds"
+        )
+    };
+    let (min, max) = interval.trim().split_once('-').ok_or_else(invalid)?;
+    let min = Duration::from_millis(min.trim().parse().map_err(|_| invalid())?);
+    let max = Duration::from_millis(max.trim().parse().map_err(|_| invalid())?);
+    if min < MIN_ADVERTISING_INTERVAL || min > max {
+        return Err(anyhow!(
+            "invalid value \"{interval}\" for {env_var}; the minimum must be at least {MIN_ADVERTISING_INTERVAL:?} and no more than the maximum"
+        ));
+    }
+    Ok((min, max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Source;
+
+    /// Longest advertising data that fits in a legacy advertising PDU.
+    const MAX_LEGACY_ADVERTISING_DATA_LEN: usize = 31;
+
+    const SVC_UUID: Uuid = uuid::uuid!("79cf4eca-116a-4ded-8426-fb83e53bc1d7");
+
+    /// Returns the length of the advertising data bluez encodes for `advertisement`: the flags,
+    /// the (128-bit) service UUID list, manufacturer data and service data. The local name is left
+    /// out, as bluez shortens it to fit.
+    fn encoded_len(advertisement: &Advertisement) -> usize {
+        let flags = 3;
+        let service_uuids = match advertisement.service_uuids.len() {
+            0 => 0,
+            n => 2 + 16 * n,
+        };
+        let manufacturer_data: usize = advertisement
+            .manufacturer_data
+            .values()
+            .map(|data| 2 + 2 + data.len())
+            .sum();
+        let service_data: usize = advertisement
+            .service_data
+            .values()
+            .map(|data| 2 + 16 + data.len())
+            .sum();
+        flags + service_uuids + manufacturer_data + service_data
+    }
+
+    fn advertisement(config: &AdvertisingConfig) -> Advertisement {
+        config
+            .advertisement(Phase::Fast, "machine", SVC_UUID, "part", State::Idle)
+            .unwrap()
+    }
+
+    #[test]
+    fn default_advertisement_fits_in_legacy_advertisement() {
+        let config = AdvertisingConfig::from_values(&Values::default()).unwrap();
+        assert!(!config.carries_record());
+        assert!(encoded_len(&advertisement(&config)) <= MAX_LEGACY_ADVERTISING_DATA_LEN);
+    }
+
+    #[test]
+    fn record_does_not_fit_in_legacy_advertisement() {
+        let mut config = AdvertisingConfig::from_values(&Values::default()).unwrap();
+        config.manufacturer_data = true;
+        assert_eq!(encoded_len(&advertisement(&config)), 34);
+        config.manufacturer_data = false;
+        config.service_data = true;
+        assert!(encoded_len(&advertisement(&config)) > MAX_LEGACY_ADVERTISING_DATA_LEN);
+    }
+
+    #[test]
+    fn record_is_state_code_and_machine_part_id_hash() {
+        let record = record("part", State::Bridged);
+        assert_eq!(record.len(), 1 + MACHINE_PART_ID_HASH_LEN);
+        assert_eq!(record[0], State::Bridged.code());
+        assert_eq!(
+            record[1..],
+            Sha256::digest(b"part")[..MACHINE_PART_ID_HASH_LEN]
+        );
+    }
+
+    fn millis(min: u64, max: u64) -> (Duration, Duration) {
+        (Duration::from_millis(min), Duration::from_millis(max))
+    }
+
+    #[test]
+    fn intervals_are_parsed() {
+        let env_var = ADVERTISING_INTERVAL_ENV_VAR;
+        assert_eq!(parse_interval(env_var, "20-100").unwrap(), millis(20, 100));
+        assert_eq!(
+            parse_interval(env_var, " 1000 - 1500 ").unwrap(),
+            millis(1000, 1500)
+        );
+        assert_eq!(parse_interval(env_var, "50-50").unwrap(), millis(50, 50));
+    }
+
+    #[test]
+    fn invalid_intervals_are_rejected() {
+        let env_var = ADVERTISING_INTERVAL_ENV_VAR;
+        for interval in ["", "100", "-100", "20-", "a-100", "20-1e3", "20--100"] {
+            let error = parse_interval(env_var, interval).unwrap_err().to_string();
+            assert!(error.contains("expected"), "{interval}: {error}");
+        }
+        // Below the shortest interval the Bluetooth specification allows.
+        assert!(parse_interval(env_var, "10-100").is_err());
+        // Minimum above the maximum.
+        assert!(parse_interval(env_var, "200-100").is_err());
+    }
+
+    #[test]
+    fn advertising_config_defaults() {
+        let config = AdvertisingConfig::from_values(&Values::default()).unwrap();
+        assert_eq!(
+            (config.min_interval, config.max_interval),
+            DEFAULT_ADVERTISING_INTERVAL
+        );
+        assert_eq!(
+            (config.slow_min_interval, config.slow_max_interval),
+            DEFAULT_SLOW_ADVERTISING_INTERVAL
+        );
+        assert_eq!(config.fast_window, DEFAULT_FAST_WINDOW);
+        assert_eq!(config.quiet_after, None);
+        assert_eq!(config.tx_power, None);
+    }
+
+    #[test]
+    fn invalid_advertising_config_is_rejected() {
+        for (env_var, value) in [
+            (ADVERTISING_INTERVAL_ENV_VAR, "fast"),
+            (ADVERTISING_SLOW_INTERVAL_ENV_VAR, "1500-1000"),
+            (ADVERTISING_FAST_WINDOW_ENV_VAR, "a minute"),
+            (ADVERTISING_TX_POWER_ENV_VAR, "21"),
+            (ADVERTISING_MANUFACTURER_DATA_ENV_VAR, "1"),
+        ] {
+            let mut values = Values::default();
+            values.insert(env_var, value.to_string(), Source::Cli);
+            assert!(
+                AdvertisingConfig::from_values(&values).is_err(),
+                "{env_var}={value}"
+            );
+        }
+
+        // Quiet before the fast window ends.
+        let mut values = Values::default();
+        values.insert(
+            ADVERTISING_FAST_WINDOW_ENV_VAR,
+            "60".to_string(),
+            Source::Cli,
+        );
+        values.insert(
+            ADVERTISING_QUIET_AFTER_ENV_VAR,
+            "30".to_string(),
+            Source::Cli,
+        );
+        assert!(AdvertisingConfig::from_values(&values).is_err());
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    /// Returns the default advertising configuration with `quiet_after`.
+    fn config_quiet_after(quiet_after: Option<Duration>) -> AdvertisingConfig {
+        AdvertisingConfig {
+            quiet_after,
+            ..AdvertisingConfig::from_values(&Values::default()).unwrap()
+        }
+    }
+
+    #[test]
+    fn phase_is_fast_during_fast_window() {
+        let config = config_quiet_after(Some(secs(300)));
+        assert_eq!(
+            config.phase(State::Idle, Duration::ZERO),
+            (Phase::Fast, Some(DEFAULT_FAST_WINDOW))
+        );
+        assert_eq!(
+            config.phase(State::Bridged, secs(59)),
+            (Phase::Fast, Some(secs(1)))
+        );
+    }
+
+    #[test]
+    fn phase_is_slow_after_fast_window_until_quiet() {
+        let config = config_quiet_after(Some(secs(300)));
+        assert_eq!(
+            config.phase(State::Idle, secs(60)),
+            (Phase::Slow, Some(secs(240)))
+        );
+        assert_eq!(
+            config.phase(State::Scanning, secs(299)),
+            (Phase::Slow, Some(secs(1)))
+        );
+        assert_eq!(config.phase(State::Idle, secs(300)), (Phase::Quiet, None));
+        assert_eq!(config.phase(State::Idle, secs(3600)), (Phase::Quiet, None));
+    }
+
+    #[test]
+    fn phase_never_goes_quiet_while_bridged() {
+        let config = config_quiet_after(Some(secs(300)));
+        assert_eq!(config.phase(State::Bridged, secs(60)), (Phase::Slow, None));
+        assert_eq!(
+            config.phase(State::Bridged, secs(3600)),
+            (Phase::Slow, None)
+        );
+    }
+
+    #[test]
+    fn phase_stays_slow_without_quiet_after() {
+        let config = config_quiet_after(None);
+        assert_eq!(config.phase(State::Idle, secs(60)), (Phase::Slow, None));
+        assert_eq!(config.phase(State::Idle, secs(3600)), (Phase::Slow, None));
+    }
+
+    #[test]
+    fn quiet_phase_has_no_advertisement() {
+        let config = config_quiet_after(None);
+        assert!(config
+            .advertisement(Phase::Quiet, "machine", SVC_UUID, "part", State::Idle)
+            .is_none());
+        let slow = config
+            .advertisement(Phase::Slow, "machine", SVC_UUID, "part", State::Idle)
+            .unwrap();
+        assert_eq!(
+            (slow.min_interval, slow.max_interval),
+            (
+                Some(DEFAULT_SLOW_ADVERTISING_INTERVAL.0),
+                Some(DEFAULT_SLOW_ADVERTISING_INTERVAL.1)
+            )
+        );
+    }
+}
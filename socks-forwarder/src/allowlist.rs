@@ -0,0 +1,182 @@
+//! Defines the optional allowlist of mobile devices permitted to register with this forwarder.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use bluer::Address;
+use tokio::fs;
+
+use crate::state::write_atomically;
+
+/// Path to the allowlist file. Each line is a mobile device name or a Bluetooth address; blank
+/// lines and lines starting with `#` are ignored.
+const ALLOWLIST_FP: &str = "/etc/socks-forwarder/allowlist";
+
+/// Mobile device names and addresses permitted to register. An empty allowlist permits any
+/// mobile device.
+#[derive(Debug)]
+pub struct Allowlist {
+    path: PathBuf,
+    entries: Vec<String>,
+}
+
+impl Allowlist {
+    /// Loads the allowlist from `ALLOWLIST_FP` (see `load_from`.)
+    pub async fn load() -> Result<Self> {
+        Self::load_from(Path::new(ALLOWLIST_FP)).await
+    }
+
+    /// Loads the allowlist from `path`, to which it is also saved. A missing file results in an
+    /// empty allowlist.
+    pub async fn load_from(path: &Path) -> Result<Self> {
+        let contents = match fs::read_to_string(path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(anyhow!("could not read allowlist from {path:#?}: {e}"));
+            }
+        };
+        let entries = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect();
+        Ok(Allowlist {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    /// Returns whether the mobile device named `name` writing from `address` may register.
+    pub fn permits(&self, name: &str, address: Address) -> bool {
+        self.entries.is_empty()
+            || self.entries.iter().any(|entry| {
+                entry == name || Address::from_str(entry).is_ok_and(|addr| addr == address)
+            })
+    }
+
+    /// Returns whether `address` is in the allowlist (names are not considered.)
+    pub fn lists_address(&self, address: Address) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| Address::from_str(entry).is_ok_and(|addr| addr == address))
+    }
+
+    /// Returns the entries in the allowlist.
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    /// Adds `entry` to the allowlist and saves it.
+    pub async fn add(&mut self, entry: &str) -> Result<()> {
+        let entry = entry.trim();
+        if entry.is_empty() || entry.starts_with('#') {
+            return Err(anyhow!("\"{entry}\" is not a valid allowlist entry"));
+        }
+        if self.entries.iter().any(|e| e == entry) {
+            return Err(anyhow!("\"{entry}\" is already in the allowlist"));
+        }
+        self.entries.push(entry.to_string());
+        self.save().await
+    }
+
+    /// Removes `entry` from the allowlist and saves it.
+    pub async fn remove(&mut self, entry: &str) -> Result<()> {
+        let len = self.entries.len();
+        self.entries.retain(|e| e != entry.trim());
+        if self.entries.len() == len {
+            return Err(anyhow!("\"{entry}\" is not in the allowlist"));
+        }
+        self.save().await
+    }
+
+    /// Atomically writes the allowlist to its path (see `state::write_atomically`.)
+    async fn save(&self) -> Result<()> {
+        let mut contents = String::from(
+            "# Mobile device names or Bluetooth addresses permitted to register (one per line).\n",
+        );
+        for entry in &self.entries {
+            contents.push_str(entry);
+            contents.push('\n');
+        }
+        write_atomically(&self.path, contents.as_bytes())
+            .await
+            .map_err(|e| anyhow!("could not save allowlist to {:#?}: {e}", self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: Address = Address::new([0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+    const OTHER_ADDRESS: Address = Address::new([0xcb, 0xa9, 0x87, 0x65, 0x43, 0x21]);
+
+    async fn allowlist(contents: &str) -> (tempfile::TempDir, Allowlist) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("allowlist");
+        std::fs::write(&path, contents).unwrap();
+        let allowlist = Allowlist::load_from(&path).await.unwrap();
+        (dir, allowlist)
+    }
+
+    #[tokio::test]
+    async fn comments_and_blank_lines_are_ignored() {
+        let (_dir, allowlist) =
+            allowlist("# Phones\n\n  phone  \n12:34:56:78:9A:BC\n   # tablet\nnot an address\n")
+                .await;
+        assert_eq!(
+            allowlist.entries(),
+            ["phone", "12:34:56:78:9A:BC", "not an address"]
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_allowlist_is_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let allowlist = Allowlist::load_from(&dir.path().join("allowlist"))
+            .await
+            .unwrap();
+        assert!(allowlist.entries().is_empty());
+    }
+
+    #[tokio::test]
+    async fn empty_allowlist_permits_any_mobile_device() {
+        let (_dir, allowlist) = allowlist("# Nothing yet\n").await;
+        assert!(allowlist.permits("phone", ADDRESS));
+        assert!(!allowlist.lists_address(ADDRESS));
+    }
+
+    #[tokio::test]
+    async fn allowlist_permits_listed_names_and_addresses() {
+        let (_dir, allowlist) = allowlist("phone\n12:34:56:78:9a:bc\nAA:BB\n").await;
+        assert!(allowlist.permits("phone", OTHER_ADDRESS));
+        assert!(allowlist.permits("tablet", ADDRESS));
+        assert!(!allowlist.permits("tablet", OTHER_ADDRESS));
+        assert!(allowlist.lists_address(ADDRESS));
+        assert!(!allowlist.lists_address(OTHER_ADDRESS));
+        // Entries that are not valid addresses (such as "AA:BB") are only matched as names.
+        assert!(allowlist.permits("AA:BB", OTHER_ADDRESS));
+        assert!(!allowlist.permits("AA:BB:CC:DD:EE:FF", OTHER_ADDRESS));
+    }
+
+    #[tokio::test]
+    async fn entries_are_added_and_removed() {
+        let (dir, mut allowlist) = allowlist("phone\n").await;
+        allowlist.add(" tablet ").await.unwrap();
+        assert!(allowlist.add("tablet").await.is_err());
+        assert!(allowlist.add("  ").await.is_err());
+        assert!(allowlist.add("# tablet").await.is_err());
+        assert!(allowlist.remove("watch").await.is_err());
+        allowlist.remove("phone").await.unwrap();
+
+        let path = dir.path().join("allowlist");
+        assert_eq!(
+            Allowlist::load_from(&path).await.unwrap().entries(),
+            ["tablet"]
+        );
+        assert!(!dir.path().join("allowlist.tmp").exists());
+    }
+}
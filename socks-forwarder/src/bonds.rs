@@ -0,0 +1,84 @@
+//! Defines management of the mobile devices bluez has bonded with (paired.)
+
+use anyhow::{anyhow, Result};
+use bluer::{Address, Session};
+use log::info;
+
+use crate::state::Store;
+
+/// A device bluez knows of through one of the adapters.
+pub struct Bond {
+    pub adapter_name: String,
+    pub address: Address,
+    pub alias: String,
+    pub paired: bool,
+    pub trusted: bool,
+    pub connected: bool,
+}
+
+/// Returns every paired or trusted device on every adapter.
+pub async fn list(session: &Session) -> Result<Vec<Bond>> {
+    let mut bonds = Vec::new();
+    for adapter_name in session.adapter_names().await? {
+        let adapter = session.adapter(&adapter_name)?;
+        for address in adapter.device_addresses().await? {
+            let device = adapter.device(address)?;
+            let paired = device.is_paired().await?;
+            let trusted = device.is_trusted().await?;
+            if !paired && !trusted {
+                continue;
+            }
+            bonds.push(Bond {
+                adapter_name: adapter_name.clone(),
+                address,
+                alias: device.alias().await?,
+                paired,
+                trusted,
+                connected: device.is_connected().await?,
+            });
+        }
+    }
+    Ok(bonds)
+}
+
+/// Removes the device at `address` from every adapter that knows of it (disconnecting it and
+/// deleting any bond) and forgets it in `state`.
+pub async fn remove(session: &Session, state: &Store, address: Address) -> Result<()> {
+    let mut removed = false;
+    for adapter_name in session.adapter_names().await? {
+        let adapter = session.adapter(&adapter_name)?;
+        if adapter.device_addresses().await?.contains(&address) {
+            adapter.remove_device(address).await?;
+            info!("Removed device {address} from adapter {adapter_name}");
+            removed = true;
+        }
+    }
+    let forgotten = state.lock().forget(address);
+    if forgotten {
+        state.save_or_warn().await;
+    }
+    if !removed && !forgotten {
+        return Err(anyhow!("device {address} is not known"));
+    }
+    Ok(())
+}
+
+/// Removes every paired or trusted device from every adapter and forgets all mobile devices in
+/// `state`. Returns the addresses of the removed devices.
+pub async fn purge(session: &Session, state: &Store) -> Result<Vec<Address>> {
+    let mut removed = Vec::new();
+    for bond in list(session).await? {
+        session
+            .adapter(&bond.adapter_name)?
+            .remove_device(bond.address)
+            .await?;
+        info!(
+            "Removed device {} from adapter {}",
+            bond.address, bond.adapter_name
+        );
+        removed.push(bond.address);
+    }
+    state.lock().forget_all();
+    state.save_or_warn().await;
+    Ok(removed)
+}
@@ -0,0 +1,190 @@
+//! Defines the capability record mobile devices publish on the PSM characteristic, from which the
+//! SOCKS forwarder picks protocol options before connecting.
+//!
+//! Version 1 of the record is laid out as follows (integers are little-endian, as in the
+//! multiplexing protocol):
+//!
+//! | Offset | Length | Field                                               |
+//! |--------|--------|-----------------------------------------------------|
+//! | 0      | 1      | Record version (1)                                  |
+//! | 1      | 2      | PSM                                                 |
+//! | 3      | 1      | Protocol version                                    |
+//! | 4      | 2      | Supported features (see `FEATURE_*`)                |
+//! | 6      | 2      | Preferred MTU (0 if none)                           |
+//! | 8      | 1      | Length N of the app version                         |
+//! | 9      | N      | App version (UTF-8)                                 |
+//!
+//! Bytes past the app version are reserved for later record versions and ignored. Only version 1 of
+//! the multiplexing protocol exists so far, so the protocol version is only checked to be set.
+//! Older apps publish the PSM as a UTF-8 decimal string instead, which is still accepted.
+
+use anyhow::{anyhow, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use log::info;
+
+/// Version of the capability record understood here.
+const RECORD_VERSION: u8 = 1;
+
+/// Length of the fixed-size part of the capability record.
+const FIXED_LEN: usize = 9;
+
+/// Feature bit set by mobile devices that can end-to-end encrypt the L2CAP stream (see
+/// `socks::noise`.)
+pub const FEATURE_NOISE: u16 = 1 << 0;
+
+/// What a mobile device published about itself on the PSM characteristic.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    /// PSM the mobile device is listening for L2CAP connections on.
+    pub psm: u16,
+    /// Supported features, or `None` if the mobile device published a plain PSM (so its features
+    /// are unknown.)
+    pub features: Option<u16>,
+    /// MTU the mobile device prefers for the L2CAP stream, if any.
+    pub preferred_mtu: Option<u16>,
+    /// Version of the app running on the mobile device, if published.
+    pub app_version: Option<String>,
+}
+
+impl Capabilities {
+    /// Parses a PSM characteristic value: a capability record, or a UTF-8 decimal PSM from older
+    /// apps.
+    pub fn parse(value: &[u8]) -> Result<Self> {
+        if !value.is_empty() && value.iter().all(u8::is_ascii_digit) {
+            let psm = String::from_utf8_lossy(value)
+                .parse::<u16>()
+                .map_err(|e| anyhow!("found PSM is not a valid u16: {e}"))?;
+            return Ok(Self::from_psm(psm));
+        }
+
+        if value.len() < FIXED_LEN {
+            return Err(anyhow!(
+                "capability record of {} bytes is shorter than {FIXED_LEN} bytes",
+                value.len()
+            ));
+        }
+        if value[0] != RECORD_VERSION {
+            return Err(anyhow!(
+                "unsupported capability record version {}; expected {RECORD_VERSION}",
+                value[0]
+            ));
+        }
+        let psm = LittleEndian::read_u16(&value[1..3]);
+        if value[3] == 0 {
+            return Err(anyhow!("capability record has invalid protocol version 0"));
+        }
+        let features = LittleEndian::read_u16(&value[4..6]);
+        let preferred_mtu = LittleEndian::read_u16(&value[6..8]);
+        let app_version_len = value[8] as usize;
+        let app_version = value
+            .get(FIXED_LEN..FIXED_LEN + app_version_len)
+            .ok_or_else(|| anyhow!("capability record is truncated in the app version"))?;
+        let app_version = String::from_utf8(app_version.to_vec())
+            .map_err(|e| anyhow!("capability record app version is not UTF-8: {e}"))?;
+
+        Ok(Capabilities {
+            psm,
+            features: Some(features),
+            preferred_mtu: (preferred_mtu != 0).then_some(preferred_mtu),
+            app_version: (!app_version.is_empty()).then_some(app_version),
+        })
+    }
+
+    /// Returns the capabilities of a mobile device that only published `psm`.
+    pub fn from_psm(psm: u16) -> Self {
+        Capabilities {
+            psm,
+            features: None,
+            preferred_mtu: None,
+            app_version: None,
+        }
+    }
+
+    /// Returns whether the mobile device supports `feature`, or `None` if its features are
+    /// unknown.
+    pub fn supports(&self, feature: u16) -> Option<bool> {
+        self.features.map(|features| features & feature != 0)
+    }
+
+    /// Logs the capabilities.
+    pub fn log(&self) {
+        match self.features {
+            Some(features) => info!(
+                "Mobile device app {} has features {features:#06x} and preferred MTU {:?} on PSM {}",
+                self.app_version.as_deref().unwrap_or("(unknown version)"),
+                self.preferred_mtu,
+                self.psm
+            ),
+            None => info!(
+                "Mobile device published plain PSM {} (older app)",
+                self.psm
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_legacy_decimal_psm() {
+        assert_eq!(
+            Capabilities::parse(b"192").unwrap(),
+            Capabilities::from_psm(192)
+        );
+        assert!(Capabilities::parse(b"70000").is_err());
+    }
+
+    #[test]
+    fn parses_version_1_record() {
+        let value = [
+            1, 0x81, 0x00, 1, 0x01, 0x00, 0x00, 0x20, 3, b'1', b'.', b'2',
+        ];
+        assert_eq!(
+            Capabilities::parse(&value).unwrap(),
+            Capabilities {
+                psm: 0x81,
+                features: Some(FEATURE_NOISE),
+                preferred_mtu: Some(8192),
+                app_version: Some("1.2".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_record_without_preferred_mtu_or_app_version() {
+        let value = [1, 0x81, 0x00, 1, 0x00, 0x00, 0x00, 0x00, 0];
+        let capabilities = Capabilities::parse(&value).unwrap();
+        assert_eq!(capabilities.supports(FEATURE_NOISE), Some(false));
+        assert_eq!(capabilities.preferred_mtu, None);
+        assert_eq!(capabilities.app_version, None);
+    }
+
+    #[test]
+    fn rejects_truncated_record() {
+        assert!(Capabilities::parse(&[1, 0x81, 0x00, 1, 0x00, 0x00, 0x00, 0x00]).is_err());
+        // App version shorter than its length.
+        assert!(Capabilities::parse(&[1, 0x81, 0x00, 1, 0x00, 0x00, 0x00, 0x00, 3, b'1']).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_record_version() {
+        assert!(Capabilities::parse(&[2, 0x81, 0x00, 1, 0x00, 0x00, 0x00, 0x00, 0]).is_err());
+    }
+
+    #[test]
+    fn rejects_protocol_version_0() {
+        assert!(Capabilities::parse(&[1, 0x81, 0x00, 0, 0x00, 0x00, 0x00, 0x00, 0]).is_err());
+    }
+
+    #[test]
+    fn ignores_trailing_bytes() {
+        let value = [
+            1, 0x81, 0x00, 1, 0x00, 0x00, 0x00, 0x00, 1, b'2', 0xAA, 0xBB,
+        ];
+        let capabilities = Capabilities::parse(&value).unwrap();
+        assert_eq!(capabilities.psm, 0x81);
+        assert_eq!(capabilities.app_version.as_deref(), Some("2"));
+    }
+}
@@ -1,31 +1,140 @@
 //! Defines central logic.
 
-use std::{collections::HashSet, time::Duration};
+use std::collections::HashSet;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 use bluer::{
-    AdapterEvent, Device, DeviceEvent, DeviceProperty, DiscoveryFilter, DiscoveryTransport,
+    Adapter, AdapterEvent, Address, Device, DeviceEvent, DeviceProperty, DiscoveryFilter,
+    DiscoveryTransport,
 };
+use futures::stream::{BoxStream, SelectAll};
 use futures::{pin_mut, select, FutureExt, StreamExt};
-use log::{debug, info};
-use tokio::time::sleep;
+use log::{debug, info, warn};
+use tokio::time::{sleep, timeout};
 
-/// Finds previously paired device and its exposed PSM:
+use crate::capabilities::Capabilities;
+use crate::config;
+use crate::recovery::{Recovery, Step};
+use crate::status::StatusHandle;
+
+/// Default for how long to wait for GATT services to resolve after connecting.
+pub const DEFAULT_GATT_RESOLUTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait before first retrying a device that failed to connect or resolve GATT
+/// services; doubled after every further failure up to `MAX_RETRY_DELAY`.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest wait before retrying a device that failed to connect or resolve GATT services.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// How long to collect discovered devices before evaluating them strongest first (devices
+/// discovered later are evaluated as they come.)
+const SELECTION_WINDOW: Duration = Duration::from_secs(3);
+
+/// How often to sample the RSSI of bridged mobile devices.
+const RSSI_SAMPLE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to discover for when sampling the RSSI of bridged mobile devices.
+const RSSI_SAMPLE_WINDOW: Duration = Duration::from_secs(2);
+
+/// Default for how long to scan before giving up.
+pub const DEFAULT_SCAN_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Outcome of evaluating a discovered device.
+enum Evaluation {
+    /// The device is a match; holds a handle to it, its name and its capabilities.
+    Found(Device, String, Capabilities),
+    /// The device is not (yet) eligible; it is evaluated again if its properties change.
+    Ineligible,
+    /// The device was checked and is not a match; it is not evaluated again.
+    Rejected,
+    /// The adapter was power cycled to recover the device, which stops discovery on it.
+    AdapterReset,
+}
+
+/// Finds a previously paired device and its exposed PSM:
 ///
 /// - with adapter `adapter`
-/// - named `device_name`
+/// - named any of `device_names`
 /// - with a service IDed as `svc_uuuid`
 /// - with a characteristic IDed as `mobile_device_name_char_uuid`
 /// - with a characteristic IDed as `psm_char_uuid`
 ///
-/// Returns a handle to that device and the PSM it's advertising.
+/// Returns a handle to the first such device found (trying the strongest of those discovered
+/// within `SELECTION_WINDOW` first), its name, and the capabilities (including the PSM) it's
+/// advertising (see `capabilities`.) Devices received below the minimum RSSI (the `min-rssi`
+/// setting) are skipped. Devices that repeatedly fail to connect or resolve GATT services are
+/// retried with exponential backoff and recovered through `recovery`. Gives up after the
+/// configured scan timeout (the `scan-timeout` setting; defaults to `DEFAULT_SCAN_TIMEOUT`.)
 pub async fn find_device_and_psm(
-    adapter: &bluer::Adapter,
-    device_name: String,
+    adapter: &Adapter,
+    device_names: &[String],
+    svc_uuid: uuid::Uuid,
+    mobile_device_name_char_uuid: uuid::Uuid,
+    psm_char_uuid: uuid::Uuid,
+    recovery: &Recovery,
+) -> Result<(Device, String, Capabilities)> {
+    let scan_timeout = config::current().settings.scan_timeout;
+    let scan = scan(
+        adapter,
+        device_names,
+        svc_uuid,
+        mobile_device_name_char_uuid,
+        psm_char_uuid,
+        recovery,
+    );
+    match timeout(scan_timeout, scan).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!(
+            "none of {device_names:?} found on adapter {} after {scan_timeout:?}",
+            adapter.name()
+        )),
+    }
+}
+
+/// Scans until a device as described by `find_device_and_psm` is found, discovering again
+/// whenever the adapter is power cycled to recover a device.
+async fn scan(
+    adapter: &Adapter,
+    device_names: &[String],
     svc_uuid: uuid::Uuid,
     mobile_device_name_char_uuid: uuid::Uuid,
     psm_char_uuid: uuid::Uuid,
-) -> Result<(Device, u16)> {
+    recovery: &Recovery,
+) -> Result<(Device, String, Capabilities)> {
+    loop {
+        let found = discover_and_evaluate(
+            adapter,
+            device_names,
+            svc_uuid,
+            mobile_device_name_char_uuid,
+            psm_char_uuid,
+            recovery,
+        )
+        .await?;
+        if let Some(found) = found {
+            return Ok(found);
+        }
+        info!(
+            "Adapter {} was reset; continuing to scan for other devices",
+            adapter.name()
+        );
+    }
+}
+
+/// Discovers until a device as described by `find_device_and_psm` is found. Discovered devices
+/// are evaluated as they are added, and devices that were not yet eligible (e.g. out of range or
+/// not yet advertising the service) are evaluated again when their properties change. Returns
+/// `None` if the adapter had to be power cycled, as that stops discovery.
+async fn discover_and_evaluate(
+    adapter: &Adapter,
+    device_names: &[String],
+    svc_uuid: uuid::Uuid,
+    mobile_device_name_char_uuid: uuid::Uuid,
+    psm_char_uuid: uuid::Uuid,
+    recovery: &Recovery,
+) -> Result<Option<(Device, String, Capabilities)>> {
     info!(
         "Discovering on Bluetooth adapter {} with address {}\n",
         adapter.name(),
@@ -40,164 +149,492 @@ pub async fn find_device_and_psm(
     };
     adapter.set_discovery_filter(filter).await?;
 
-    if adapter.is_discovering().await? {
-        return Err(anyhow!("must stop discovering outside of this process"));
-    }
-
-    let discover = adapter.discover_devices_with_changes().await?;
-    pin_mut!(discover);
+    let mut discover = discover(adapter).await?;
+    let mut changes = SelectAll::new();
+    let mut watched = HashSet::new();
+    let mut rejected = HashSet::new();
+    let min_rssi = config::current().settings.min_rssi;
 
-    'evt_loop: while let Some(evt) = discover.next().await {
-        match evt {
-            AdapterEvent::DeviceAdded(addr) => {
-                let device = adapter.device(addr)?;
-                let remote_addr = device.remote_address().await?;
+    // Devices discovered during the selection window are evaluated strongest first once it ends.
+    let selection_window = sleep(SELECTION_WINDOW);
+    pin_mut!(selection_window);
+    let mut selecting = true;
+    let mut selection = Vec::new();
 
-                match device.rssi().await? {
-                    Some(rssi) if rssi <= -200 => {
-                        debug!("Device {remote_addr} out of range; skipping");
-                        continue;
+    loop {
+        let addr = tokio::select! {
+            evt = discover.next() => match evt {
+                Some(AdapterEvent::DeviceAdded(addr)) => {
+                    // Evaluated afresh whenever bluez adds it (again.)
+                    rejected.remove(&addr);
+                    if watched.insert(addr) {
+                        let device_events = adapter.device(addr)?.events().await?;
+                        changes.push(device_events.map(move |evt| (addr, evt)));
                     }
-                    None if !device.is_connected().await? => {
-                        debug!("Device {remote_addr} has no RSSI and not connected; skipping");
-                        continue;
+                    addr
+                }
+                Some(_) => continue, // Ignore all events beyond DeviceAdded.
+                None => break,
+            },
+            Some((addr, DeviceEvent::PropertyChanged(property))) = changes.next(), if !changes.is_empty() => {
+                if rejected.contains(&addr) || !may_make_eligible(&property) {
+                    continue;
+                }
+                if !selecting {
+                    debug!("Device {addr} changed ({property:?}); evaluating it again");
+                }
+                addr
+            }
+            () = &mut selection_window, if selecting => {
+                selecting = false;
+                let candidates = strongest_first(adapter, std::mem::take(&mut selection)).await;
+                match evaluate_devices(
+                    adapter,
+                    &candidates,
+                    device_names,
+                    svc_uuid,
+                    mobile_device_name_char_uuid,
+                    psm_char_uuid,
+                    min_rssi,
+                    recovery,
+                    &mut rejected,
+                )
+                .await?
+                {
+                    Evaluation::Found(device, name, capabilities) => {
+                        return Ok(Some((device, name, capabilities)));
                     }
-                    _ => {}
+                    Evaluation::AdapterReset => return Ok(None),
+                    Evaluation::Ineligible | Evaluation::Rejected => {}
                 }
+                continue;
+            }
+        };
+        if selecting {
+            if !selection.contains(&addr) {
+                selection.push(addr);
+            }
+            continue;
+        }
 
-                // It's possible the connection was lost, so try to reconnect if so.
-                if !device.is_connected().await? {
-                    info!("Device {remote_addr} not connected to; reconnecting now");
-                    device.connect().await?;
+        match evaluate_devices(
+            adapter,
+            &[addr],
+            device_names,
+            svc_uuid,
+            mobile_device_name_char_uuid,
+            psm_char_uuid,
+            min_rssi,
+            recovery,
+            &mut rejected,
+        )
+        .await?
+        {
+            Evaluation::Found(device, name, capabilities) => {
+                return Ok(Some((device, name, capabilities)));
+            }
+            Evaluation::AdapterReset => return Ok(None),
+            Evaluation::Ineligible | Evaluation::Rejected => {}
+        }
+    }
+    Err(anyhow!(
+        "Desired service and characteristic combination not found"
+    ))
+}
+
+/// Evaluates each of `candidates` in turn (see `find_device_and_psm`) and returns the first that
+/// is a match (`Found`), or `AdapterReset` if the adapter had to be power cycled (the remaining
+/// candidates are then left for the next discovery), or `Ineligible` if none is a match. Devices
+/// that are checked and found not to match are added to `rejected`.
+#[allow(clippy::too_many_arguments)]
+async fn evaluate_devices(
+    adapter: &Adapter,
+    candidates: &[Address],
+    device_names: &[String],
+    svc_uuid: uuid::Uuid,
+    mobile_device_name_char_uuid: uuid::Uuid,
+    psm_char_uuid: uuid::Uuid,
+    min_rssi: Option<i16>,
+    recovery: &Recovery,
+    rejected: &mut HashSet<Address>,
+) -> Result<Evaluation> {
+    for &addr in candidates {
+        let evaluation = evaluate_device(
+            adapter,
+            addr,
+            device_names,
+            svc_uuid,
+            mobile_device_name_char_uuid,
+            psm_char_uuid,
+            min_rssi,
+            recovery,
+        )
+        .await?;
+        match evaluation {
+            Evaluation::Found(..) => {
+                recovery.record_success(addr);
+                return Ok(evaluation);
+            }
+            Evaluation::Ineligible => {}
+            Evaluation::Rejected => {
+                rejected.insert(addr);
+            }
+            Evaluation::AdapterReset => return Ok(evaluation),
+        }
+    }
+    Ok(Evaluation::Ineligible)
+}
+
+/// Returns `addrs` ordered by RSSI, strongest first (devices without an RSSI last.)
+async fn strongest_first(adapter: &Adapter, addrs: Vec<Address>) -> Vec<Address> {
+    let mut ranked = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        let rssi = match adapter.device(addr) {
+            Ok(device) => device.rssi().await.ok().flatten(),
+            Err(_) => None,
+        };
+        ranked.push((rssi, addr));
+    }
+    ranked.sort_by_key(|(rssi, _)| std::cmp::Reverse(*rssi));
+    if ranked.len() > 1 {
+        debug!("Evaluating devices strongest first: {ranked:?}");
+    }
+    ranked.into_iter().map(|(_, addr)| addr).collect()
+}
+
+/// Samples the RSSI of each of `devices` every `RSSI_SAMPLE_INTERVAL` and reports the weakest
+/// through `status` (warning if it is below `min_rssi`), until aborted. bluez only reports RSSI
+/// while discovering, so each sample discovers on the devices' adapters for
+/// `RSSI_SAMPLE_WINDOW`.
+pub async fn monitor_rssi(
+    devices: Vec<Device>,
+    svc_uuid: uuid::Uuid,
+    min_rssi: Option<i16>,
+    status: StatusHandle,
+) {
+    let session = match bluer::Session::new().await {
+        Ok(session) => session,
+        Err(e) => {
+            warn!("Cannot sample RSSI of bridged mobile devices: {e}");
+            return;
+        }
+    };
+    let mut adapter_names: Vec<&str> = devices.iter().map(|d| d.adapter_name()).collect();
+    adapter_names.sort_unstable();
+    adapter_names.dedup();
+    let mut sample_interval = tokio::time::interval(RSSI_SAMPLE_INTERVAL);
+    loop {
+        sample_interval.tick().await;
+
+        let mut discoveries = Vec::new();
+        for adapter_name in &adapter_names {
+            let discovery = async {
+                let adapter = session.adapter(adapter_name)?;
+                adapter
+                    .set_discovery_filter(DiscoveryFilter {
+                        transport: DiscoveryTransport::Le,
+                        uuids: HashSet::from([svc_uuid]),
+                        ..Default::default()
+                    })
+                    .await?;
+                adapter.discover_devices().await
+            };
+            match discovery.await {
+                Ok(discovery) => discoveries.push(discovery.boxed()),
+                Err(e) => {
+                    debug!("Could not discover on adapter {adapter_name} to sample RSSI: {e}")
+                }
+            }
+        }
+        sleep(RSSI_SAMPLE_WINDOW).await;
+
+        let mut weakest = None;
+        for device in &devices {
+            if let Ok(Some(rssi)) = device.rssi().await {
+                debug!("Mobile device {} RSSI is {rssi} dBm", device.address());
+                if min_rssi.is_some_and(|min_rssi| rssi < min_rssi) {
+                    warn!(
+                        "Mobile device {} RSSI of {rssi} dBm is below the minimum of {} dBm; it may be too far away",
+                        device.address(),
+                        min_rssi.unwrap_or_default()
+                    );
                 }
+                weakest = Some(weakest.map_or(rssi, |weakest: i16| weakest.min(rssi)));
+            }
+        }
+        drop(discoveries);
+        status.set_rssi(weakest);
+    }
+}
 
-                info!(
-                    "Device {remote_addr} connected={} paired={} trusted={}",
-                    device.is_connected().await?,
-                    device.is_paired().await?,
-                    device.is_trusted().await?,
+/// Starts discovery on `adapter` and returns its events. Discovery left running by another
+/// process is tolerated: bluez merges the discovery filters of all its clients (so devices are
+/// checked for the service here anyway), and if discovery cannot be started alongside it, the
+/// devices it discovers are watched for instead.
+async fn discover(adapter: &Adapter) -> Result<BoxStream<'static, AdapterEvent>> {
+    let discovering_elsewhere = adapter.is_discovering().await?;
+    if discovering_elsewhere {
+        warn!(
+            "Adapter {} is already discovering (likely for another process); sharing its discovery",
+            adapter.name()
+        );
+    }
+    match adapter.discover_devices().await {
+        Ok(discover) => Ok(discover.boxed()),
+        Err(e) if discovering_elsewhere => {
+            warn!(
+                "Could not start discovery on adapter {} ({e}); watching devices discovered by the other process",
+                adapter.name()
+            );
+            Ok(adapter.events().await?.boxed())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns whether a change of `property` may make a device that was not eligible eligible.
+fn may_make_eligible(property: &DeviceProperty) -> bool {
+    matches!(
+        property,
+        DeviceProperty::Rssi(_) | DeviceProperty::Connected(true) | DeviceProperty::Uuids(_)
+    )
+}
+
+/// Evaluates the device at `addr` (see `find_device_and_psm`.)
+#[allow(clippy::too_many_arguments)]
+async fn evaluate_device(
+    adapter: &Adapter,
+    addr: Address,
+    device_names: &[String],
+    svc_uuid: uuid::Uuid,
+    mobile_device_name_char_uuid: uuid::Uuid,
+    psm_char_uuid: uuid::Uuid,
+    min_rssi: Option<i16>,
+    recovery: &Recovery,
+) -> Result<Evaluation> {
+    let device = adapter.device(addr)?;
+    let remote_addr = device.remote_address().await?;
+
+    match device.rssi().await? {
+        Some(rssi) if min_rssi.is_some_and(|min_rssi| rssi < min_rssi) => {
+            debug!("Device {remote_addr} RSSI of {rssi} dBm is below the minimum; skipping");
+            return Ok(Evaluation::Ineligible);
+        }
+        None if !device.is_connected().await? => {
+            debug!("Device {remote_addr} has no RSSI and not connected; skipping");
+            return Ok(Evaluation::Ineligible);
+        }
+        _ => {}
+    }
+
+    // Retry until connected with services resolved, backing off and escalating recovery as
+    // failures repeat.
+    let mut retry_delay = INITIAL_RETRY_DELAY;
+    loop {
+        match connect_and_resolve_services(&device, svc_uuid).await {
+            Ok(true) => break,
+            Ok(false) => return Ok(Evaluation::Ineligible),
+            Err(e) => {
+                warn!(
+                    "Could not connect to device {remote_addr} and resolve its GATT services: {e}"
                 );
+                match recovery.record_failure(adapter, addr).await {
+                    Step::None | Step::Disconnect => {
+                        debug!("Retrying device {remote_addr} in {retry_delay:?}");
+                        sleep(retry_delay).await;
+                        retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                    }
+                    // The device must be discovered again.
+                    Step::RemoveBond => return Ok(Evaluation::Rejected),
+                    Step::ResetAdapter => return Ok(Evaluation::AdapterReset),
+                }
+            }
+        }
+    }
 
-                let uuids = device.uuids().await?.unwrap_or_default();
+    match read_name_and_capabilities(
+        &device,
+        device_names,
+        svc_uuid,
+        mobile_device_name_char_uuid,
+        psm_char_uuid,
+    )
+    .await?
+    {
+        Some((name, capabilities)) => Ok(Evaluation::Found(device, name, capabilities)),
+        None => {
+            debug!("Device {remote_addr} does not match; skipping");
+            Ok(Evaluation::Rejected)
+        }
+    }
+}
 
-                if uuids.contains(&svc_uuid) {
-                    info!(
-                        "Device {remote_addr} provides target service {}",
-                        device.address_type().await?
-                    );
+/// Connects directly to the device last found at `address` on `adapter` (without discovering
+/// it) and checks it as `find_device_and_psm` does. Returns `None` if bluez does not know the
+/// device or it is not a match.
+pub async fn connect_known_device(
+    adapter: &Adapter,
+    address: Address,
+    device_names: &[String],
+    svc_uuid: uuid::Uuid,
+    mobile_device_name_char_uuid: uuid::Uuid,
+    psm_char_uuid: uuid::Uuid,
+) -> Result<Option<(Device, String, Capabilities)>> {
+    if !adapter.device_addresses().await?.contains(&address) {
+        debug!(
+            "Device {address} is not known to adapter {}",
+            adapter.name()
+        );
+        return Ok(None);
+    }
+    let device = adapter.device(address)?;
+    if !connect_and_resolve_services(&device, svc_uuid).await? {
+        return Ok(None);
+    }
+    let found = read_name_and_capabilities(
+        &device,
+        device_names,
+        svc_uuid,
+        mobile_device_name_char_uuid,
+        psm_char_uuid,
+    )
+    .await?;
+    Ok(found.map(|(name, capabilities)| (device, name, capabilities)))
+}
 
-                    let wait_interval = Duration::from_secs(30);
+/// Reads the name and capabilities of connected `device` with GATT services resolved. Returns
+/// `None` if it does not provide the service or its name is not any of `device_names`.
+async fn read_name_and_capabilities(
+    device: &Device,
+    device_names: &[String],
+    svc_uuid: uuid::Uuid,
+    mobile_device_name_char_uuid: uuid::Uuid,
+    psm_char_uuid: uuid::Uuid,
+) -> Result<Option<(String, Capabilities)>> {
+    debug!("Getting resolved services");
+    let services = device.services().await?;
 
-                    let changes = device.events().await?.fuse();
-                    pin_mut!(changes);
+    debug!("... found {} services", services.len());
+    for service in services {
+        let uuid = service.uuid().await?;
+        debug!("Service UUID: {}", &uuid);
+        if uuid == svc_uuid {
+            info!("Found target service");
 
-                    if !device.is_services_resolved().await? {
-                        if !device.is_connected().await? {
-                            debug!("Reconnecting before waiting for GATT service resolution");
-                            device.connect().await?;
+            debug!("Checking name");
+            let mut found_name = None;
+            for characteristic in service.characteristics().await? {
+                let uuid = characteristic.uuid().await?;
+                debug!("Characteristic UUID: {}", &uuid);
+                if uuid == mobile_device_name_char_uuid {
+                    info!("Found name characteristic");
+                    if characteristic.flags().await?.read {
+                        debug!("Reading characteristic value");
+                        let value = characteristic.read().await?;
+                        let found_device_name = String::from_utf8_lossy(&value);
+                        if device_names.iter().any(|name| *name == found_device_name) {
+                            found_name = Some(found_device_name.into_owned());
+                            break;
                         }
+                        debug!("Read str: {:x?}", &found_device_name);
+                    }
+                }
+            }
+            let Some(found_name) = found_name else {
+                debug!("Skipping this device; as name characteristic did not match any of {device_names:?}");
+                continue;
+            };
 
-                        debug!("Waiting for GATT services to resolve");
-                        let timeout = sleep(wait_interval).fuse();
-                        pin_mut!(timeout);
-
-                        loop {
-                            select! {
-                                change_opt = changes.next() => {
-                                    match change_opt {
-                                        Some(DeviceEvent::PropertyChanged (DeviceProperty::ServicesResolved(true)) ) => {
-                                            debug!("GATT services resolved");
-                                            break
-                                        },
-                                        Some(DeviceEvent::PropertyChanged (DeviceProperty::Connected(false)) ) => {
-                                            debug!("Lost connection while waiting for GATT service resolution; reconnecting");
-                                            device.connect().await?;
-                                        },
-                                        Some(_) => { // check anyway
-                                            if device.is_services_resolved().await? {
-                                                debug!("GATT services resolved");
-                                                break;
-                                            }
-                                        },
-                                        None => {
-                                            debug!("Changes for device stopped streaming; will restart waiting for GATT service resolution");
-                                            continue 'evt_loop;
-                                        },
-                                    }
-                                },
-                                () = &mut timeout => {
-                                    debug!("GATT services failed to resolve after {wait_interval:?} will restart waiting for GATT service resolution");
-                                    continue 'evt_loop;
-                                },
-                            }
-                        }
+            info!("Getting PSM from characteristics");
+            for char in service.characteristics().await? {
+                let uuid = char.uuid().await?;
+                debug!("Characteristic UUID: {}", &uuid);
+                if uuid == psm_char_uuid {
+                    info!("Found PSM characteristic");
+                    if char.flags().await?.read {
+                        debug!("Reading PSM characteristic value");
+                        let value = char.read().await?;
+                        debug!("Read value: {:x?}", &value);
+                        let capabilities = Capabilities::parse(&value)?;
+                        capabilities.log();
+                        return Ok(Some((found_name, capabilities)));
                     }
-                    debug!("Getting resolved services");
-                    let services = device.services().await?;
-
-                    debug!("... found {} services", services.len());
-                    for service in services {
-                        let uuid = service.uuid().await?;
-                        debug!("Service UUID: {}", &uuid);
-                        if uuid == svc_uuid {
-                            info!("Found target service");
-
-                            debug!("Checking name");
-                            let mut found_name = false;
-                            for characteristic in service.characteristics().await? {
-                                let uuid = characteristic.uuid().await?;
-                                debug!("Characteristic UUID: {}", &uuid);
-                                if uuid == mobile_device_name_char_uuid {
-                                    info!("Found name characteristic");
-                                    if characteristic.flags().await?.read {
-                                        debug!("Reading characteristic value");
-                                        let value = characteristic.read().await?;
-                                        let found_device_name = String::from_utf8_lossy(&value);
-                                        if found_device_name == device_name {
-                                            found_name = true;
-                                            break;
-                                        }
-                                        debug!("Read str: {:x?}", &found_device_name);
-                                    }
-                                }
-                            }
-                            if !found_name {
-                                debug!("Skipping this device; as name characteristic did not match {device_name}");
-                                continue;
-                            }
+                }
+            }
+        }
+    }
+    Ok(None)
+}
 
-                            info!("Getting PSM from characteristics");
-                            for char in service.characteristics().await? {
-                                let uuid = char.uuid().await?;
-                                debug!("Characteristic UUID: {}", &uuid);
-                                if uuid == psm_char_uuid {
-                                    info!("Found PSM characteristic");
-                                    if char.flags().await?.read {
-                                        debug!("Reading PSM characteristic value");
-                                        let value = char.read().await?;
-                                        debug!("Read value: {:x?}", &value);
-                                        let str_psm = String::from_utf8_lossy(&value);
-                                        match str_psm.parse::<u16>() {
-                                            Ok(psm) => {
-                                                return Ok((device, psm));
-                                            }
-                                            Err(e) => {
-                                                return Err(anyhow!(
-                                                    "found PSM is not a valid u16: {e}"
-                                                ));
-                                            }
-                                        }
-                                    }
-                                }
+/// Connects to `device` (if not already connected) and, if it provides the service IDed as
+/// `svc_uuid`, waits for its GATT services to resolve. Returns whether it provides the service.
+async fn connect_and_resolve_services(device: &Device, svc_uuid: uuid::Uuid) -> Result<bool> {
+    let remote_addr = device.remote_address().await?;
+
+    // It's possible the connection was lost, so try to reconnect if so.
+    if !device.is_connected().await? {
+        info!("Device {remote_addr} not connected to; reconnecting now");
+        device.connect().await?;
+    }
+
+    info!(
+        "Device {remote_addr} connected={} paired={} trusted={}",
+        device.is_connected().await?,
+        device.is_paired().await?,
+        device.is_trusted().await?,
+    );
+
+    let uuids = device.uuids().await?.unwrap_or_default();
+    if !uuids.contains(&svc_uuid) {
+        return Ok(false);
+    }
+    info!(
+        "Device {remote_addr} provides target service {}",
+        device.address_type().await?
+    );
+
+    let changes = device.events().await?.fuse();
+    pin_mut!(changes);
+
+    if !device.is_services_resolved().await? {
+        if !device.is_connected().await? {
+            debug!("Reconnecting before waiting for GATT service resolution");
+            device.connect().await?;
+        }
+
+        debug!("Waiting for GATT services to resolve");
+        let gatt_resolution_timeout = config::current().settings.gatt_resolution_timeout;
+        let timeout = sleep(gatt_resolution_timeout).fuse();
+        pin_mut!(timeout);
+
+        loop {
+            select! {
+                change_opt = changes.next() => {
+                    match change_opt {
+                        Some(DeviceEvent::PropertyChanged(DeviceProperty::ServicesResolved(true))) => {
+                            debug!("GATT services resolved");
+                            break;
+                        },
+                        Some(DeviceEvent::PropertyChanged(DeviceProperty::Connected(false))) => {
+                            debug!("Lost connection while waiting for GATT service resolution; reconnecting");
+                            device.connect().await?;
+                        },
+                        Some(_) => { // check anyway
+                            if device.is_services_resolved().await? {
+                                debug!("GATT services resolved");
+                                break;
                             }
-                        }
+                        },
+                        None => {
+                            return Err(anyhow!("changes for device stopped streaming while waiting for GATT service resolution"));
+                        },
                     }
-                }
+                },
+                () = &mut timeout => {
+                    return Err(anyhow!("GATT services failed to resolve after {gatt_resolution_timeout:?}"));
+                },
             }
-            _ => (), // Ignore all events beyond AddedDevice.
         }
     }
-    Err(anyhow!(
-        "Desired service and characteristic combination not found"
-    ))
+    Ok(true)
 }
@@ -0,0 +1,194 @@
+//! Defines the command line interface. With no subcommand the SOCKS forwarder runs as a service;
+//! subcommands manage its configuration and exit.
+
+use anyhow::{anyhow, Result};
+use bluer::Address;
+use clap::{Parser, Subcommand};
+
+use crate::allowlist::Allowlist;
+use crate::state::Store;
+use crate::{bonds, config, control};
+
+/// Viam SOCKS forwarder: bridges local SOCKS connections through a mobile device over Bluetooth.
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    /// Path to the configuration file.
+    #[arg(long, value_name = "PATH", default_value = config::CONFIG_FP)]
+    pub config: String,
+    /// Validate the configuration, print the effective configuration and exit.
+    #[arg(long)]
+    pub check_config: bool,
+    #[command(flatten)]
+    pub overrides: config::Overrides,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Manage the allowlist of mobile devices permitted to register.
+    Allowlist {
+        #[command(subcommand)]
+        command: AllowlistCommand,
+    },
+    /// Control the running SOCKS forwarder's advertising.
+    Advertising {
+        #[command(subcommand)]
+        command: AdvertisingCommand,
+    },
+    /// Show how the running SOCKS forwarder pairs with mobile devices.
+    Pairing {
+        #[command(subcommand)]
+        command: PairingCommand,
+    },
+    /// Manage the mobile devices bluez has bonded with. Changes are made through the running SOCKS
+    /// forwarder if there is one.
+    Bonds {
+        #[command(subcommand)]
+        command: BondsCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AllowlistCommand {
+    /// List the mobile device names and addresses in the allowlist.
+    List,
+    /// Add a mobile device name or Bluetooth address to the allowlist.
+    Add { entry: String },
+    /// Remove a mobile device name or Bluetooth address from the allowlist.
+    Remove { entry: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AdvertisingCommand {
+    /// Advertise fast again (waking up from slow or quiet advertising.)
+    Wake,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PairingCommand {
+    /// Print the passkey mobile devices must enter to pair (including a random one.)
+    Passkey,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum BondsCommand {
+    /// List paired or trusted devices.
+    List,
+    /// Remove a device (and its bond) and forget it as a registered mobile device.
+    Remove { address: Address },
+    /// Remove all paired or trusted devices and forget all registered mobile devices.
+    Purge,
+}
+
+impl Command {
+    /// Runs the subcommand.
+    pub async fn run(self) -> Result<()> {
+        match self {
+            Command::Allowlist { command } => run_allowlist_command(command).await,
+            Command::Advertising { command } => run_advertising_command(command).await,
+            Command::Pairing { command } => run_pairing_command(command).await,
+            Command::Bonds { command } => run_bonds_command(command).await,
+        }
+    }
+}
+
+/// Checks that the Bluetooth adapter(s) selected by the configuration in effect exist, if any are
+/// (its values were already checked when it was loaded, see `config::Settings`), and prints it.
+pub async fn check_config() -> Result<()> {
+    let config = config::current();
+    if let Some(adapter) = &config.settings.adapter {
+        let session = bluer::Session::new()
+            .await
+            .map_err(|e| anyhow!("could not check adapter \"{adapter}\": {e}"))?;
+        crate::select_adapters(&session, Some(adapter)).await?;
+    }
+    config.print();
+    Ok(())
+}
+
+async fn run_allowlist_command(command: AllowlistCommand) -> Result<()> {
+    let mut allowlist = Allowlist::load().await?;
+    match command {
+        AllowlistCommand::List => {
+            if allowlist.entries().is_empty() {
+                println!("Allowlist is empty; any mobile device may register");
+            }
+            for entry in allowlist.entries() {
+                println!("{entry}");
+            }
+        }
+        AllowlistCommand::Add { entry } => {
+            allowlist.add(&entry).await?;
+            println!("Added \"{}\" to the allowlist", entry.trim());
+        }
+        AllowlistCommand::Remove { entry } => {
+            allowlist.remove(&entry).await?;
+            println!("Removed \"{}\" from the allowlist", entry.trim());
+        }
+    }
+    Ok(())
+}
+
+async fn run_advertising_command(command: AdvertisingCommand) -> Result<()> {
+    match command {
+        AdvertisingCommand::Wake => match control::request("advertising wake").await? {
+            Some(message) => println!("SOCKS forwarder {message}"),
+            None => return Err(anyhow!("no SOCKS forwarder is running")),
+        },
+    }
+    Ok(())
+}
+
+async fn run_pairing_command(command: PairingCommand) -> Result<()> {
+    match command {
+        PairingCommand::Passkey => match control::request("pairing passkey").await? {
+            Some(message) => println!("SOCKS forwarder {message}"),
+            None => return Err(anyhow!("no SOCKS forwarder is running")),
+        },
+    }
+    Ok(())
+}
+
+async fn run_bonds_command(command: BondsCommand) -> Result<()> {
+    let session = bluer::Session::new().await?;
+    match command {
+        BondsCommand::List => {
+            let bonds = bonds::list(&session).await?;
+            if bonds.is_empty() {
+                println!("No paired or trusted devices");
+            }
+            for bond in bonds {
+                println!(
+                    "{} {} \"{}\" paired={} trusted={} connected={}",
+                    bond.adapter_name,
+                    bond.address,
+                    bond.alias,
+                    bond.paired,
+                    bond.trusted,
+                    bond.connected
+                );
+            }
+        }
+        BondsCommand::Remove { address } => {
+            match control::request(&format!("bonds remove {address}")).await? {
+                Some(message) => println!("SOCKS forwarder {message}"),
+                None => {
+                    let state = Store::load().await;
+                    bonds::remove(&session, &state, address).await?;
+                    println!("Removed {address}");
+                }
+            }
+        }
+        BondsCommand::Purge => match control::request("bonds purge").await? {
+            Some(message) => println!("SOCKS forwarder {message}"),
+            None => {
+                let state = Store::load().await;
+                let removed = bonds::purge(&session, &state).await?;
+                println!("Removed {} device(s)", removed.len());
+            }
+        },
+    }
+    Ok(())
+}
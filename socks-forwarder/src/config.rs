@@ -0,0 +1,882 @@
+//! Defines the configuration of the SOCKS forwarder. Every setting can be given in the
+//! configuration file at `CONFIG_FP` (TOML), in its environment variable (see `env`) or as a
+//! command line flag; the command line takes precedence over the environment, which takes
+//! precedence over the configuration file. Settings given nowhere keep their defaults.
+//!
+//! Every value is interpreted and checked into `Settings` when the configuration is loaded, so an
+//! invalid value fails the load (and `--check-config`) rather than being ignored later. The
+//! configuration can be reloaded while the SOCKS forwarder runs (see `reload`); each setting takes
+//! effect right away, or once the active bridge has drained (see `Applies`.)
+
+use std::collections::BTreeMap;
+use std::env::{self, VarError};
+use std::fmt::Display;
+use std::io;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use clap::{Arg, ArgMatches, Args, FromArgMatches};
+
+use crate::advertising::AdvertisingConfig;
+use crate::env::{
+    ADAPTER_ENV_VAR, ADVERTISED_NAME_ENV_VAR, ADVERTISING_FAST_WINDOW_ENV_VAR,
+    ADVERTISING_INTERVAL_ENV_VAR, ADVERTISING_MANUFACTURER_DATA_ENV_VAR,
+    ADVERTISING_QUIET_AFTER_ENV_VAR, ADVERTISING_SERVICE_DATA_ENV_VAR,
+    ADVERTISING_SLOW_INTERVAL_ENV_VAR, ADVERTISING_TX_POWER_ENV_VAR,
+    AUTHENTICATED_REGISTRATION_ENV_VAR, ENCRYPTION_ENV_VAR, FAST_RECONNECT_TIMEOUT_ENV_VAR,
+    FORWARDS_ENV_VAR, GATT_RESOLUTION_TIMEOUT_ENV_VAR, LOAD_BALANCING_ENV_VAR,
+    MAX_MOBILE_DEVICES_ENV_VAR, MIN_RSSI_ENV_VAR, MOBILE_DEVICE_NAMES_ENV_VAR, PAIRING_ENV_VAR,
+    PASSKEY_ENV_VAR, RECV_MTU_OVERRIDE_ENV_VAR, REGISTRATION_MODE_ENV_VAR, REVERSE_PORTS_ENV_VAR,
+    SCAN_TIMEOUT_ENV_VAR, SECURITY_LEVEL_ENV_VAR, SOCKS_PORT_ENV_VAR, VIAM_CONFIG_ENV_VAR,
+    VIAM_CONFIG_FP,
+};
+use crate::pairing::PairingPolicy;
+use crate::registration::RegistrationMode;
+use crate::security::SecurityLevel;
+use crate::socks::forward::{self, Forward};
+use crate::socks::reverse::ReverseTargets;
+use crate::socks::{self, LoadBalancing};
+use crate::{central, DEFAULT_FAST_RECONNECT_TIMEOUT};
+
+/// Path to the default configuration file.
+pub const CONFIG_FP: &str = "/etc/socks-forwarder.toml";
+
+/// Type of the value of a setting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Integer,
+    Boolean,
+    String,
+    /// A list of strings or integers (comma-separated in the environment and on the command line.)
+    List,
+}
+
+/// When a changed setting takes effect after the configuration is reloaded (see `reload`.)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Applies {
+    /// Right away (or the next time the setting is used.)
+    Now,
+    /// Right away if no bridge is up, or else once the active bridge has drained.
+    NextBridge,
+    /// Right away if no bridge is up, or else once the active bridge has drained, by setting up
+    /// Bluetooth again: the pairing agent, adapters, GATT application and advertisement (see
+    /// `start_bluetooth`.)
+    Bluetooth,
+}
+
+/// A setting, known by the same key in the configuration file and (as `--<key>`) on the command
+/// line.
+struct Setting {
+    key: &'static str,
+    env_var: &'static str,
+    kind: Kind,
+    applies: Applies,
+    /// Description of the default, shown when the setting is not given.
+    default: &'static str,
+    help: &'static str,
+}
+
+/// Every setting.
+const SETTINGS: &[Setting] = &[
+    Setting {
+        key: "socks-port",
+        env_var: SOCKS_PORT_ENV_VAR,
+        kind: Kind::Integer,
+        applies: Applies::NextBridge,
+        default: "1080",
+        help: "Local port to accept SOCKS connections on",
+    },
+    Setting {
+        key: "recv-mtu",
+        env_var: RECV_MTU_OVERRIDE_ENV_VAR,
+        kind: Kind::Integer,
+        applies: Applies::NextBridge,
+        default: "the mobile device's preferred MTU, else 32768",
+        help: "Recv MTU of L2CAP streams",
+    },
+    Setting {
+        key: "advertised-name",
+        env_var: ADVERTISED_NAME_ENV_VAR,
+        kind: Kind::String,
+        applies: Applies::Now,
+        default: "first line of /etc/advertised_ble_name.txt, else \"Viam SOCKS forwarder\"",
+        help: "Name to advertise over BLE",
+    },
+    Setting {
+        key: "viam-config",
+        env_var: VIAM_CONFIG_ENV_VAR,
+        kind: Kind::String,
+        applies: Applies::Now,
+        default: "/etc/viam.json",
+        help: "Path to the Viam cloud config",
+    },
+    Setting {
+        key: "adapter",
+        env_var: ADAPTER_ENV_VAR,
+        kind: Kind::String,
+        applies: Applies::Bluetooth,
+        default: "the default adapter",
+        help: "Bluetooth adapter to use, by name or address, or \"all\"",
+    },
+    Setting {
+        key: "pairing",
+        env_var: PAIRING_ENV_VAR,
+        kind: Kind::String,
+        applies: Applies::Bluetooth,
+        default: "static",
+        help: "Pairing policy (\"just-works\", \"static\" or \"random\")",
+    },
+    Setting {
+        key: "passkey",
+        env_var: PASSKEY_ENV_VAR,
+        kind: Kind::Integer,
+        applies: Applies::Bluetooth,
+        default: "123456",
+        help: "Passkey for the static pairing policy",
+    },
+    Setting {
+        key: "security-level",
+        env_var: SECURITY_LEVEL_ENV_VAR,
+        kind: Kind::String,
+        applies: Applies::Bluetooth,
+        default: "open",
+        help: "Link security required of mobile devices (\"open\", \"encrypted\" or \"authenticated\")",
+    },
+    Setting {
+        key: "registration-mode",
+        env_var: REGISTRATION_MODE_ENV_VAR,
+        kind: Kind::String,
+        applies: Applies::NextBridge,
+        default: "peripheral",
+        help: "How mobile devices register (\"peripheral\" or \"central\")",
+    },
+    Setting {
+        key: "mobile-device-names",
+        env_var: MOBILE_DEVICE_NAMES_ENV_VAR,
+        kind: Kind::List,
+        applies: Applies::NextBridge,
+        default: "none",
+        help: "Mobile device names to scan for in the central registration mode",
+    },
+    Setting {
+        key: "authenticated-registration",
+        env_var: AUTHENTICATED_REGISTRATION_ENV_VAR,
+        kind: Kind::Boolean,
+        applies: Applies::Bluetooth,
+        default: "false",
+        help: "Require mobile devices to authenticate with the machine part secret to register",
+    },
+    Setting {
+        key: "encryption",
+        env_var: ENCRYPTION_ENV_VAR,
+        kind: Kind::Boolean,
+        applies: Applies::NextBridge,
+        default: "false",
+        help: "End-to-end encrypt L2CAP streams with the machine part secret",
+    },
+    Setting {
+        key: "max-mobile-devices",
+        env_var: MAX_MOBILE_DEVICES_ENV_VAR,
+        kind: Kind::Integer,
+        applies: Applies::Now,
+        default: "1",
+        help: "Maximum number of mobile devices to bridge through at once",
+    },
+    Setting {
+        key: "load-balancing",
+        env_var: LOAD_BALANCING_ENV_VAR,
+        kind: Kind::String,
+        applies: Applies::NextBridge,
+        default: "round-robin",
+        help: "How connections are spread across mobile devices (\"round-robin\", \"least-outstanding-bytes\" or \"destination-hash\")",
+    },
+    Setting {
+        key: "advertising-interval",
+        env_var: ADVERTISING_INTERVAL_ENV_VAR,
+        kind: Kind::String,
+        applies: Applies::Bluetooth,
+        default: "20-100",
+        help: "Fast advertising interval range (\"<min>-<max>\" in milliseconds)",
+    },
+    Setting {
+        key: "advertising-slow-interval",
+        env_var: ADVERTISING_SLOW_INTERVAL_ENV_VAR,
+        kind: Kind::String,
+        applies: Applies::Bluetooth,
+        default: "1000-1500",
+        help: "Slow advertising interval range (\"<min>-<max>\" in milliseconds)",
+    },
+    Setting {
+        key: "advertising-fast-window",
+        env_var: ADVERTISING_FAST_WINDOW_ENV_VAR,
+        kind: Kind::Integer,
+        applies: Applies::Bluetooth,
+        default: "60",
+        help: "Seconds to advertise fast after start, a lost bridge or a wake request",
+    },
+    Setting {
+        key: "advertising-quiet-after",
+        env_var: ADVERTISING_QUIET_AFTER_ENV_VAR,
+        kind: Kind::Integer,
+        applies: Applies::Bluetooth,
+        default: "never",
+        help: "Seconds without a bridge after which to stop advertising until woken up",
+    },
+    Setting {
+        key: "advertising-tx-power",
+        env_var: ADVERTISING_TX_POWER_ENV_VAR,
+        kind: Kind::Integer,
+        applies: Applies::Bluetooth,
+        default: "the adapter's choice",
+        help: "Advertising TX power in dBm",
+    },
+    Setting {
+        key: "advertising-manufacturer-data",
+        env_var: ADVERTISING_MANUFACTURER_DATA_ENV_VAR,
+        kind: Kind::Boolean,
+        applies: Applies::Bluetooth,
+        default: "false",
+        help: "Advertise the machine part ID and state record as manufacturer data",
+    },
+    Setting {
+        key: "advertising-service-data",
+        env_var: ADVERTISING_SERVICE_DATA_ENV_VAR,
+        kind: Kind::Boolean,
+        applies: Applies::Bluetooth,
+        default: "false",
+        help: "Advertise the machine part ID and state record as service data",
+    },
+    Setting {
+        key: "scan-timeout",
+        env_var: SCAN_TIMEOUT_ENV_VAR,
+        kind: Kind::Integer,
+        applies: Applies::Now,
+        default: "120",
+        help: "Seconds to scan for a mobile device before giving up",
+    },
+    Setting {
+        key: "min-rssi",
+        env_var: MIN_RSSI_ENV_VAR,
+        kind: Kind::Integer,
+        applies: Applies::Now,
+        default: "none",
+        help: "Minimum RSSI in dBm at which to connect to a mobile device",
+    },
+    Setting {
+        key: "fast-reconnect-timeout",
+        env_var: FAST_RECONNECT_TIMEOUT_ENV_VAR,
+        kind: Kind::Integer,
+        applies: Applies::Now,
+        default: "20",
+        help: "Seconds to try reconnecting to known mobile devices before advertising",
+    },
+    Setting {
+        key: "gatt-resolution-timeout",
+        env_var: GATT_RESOLUTION_TIMEOUT_ENV_VAR,
+        kind: Kind::Integer,
+        applies: Applies::Now,
+        default: "30",
+        help: "Seconds to wait for a mobile device's GATT services to resolve after connecting",
+    },
+    Setting {
+        key: "reverse-ports",
+        env_var: REVERSE_PORTS_ENV_VAR,
+        kind: Kind::List,
+        applies: Applies::NextBridge,
+        default: "none",
+        help: "Local TCP ports mobile devices may open reverse streams to",
+    },
+    Setting {
+        key: "forwards",
+        env_var: FORWARDS_ENV_VAR,
+        kind: Kind::List,
+        applies: Applies::NextBridge,
+        default: "none",
+        help: "Static forwards, each as \"<local port>=<host>:<port>\"",
+    },
+];
+
+/// Where the value of a setting came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    File,
+    Env,
+    Cli,
+}
+
+/// Values of the settings given in the configuration file, the environment or on the command
+/// line, by environment variable name, as given.
+#[derive(Clone, Debug, Default)]
+pub struct Values(BTreeMap<&'static str, (String, Source)>);
+
+impl Values {
+    /// Reads the values of the settings given in `contents` of the configuration file at `path`.
+    fn from_toml(path: &str, contents: &str) -> Result<Self> {
+        let mut values = Values::default();
+        let table = contents
+            .parse::<toml::Table>()
+            .map_err(|e| anyhow!("could not parse \"{path}\": {e}"))?;
+        for (key, value) in table {
+            let setting = SETTINGS
+                .iter()
+                .find(|setting| setting.key == key)
+                .ok_or_else(|| anyhow!("unknown setting \"{key}\" in \"{path}\""))?;
+            let value = from_toml(setting, &value)
+                .map_err(|e| anyhow!("invalid \"{key}\" in \"{path}\": {e}"))?;
+            values.insert(setting.env_var, value, Source::File);
+        }
+        Ok(values)
+    }
+
+    /// Adds the settings given in the environment.
+    fn add_env(&mut self) {
+        for setting in SETTINGS {
+            if let Ok(value) = env::var(setting.env_var) {
+                self.0.insert(setting.env_var, (value, Source::Env));
+            }
+        }
+    }
+
+    /// Sets the value of the setting read from `env_var`.
+    pub fn insert(&mut self, env_var: &'static str, value: String, source: Source) {
+        self.0.insert(env_var, (value, source));
+    }
+
+    /// Returns the value of the setting read from `env_var`, like `std::env::var`.
+    pub fn var(&self, env_var: &str) -> Result<String, VarError> {
+        self.0
+            .get(env_var)
+            .map(|(value, _)| value.clone())
+            .ok_or(VarError::NotPresent)
+    }
+
+    /// Reads an integer within `range` from `env_var`, if it is set.
+    pub fn integer<T>(&self, env_var: &str, range: RangeInclusive<T>) -> Result<Option<T>>
+    where
+        T: FromStr + PartialOrd + Display,
+    {
+        match self.var(env_var) {
+            Ok(value) => value
+                .trim()
+                .parse()
+                .ok()
+                .filter(|integer| range.contains(integer))
+                .map(Some)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "invalid value \"{value}\" for {env_var}; expected an integer from {} to {}",
+                        range.start(),
+                        range.end()
+                    )
+                }),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Reads a number of seconds from `env_var`, if it is set.
+    pub fn secs(&self, env_var: &str) -> Result<Option<Duration>> {
+        match self.var(env_var) {
+            Ok(secs) => secs
+                .trim()
+                .parse()
+                .map(|secs| Some(Duration::from_secs(secs)))
+                .map_err(|_| anyhow!("invalid value \"{secs}\" for {env_var}; expected seconds")),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Reads a timeout of at least a second from `env_var`, defaulting to `default` if it is not
+    /// set.
+    fn timeout(&self, env_var: &str, default: Duration) -> Result<Duration> {
+        match self.secs(env_var)? {
+            Some(timeout) if timeout.is_zero() => Err(anyhow!(
+                "invalid value \"0\" for {env_var}; expected at least a second"
+            )),
+            Some(timeout) => Ok(timeout),
+            None => Ok(default),
+        }
+    }
+
+    /// Reads a "true" or "false" flag from `env_var`, defaulting to `default` if it is not set.
+    pub fn flag(&self, env_var: &str, default: bool) -> Result<bool> {
+        match self.var(env_var).as_deref() {
+            Err(_) => Ok(default),
+            Ok("true") => Ok(true),
+            Ok("false") => Ok(false),
+            Ok(value) => Err(anyhow!(
+                "invalid value \"{value}\" for {env_var}; expected \"true\" or \"false\""
+            )),
+        }
+    }
+
+    /// Reads a comma-separated list from `env_var` (empty if it is not set.)
+    pub fn list(&self, env_var: &str) -> Vec<String> {
+        self.var(env_var)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+/// Every setting, interpreted and checked. Settings that are not given hold their defaults.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// Local port to accept SOCKS connections on.
+    pub socks_port: u16,
+    /// Recv MTU of L2CAP streams (the mobile device's preferred MTU, else the default, if unset.)
+    pub recv_mtu: Option<u16>,
+    /// Name to advertise over BLE (read from `env::ADVERTISED_BLE_NAME_FP` if unset.)
+    pub advertised_name: Option<String>,
+    /// Path to the Viam cloud config.
+    pub viam_config: String,
+    /// Bluetooth adapter(s) to use, by name or address, or "all" (the default adapter if unset.)
+    pub adapter: Option<String>,
+    pub pairing_policy: PairingPolicy,
+    pub security_level: SecurityLevel,
+    pub registration_mode: RegistrationMode,
+    /// Whether mobile devices must authenticate with the machine part secret to register.
+    pub authenticated_registration: bool,
+    /// Whether L2CAP streams are end-to-end encrypted with the machine part secret.
+    pub encryption: bool,
+    /// Maximum number of mobile devices to bridge through at once.
+    pub max_mobile_devices: usize,
+    pub load_balancing: LoadBalancing,
+    pub advertising: AdvertisingConfig,
+    /// How long to scan for a mobile device before giving up.
+    pub scan_timeout: Duration,
+    /// Minimum RSSI in dBm at which to connect to a mobile device (no minimum if unset.)
+    pub min_rssi: Option<i16>,
+    /// How long to try reconnecting to known mobile devices before advertising.
+    pub fast_reconnect_timeout: Duration,
+    /// How long to wait for GATT services to resolve after connecting to a mobile device.
+    pub gatt_resolution_timeout: Duration,
+    pub reverse_targets: ReverseTargets,
+    pub forwards: Vec<Forward>,
+}
+
+impl Settings {
+    /// Interprets and checks `values`, failing on the first invalid one.
+    pub fn from_values(values: &Values) -> Result<Self> {
+        let socks_port = values
+            .integer(SOCKS_PORT_ENV_VAR, 1..=u16::MAX)?
+            .unwrap_or(socks::DEFAULT_PORT);
+        let forwards = forward::from_values(values)?;
+        if let Some(forward) = forwards.iter().find(|f| f.local_port == socks_port) {
+            return Err(anyhow!(
+                "forward \"{}\" in {FORWARDS_ENV_VAR} uses the SOCKS port {socks_port}",
+                forward.destination()
+            ));
+        }
+        let advertised_name = match values.var(ADVERTISED_NAME_ENV_VAR) {
+            Ok(name) if name.trim().is_empty() => {
+                return Err(anyhow!("{ADVERTISED_NAME_ENV_VAR} must not be empty"));
+            }
+            Ok(name) => Some(name),
+            Err(_) => None,
+        };
+        let adapter = match values.var(ADAPTER_ENV_VAR) {
+            Ok(adapter) if adapter.trim().is_empty() => {
+                return Err(anyhow!("{ADAPTER_ENV_VAR} must not be empty"));
+            }
+            Ok(adapter) => Some(adapter),
+            Err(_) => None,
+        };
+        let load_balancing = match values.var(LOAD_BALANCING_ENV_VAR) {
+            Ok(value) => value.parse()?,
+            Err(_) => LoadBalancing::default(),
+        };
+        let pairing_policy = PairingPolicy::from_values(values)?;
+        Ok(Settings {
+            socks_port,
+            // L2CAP requires an MTU of at least 23 bytes.
+            recv_mtu: values.integer(RECV_MTU_OVERRIDE_ENV_VAR, 23..=u16::MAX)?,
+            advertised_name,
+            viam_config: values
+                .var(VIAM_CONFIG_ENV_VAR)
+                .unwrap_or_else(|_| VIAM_CONFIG_FP.to_string()),
+            adapter,
+            pairing_policy,
+            security_level: SecurityLevel::from_values(values, pairing_policy)?,
+            registration_mode: RegistrationMode::from_values(values)?,
+            authenticated_registration: values.flag(AUTHENTICATED_REGISTRATION_ENV_VAR, false)?,
+            encryption: values.flag(ENCRYPTION_ENV_VAR, false)?,
+            max_mobile_devices: values
+                .integer(MAX_MOBILE_DEVICES_ENV_VAR, 1..=usize::from(u8::MAX))?
+                .unwrap_or(1),
+            load_balancing,
+            advertising: AdvertisingConfig::from_values(values)?,
+            scan_timeout: values.timeout(SCAN_TIMEOUT_ENV_VAR, central::DEFAULT_SCAN_TIMEOUT)?,
+            min_rssi: values.integer(MIN_RSSI_ENV_VAR, -127..=20)?,
+            fast_reconnect_timeout: values.timeout(
+                FAST_RECONNECT_TIMEOUT_ENV_VAR,
+                DEFAULT_FAST_RECONNECT_TIMEOUT,
+            )?,
+            gatt_resolution_timeout: values.timeout(
+                GATT_RESOLUTION_TIMEOUT_ENV_VAR,
+                central::DEFAULT_GATT_RESOLUTION_TIMEOUT,
+            )?,
+            reverse_targets: ReverseTargets::from_values(values)?,
+            forwards,
+        })
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings::from_values(&Values::default()).expect("the defaults are valid")
+    }
+}
+
+/// The configuration: the values of the settings as given, and interpreted (see `Settings`.)
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// Path to the configuration file, and whether it was read (it need not exist.)
+    path: String,
+    file_read: bool,
+    /// Settings given on the command line, kept so that they still apply after a reload.
+    overrides: Overrides,
+    values: Values,
+    pub settings: Settings,
+}
+
+impl Config {
+    /// Loads the configuration from the configuration file at `path` (which need not exist), the
+    /// environment and `overrides` from the command line. Fails if any value is invalid.
+    pub async fn load(path: &str, overrides: &Overrides) -> Result<Self> {
+        let mut values = Values::default();
+        let mut file_read = false;
+        match tokio::fs::read_to_string(path).await {
+            Ok(contents) => {
+                values = Values::from_toml(path, &contents)?;
+                file_read = true;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(anyhow!("could not read \"{path}\": {e}")),
+        }
+        values.add_env();
+        for (env_var, value) in &overrides.0 {
+            values.insert(env_var, value.clone(), Source::Cli);
+        }
+        Ok(Config {
+            path: path.to_string(),
+            file_read,
+            overrides: overrides.clone(),
+            settings: Settings::from_values(&values)?,
+            values,
+        })
+    }
+
+    /// Returns the path to the configuration file.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Returns the key of each setting whose value differs from `self` in `other`, and when the
+    /// change takes effect.
+    pub fn changes(&self, other: &Config) -> Vec<(&'static str, Applies)> {
+        SETTINGS
+            .iter()
+            .filter(|setting| {
+                self.values.var(setting.env_var).ok() != other.values.var(setting.env_var).ok()
+            })
+            .map(|setting| (setting.key, setting.applies))
+            .collect()
+    }
+
+    /// Prints the effective configuration and where each value came from.
+    pub fn print(&self) {
+        if self.file_read {
+            println!("# Configuration file: {}", self.path);
+        } else {
+            println!("# No configuration file at {}", self.path);
+        }
+        for setting in SETTINGS {
+            match self.values.0.get(setting.env_var) {
+                Some((value, source)) => {
+                    let source = match source {
+                        Source::File => "configuration file".to_string(),
+                        Source::Env => format!("environment variable {}", setting.env_var),
+                        Source::Cli => format!("command line flag --{}", setting.key),
+                    };
+                    println!(
+                        "{} = {}  # from {source}",
+                        setting.key,
+                        to_toml(setting, value)
+                    );
+                }
+                None => println!("# {} = {}  # default", setting.key, setting.default),
+            }
+        }
+    }
+}
+
+/// Converts the value of `setting` in its form in the environment to the configuration file.
+fn to_toml(setting: &Setting, value: &str) -> String {
+    match setting.kind {
+        Kind::Integer | Kind::Boolean => value.to_string(),
+        Kind::String => format!("{value:?}"),
+        Kind::List => format!(
+            "{:?}",
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .collect::<Vec<_>>()
+        ),
+    }
+}
+
+/// Converts the value of `setting` in the configuration file to its form in the environment.
+fn from_toml(setting: &Setting, value: &toml::Value) -> Result<String> {
+    match (setting.kind, value) {
+        (Kind::Integer, toml::Value::Integer(integer)) => Ok(integer.to_string()),
+        (Kind::Boolean, toml::Value::Boolean(boolean)) => Ok(boolean.to_string()),
+        (Kind::String, toml::Value::String(string)) => Ok(string.clone()),
+        (Kind::List, toml::Value::Array(items)) => items
+            .iter()
+            .map(|item| match item {
+                toml::Value::String(string) => Ok(string.clone()),
+                toml::Value::Integer(integer) => Ok(integer.to_string()),
+                item => Err(anyhow!(
+                    "expected strings or integers but found {}",
+                    item.type_str()
+                )),
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(|items| items.join(",")),
+        (kind, value) => Err(anyhow!(
+            "expected {} but found {}",
+            match kind {
+                Kind::Integer => "an integer",
+                Kind::Boolean => "a boolean",
+                Kind::String => "a string",
+                Kind::List => "an array",
+            },
+            value.type_str()
+        )),
+    }
+}
+
+/// Settings given on the command line, as (environment variable name, value) pairs.
+#[derive(Clone, Debug, Default)]
+pub struct Overrides(Vec<(&'static str, String)>);
+
+impl FromArgMatches for Overrides {
+    fn from_arg_matches(matches: &ArgMatches) -> Result<Self, clap::Error> {
+        let mut overrides = Overrides::default();
+        overrides.update_from_arg_matches(matches)?;
+        Ok(overrides)
+    }
+
+    fn update_from_arg_matches(&mut self, matches: &ArgMatches) -> Result<(), clap::Error> {
+        for setting in SETTINGS {
+            if let Some(value) = matches.get_one::<String>(setting.key) {
+                self.0.push((setting.env_var, value.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Args for Overrides {
+    fn augment_args(cmd: clap::Command) -> clap::Command {
+        SETTINGS.iter().fold(cmd, |cmd, setting| {
+            let arg = Arg::new(setting.key)
+                .long(setting.key)
+                .help(setting.help)
+                .help_heading("Settings");
+            let arg = match setting.kind {
+                Kind::Integer => arg
+                    .value_name("INTEGER")
+                    .allow_negative_numbers(true)
+                    .value_parser(|value: &str| value.parse::<i64>().map(|_| value.to_string())),
+                Kind::Boolean => arg.value_name("BOOLEAN").value_parser(["true", "false"]),
+                Kind::String => arg.value_name("STRING"),
+                Kind::List => arg.value_name("LIST"),
+            };
+            cmd.arg(arg)
+        })
+    }
+
+    fn augment_args_for_update(cmd: clap::Command) -> clap::Command {
+        Self::augment_args(cmd)
+    }
+}
+
+/// Configuration in effect.
+static CURRENT: OnceLock<RwLock<Arc<Config>>> = OnceLock::new();
+
+/// Returns the lock around the configuration in effect (the defaults until one is set.)
+fn current_lock() -> &'static RwLock<Arc<Config>> {
+    CURRENT.get_or_init(Default::default)
+}
+
+/// Sets the configuration in effect.
+pub fn init(config: Config) {
+    *current_lock().write().unwrap() = Arc::new(config);
+}
+
+/// Returns the configuration in effect.
+pub fn current() -> Arc<Config> {
+    current_lock().read().unwrap().clone()
+}
+
+/// Loads the configuration again from the same configuration file and command line overrides
+/// (and the environment), sets it in effect and returns the settings that changed (see
+/// `Config::changes`.) The configuration in effect is kept if the new one is invalid.
+pub async fn reload() -> Result<Vec<(&'static str, Applies)>> {
+    let previous = current();
+    let config = Config::load(&previous.path, &previous.overrides).await?;
+    let changes = previous.changes(&config);
+    init(config);
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(values: &[(&'static str, &str)]) -> Values {
+        let mut result = Values::default();
+        for (env_var, value) in values {
+            result.insert(env_var, value.to_string(), Source::Cli);
+        }
+        result
+    }
+
+    fn settings_error(env_var: &'static str, value: &str) -> String {
+        Settings::from_values(&values(&[(env_var, value)]))
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn toml_values_are_converted_to_their_form_in_the_environment() {
+        let values = Values::from_toml(
+            "config.toml",
+            r#"
+            socks-port = 1081
+            encryption = true
+            pairing = "random"
+            reverse-ports = [8080, 22]
+            forwards = ["8443=app.viam.com:443", "2222=[::1]:22"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(values.var(SOCKS_PORT_ENV_VAR).unwrap(), "1081");
+        assert_eq!(values.var(ENCRYPTION_ENV_VAR).unwrap(), "true");
+        assert_eq!(values.var(PAIRING_ENV_VAR).unwrap(), "random");
+        assert_eq!(values.var(REVERSE_PORTS_ENV_VAR).unwrap(), "8080,22");
+        assert_eq!(
+            values.var(FORWARDS_ENV_VAR).unwrap(),
+            "8443=app.viam.com:443,2222=[::1]:22"
+        );
+        assert!(values.var(SCAN_TIMEOUT_ENV_VAR).is_err());
+    }
+
+    #[test]
+    fn toml_with_unknown_keys_or_wrong_types_is_rejected() {
+        let error = Values::from_toml("config.toml", "socks-prot = 1081")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unknown setting \"socks-prot\""), "{error}");
+        let error = Values::from_toml("config.toml", "socks-port = \"1081\"")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("expected an integer but found string"),
+            "{error}"
+        );
+        assert!(Values::from_toml("config.toml", "encryption = 1").is_err());
+        assert!(Values::from_toml("config.toml", "reverse-ports = [true]").is_err());
+        assert!(Values::from_toml("config.toml", "socks-port = ").is_err());
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let settings = Settings::default();
+        assert_eq!(settings.socks_port, socks::DEFAULT_PORT);
+        assert_eq!(settings.max_mobile_devices, 1);
+        assert_eq!(settings.scan_timeout, central::DEFAULT_SCAN_TIMEOUT);
+        assert_eq!(
+            settings.fast_reconnect_timeout,
+            DEFAULT_FAST_RECONNECT_TIMEOUT
+        );
+        assert!(settings.forwards.is_empty());
+    }
+
+    #[test]
+    fn valid_values_are_interpreted() {
+        let settings = Settings::from_values(&values(&[
+            (SOCKS_PORT_ENV_VAR, "1081"),
+            (RECV_MTU_OVERRIDE_ENV_VAR, "8192"),
+            (LOAD_BALANCING_ENV_VAR, "destination-hash"),
+            (MIN_RSSI_ENV_VAR, "-85"),
+            (GATT_RESOLUTION_TIMEOUT_ENV_VAR, "45"),
+            (FORWARDS_ENV_VAR, "8443=app.viam.com:443"),
+        ]))
+        .unwrap();
+        assert_eq!(settings.socks_port, 1081);
+        assert_eq!(settings.recv_mtu, Some(8192));
+        assert_eq!(settings.load_balancing, LoadBalancing::DestinationHash);
+        assert_eq!(settings.min_rssi, Some(-85));
+        assert_eq!(settings.gatt_resolution_timeout, Duration::from_secs(45));
+        assert_eq!(settings.forwards[0].destination(), "app.viam.com:443");
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        for (env_var, value) in [
+            (SOCKS_PORT_ENV_VAR, "99999"),
+            (SOCKS_PORT_ENV_VAR, "0"),
+            (RECV_MTU_OVERRIDE_ENV_VAR, "70000"),
+            (LOAD_BALANCING_ENV_VAR, "foo"),
+            (FORWARDS_ENV_VAR, "8443=app.viam.com"),
+            (
+                FORWARDS_ENV_VAR,
+                "8443=app.viam.com:443,8443=example.com:443",
+            ),
+            (FORWARDS_ENV_VAR, "1080=app.viam.com:443"),
+            (REVERSE_PORTS_ENV_VAR, "8080,ssh"),
+            (SCAN_TIMEOUT_ENV_VAR, "two minutes"),
+            (SCAN_TIMEOUT_ENV_VAR, "0"),
+            (MIN_RSSI_ENV_VAR, "-85dBm"),
+            (MAX_MOBILE_DEVICES_ENV_VAR, "many"),
+            (MAX_MOBILE_DEVICES_ENV_VAR, "0"),
+            (FAST_RECONNECT_TIMEOUT_ENV_VAR, "-1"),
+            (ENCRYPTION_ENV_VAR, "yes"),
+            (ADAPTER_ENV_VAR, ""),
+        ] {
+            let error = settings_error(env_var, value);
+            assert!(
+                error.contains(env_var) || error.contains(value),
+                "{env_var}={value}: {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn changes_compare_values_as_given() {
+        let previous = Config {
+            values: values(&[(SOCKS_PORT_ENV_VAR, "1081"), (PAIRING_ENV_VAR, "random")]),
+            ..Default::default()
+        };
+        let config = Config {
+            values: values(&[(PAIRING_ENV_VAR, "random"), (SCAN_TIMEOUT_ENV_VAR, "60")]),
+            ..Default::default()
+        };
+        assert_eq!(
+            previous.changes(&config),
+            vec![
+                ("socks-port", Applies::NextBridge),
+                ("scan-timeout", Applies::Now)
+            ]
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! Defines the control socket through which the command line interface asks a running SOCKS
+//! forwarder to make changes, so that they do not race with (or get overwritten by) the
+//! forwarder itself.
+//!
+//! Each connection carries one request line and one response line. Responses start with "ok " or
+//! "error " followed by a message.
+
+use std::io::ErrorKind;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use bluer::Address;
+use log::{debug, info, warn};
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::watch;
+
+use crate::advertising::WakeHandle;
+use crate::bonds;
+use crate::pairing::PairingPolicy;
+use crate::state::Store;
+
+/// Path to the control socket (its directory is created by systemd.)
+const CONTROL_SOCKET_FP: &str = "/run/socks-forwarder/control.sock";
+
+/// Listens on the control socket and serves requests until the process exits. Wake requests wake
+/// up advertising through `wake`; passkey requests are answered from the pairing policy in effect
+/// in `pairing_policy`.
+pub async fn serve(
+    state: Store,
+    wake: WakeHandle,
+    pairing_policy: watch::Receiver<PairingPolicy>,
+) -> Result<()> {
+    // Remove a socket left behind by a previous run.
+    match fs::remove_file(CONTROL_SOCKET_FP).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(anyhow!("could not remove {CONTROL_SOCKET_FP:#?}: {e}")),
+    }
+    let listener = UnixListener::bind(CONTROL_SOCKET_FP)
+        .map_err(|e| anyhow!("could not bind {CONTROL_SOCKET_FP:#?}: {e}"))?;
+    info!("Listening for control requests on {CONTROL_SOCKET_FP:#?}");
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let state = state.clone();
+        let wake = wake.clone();
+        let pairing_policy = *pairing_policy.borrow();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &state, &wake, pairing_policy).await {
+                warn!("Error handling control request: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    state: &Store,
+    wake: &WakeHandle,
+    pairing_policy: PairingPolicy,
+) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut request = String::new();
+    BufReader::new(read).read_line(&mut request).await?;
+    let request = request.trim();
+    debug!("Received control request \"{request}\"");
+
+    let response = match handle_request(request, state, wake, pairing_policy).await {
+        Ok(message) => format!("ok {message}\n"),
+        Err(e) => format!("error {e}\n"),
+    };
+    write.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+async fn handle_request(
+    request: &str,
+    state: &Store,
+    wake: &WakeHandle,
+    pairing_policy: PairingPolicy,
+) -> Result<String> {
+    let words: Vec<&str> = request.split_whitespace().collect();
+    match words.as_slice() {
+        ["bonds", "remove", address] => {
+            let address = Address::from_str(address)
+                .map_err(|e| anyhow!("\"{address}\" is not a Bluetooth address: {e}"))?;
+            let session = bluer::Session::new().await?;
+            bonds::remove(&session, state, address).await?;
+            Ok(format!("removed {address}"))
+        }
+        ["bonds", "purge"] => {
+            let session = bluer::Session::new().await?;
+            let removed = bonds::purge(&session, state).await?;
+            Ok(format!("removed {} device(s)", removed.len()))
+        }
+        ["advertising", "wake"] => {
+            wake.wake();
+            Ok("woke up advertising".to_string())
+        }
+        ["pairing", "passkey"] => match pairing_policy.passkey() {
+            Some(passkey) => Ok(format!("passkey is {passkey:06}")),
+            None => Err(anyhow!("pairing policy is Just Works (no passkey)")),
+        },
+        _ => Err(anyhow!("unknown request \"{request}\"")),
+    }
+}
+
+/// Sends `request` to a running SOCKS forwarder and returns its response message, or `None` if
+/// no SOCKS forwarder is listening on the control socket.
+pub async fn request(request: &str) -> Result<Option<String>> {
+    let stream = match UnixStream::connect(CONTROL_SOCKET_FP).await {
+        Ok(stream) => stream,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+            return Ok(None);
+        }
+        Err(e) => return Err(anyhow!("could not connect to {CONTROL_SOCKET_FP:#?}: {e}")),
+    };
+    let (read, mut write) = stream.into_split();
+    write.write_all(format!("{request}\n").as_bytes()).await?;
+
+    let mut response = String::new();
+    BufReader::new(read).read_line(&mut response).await?;
+    match response.trim_end().split_once(' ') {
+        Some(("ok", message)) => Ok(Some(message.to_string())),
+        Some(("error", message)) => Err(anyhow!("{message}")),
+        _ => Err(anyhow!(
+            "unexpected control response \"{}\"",
+            response.trim_end()
+        )),
+    }
+}
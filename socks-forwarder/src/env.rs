@@ -1,62 +1,293 @@
 //! Defines logic to grab values from environment.
 
-use std::fs::File;
-use std::io::{self, BufRead};
-use std::path::Path;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::path::PathBuf;
 
 use anyhow::{anyhow, Result};
 use log::warn;
 use serde::Deserialize;
+use tokio::fs;
+use tokio::time::{sleep, Duration};
+
+use crate::config;
+use crate::reload::FileWatcher;
+use crate::status::StatusHandle;
 
 /// Path to default Viam config.
-const VIAM_CONFIG_FP: &'static str = "/etc/viam.json";
+pub const VIAM_CONFIG_FP: &str = "/etc/viam.json";
 
 /// Path to advertised BLE name file.
-const ADVERTISED_BLE_NAME_FP: &'static str = "/etc/advertised_ble_name.txt";
+pub const ADVERTISED_BLE_NAME_FP: &str = "/etc/advertised_ble_name.txt";
 
 /// Default advertised BLE name if none is specified at `ADVERSTISED_BLE_NAME_FILE`.
-const DEFAULT_ADVERTISED_BLE_NAME: &'static str = "Viam SOCKS forwarder";
+const DEFAULT_ADVERTISED_BLE_NAME: &str = "Viam SOCKS forwarder";
 
 /// Environment variable name to override the default recv MTU.
-pub const RECV_MTU_OVERRIDE_ENV_VAR: &'static str = "SOCKS_FORWARDER_RECV_MTU";
+pub const RECV_MTU_OVERRIDE_ENV_VAR: &str = "SOCKS_FORWARDER_RECV_MTU";
+
+/// Environment variable name to set the local port to accept SOCKS connections on (defaults to
+/// 1080.)
+pub const SOCKS_PORT_ENV_VAR: &str = "SOCKS_FORWARDER_SOCKS_PORT";
+
+/// Environment variable name to set the name to advertise over BLE (defaults to the first line of
+/// `ADVERTISED_BLE_NAME_FP`, else "Viam SOCKS forwarder".)
+pub const ADVERTISED_NAME_ENV_VAR: &str = "SOCKS_FORWARDER_ADVERTISED_NAME";
+
+/// Environment variable name to set the path to the Viam cloud config (defaults to
+/// "/etc/viam.json".)
+pub const VIAM_CONFIG_ENV_VAR: &str = "SOCKS_FORWARDER_VIAM_CONFIG";
+
+/// Environment variable name to select the Bluetooth adapter(s) to use, by name (e.g. "hci1") or
+/// address, or "all" to use every adapter at once (defaults to the default adapter.)
+pub const ADAPTER_ENV_VAR: &str = "SOCKS_FORWARDER_ADAPTER";
+
+/// Environment variable name to set the pairing policy ("just-works", "static" or "random";
+/// defaults to "static".)
+pub const PAIRING_ENV_VAR: &str = "SOCKS_FORWARDER_PAIRING";
+
+/// Environment variable name to set the passkey for the static pairing policy (defaults to
+/// 123456.)
+pub const PASSKEY_ENV_VAR: &str = "SOCKS_FORWARDER_PASSKEY";
+
+/// Environment variable name to set the maximum number of mobile devices to bridge through at
+/// once (defaults to 1.)
+pub const MAX_MOBILE_DEVICES_ENV_VAR: &str = "SOCKS_FORWARDER_MAX_MOBILE_DEVICES";
+
+/// Environment variable name to set how connections are spread across bridged mobile devices
+/// ("round-robin", "least-outstanding-bytes" or "destination-hash"; defaults to "round-robin".)
+pub const LOAD_BALANCING_ENV_VAR: &str = "SOCKS_FORWARDER_LOAD_BALANCING";
+
+/// Environment variable name to require mobile devices to authenticate their name writes with an
+/// HMAC keyed by the machine part secret ("true" or "false"; defaults to "false".)
+pub const AUTHENTICATED_REGISTRATION_ENV_VAR: &str = "SOCKS_FORWARDER_AUTHENTICATED_REGISTRATION";
+
+/// Environment variable name to set the link security required of mobile devices ("open",
+/// "encrypted" or "authenticated"; defaults to "open".)
+pub const SECURITY_LEVEL_ENV_VAR: &str = "SOCKS_FORWARDER_SECURITY_LEVEL";
+
+/// Environment variable name to require end-to-end encryption of the multiplexed L2CAP stream
+/// with a key derived from the machine part secret ("true" or "false"; defaults to "false".)
+pub const ENCRYPTION_ENV_VAR: &str = "SOCKS_FORWARDER_ENCRYPTION";
+
+/// Environment variable name to set the (fast) advertising interval range used during the fast
+/// window ("<min>-<max>" in milliseconds; defaults to "20-100".)
+pub const ADVERTISING_INTERVAL_ENV_VAR: &str = "SOCKS_FORWARDER_ADVERTISING_INTERVAL";
+
+/// Environment variable name to set the slow advertising interval range used after the fast
+/// window ("<min>-<max>" in milliseconds; defaults to "1000-1500".)
+pub const ADVERTISING_SLOW_INTERVAL_ENV_VAR: &str = "SOCKS_FORWARDER_ADVERTISING_SLOW_INTERVAL";
+
+/// Environment variable name to set how many seconds to advertise at the (fast) advertising
+/// interval after start, a lost bridge or a wake request (defaults to 60.)
+pub const ADVERTISING_FAST_WINDOW_ENV_VAR: &str = "SOCKS_FORWARDER_ADVERTISING_FAST_WINDOW";
+
+/// Environment variable name to set after how many seconds without a bridge (counted from start,
+/// a lost bridge or a wake request) to stop advertising until woken up (defaults to never.)
+pub const ADVERTISING_QUIET_AFTER_ENV_VAR: &str = "SOCKS_FORWARDER_ADVERTISING_QUIET_AFTER";
+
+/// Environment variable name to request an advertising TX power in dBm (defaults to the adapter's
+/// choice.)
+pub const ADVERTISING_TX_POWER_ENV_VAR: &str = "SOCKS_FORWARDER_ADVERTISING_TX_POWER";
+
+/// Environment variable name to include the machine part ID and state record in the advertisement
+/// as manufacturer data ("true" or "false"; defaults to "false".)
+pub const ADVERTISING_MANUFACTURER_DATA_ENV_VAR: &str =
+    "SOCKS_FORWARDER_ADVERTISING_MANUFACTURER_DATA";
+
+/// Environment variable name to include the machine part ID and state record in the advertisement
+/// as service data ("true" or "false"; defaults to "false".)
+pub const ADVERTISING_SERVICE_DATA_ENV_VAR: &str = "SOCKS_FORWARDER_ADVERTISING_SERVICE_DATA";
+
+/// Environment variable name to set how many seconds to scan for a mobile device before giving up
+/// (defaults to 120.)
+pub const SCAN_TIMEOUT_ENV_VAR: &str = "SOCKS_FORWARDER_SCAN_TIMEOUT";
+
+/// Environment variable name to set how many seconds to try reconnecting directly to known mobile
+/// devices before advertising and waiting for a mobile device name to be written (defaults to 20.)
+pub const FAST_RECONNECT_TIMEOUT_ENV_VAR: &str = "SOCKS_FORWARDER_FAST_RECONNECT_TIMEOUT";
+
+/// Environment variable name to set how many seconds to wait for a mobile device's GATT services
+/// to resolve after connecting to it (defaults to 30.)
+pub const GATT_RESOLUTION_TIMEOUT_ENV_VAR: &str = "SOCKS_FORWARDER_GATT_RESOLUTION_TIMEOUT";
+
+/// Environment variable name to set the minimum RSSI in dBm at which a mobile device must be
+/// received to be connected to (defaults to no minimum.)
+pub const MIN_RSSI_ENV_VAR: &str = "SOCKS_FORWARDER_MIN_RSSI";
+
+/// Environment variable name to set how mobile devices register ("peripheral" to wait for them
+/// to write their names, or "central" to scan for the names in `MOBILE_DEVICE_NAMES_ENV_VAR`;
+/// defaults to "peripheral".)
+pub const REGISTRATION_MODE_ENV_VAR: &str = "SOCKS_FORWARDER_REGISTRATION_MODE";
+
+/// Environment variable name to set the comma-separated mobile device names to scan for in the
+/// central registration mode.
+pub const MOBILE_DEVICE_NAMES_ENV_VAR: &str = "SOCKS_FORWARDER_MOBILE_DEVICE_NAMES";
+
+/// Environment variable name to set the comma-separated local TCP ports mobile devices may open
+/// reverse streams to (defaults to none.)
+pub const REVERSE_PORTS_ENV_VAR: &str = "SOCKS_FORWARDER_REVERSE_PORTS";
+
+/// Environment variable name to set the comma-separated static forwards, each as
+/// "<local port>=<host>:<port>" (e.g. "8443=app.viam.com:443"; defaults to none.)
+pub const FORWARDS_ENV_VAR: &str = "SOCKS_FORWARDER_FORWARDS";
+
+/// How often to check for the Viam cloud config if it cannot be watched for changes.
+const VIAM_CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Deserialize)]
 struct ViamCloudConfig {
-    cloud: Cloud,
+    #[serde(default)]
+    cloud: Option<Cloud>,
 }
 
 #[derive(Deserialize)]
 struct Cloud {
-    // Other fields will exist in a Viam cloud config, but we only care about `id`.
-    id: String,
+    // Other fields will exist in a Viam cloud config, but we only care about `id` and `secret`.
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    secret: Option<String>,
 }
 
-/// Finds machine part ID from `VIAM_CONFIG_FILE`'s `id` field.
-pub async fn get_machine_part_id() -> Result<String> {
-    let viam_config_file = match File::open(Path::new(VIAM_CONFIG_FP)) {
-        Ok(file) => file,
-        _ => {
-            return Err(anyhow!(
-                "could not open file from file path \"{VIAM_CONFIG_FP}\"; ensure Viam cloud config is available at that location"
-            ));
+/// Why the Viam cloud config cannot be used.
+#[derive(Debug)]
+pub enum ViamConfigError {
+    /// The file does not exist (or cannot be read.)
+    Missing { path: String, source: io::Error },
+    /// The file is not valid JSON, or not a JSON object of the expected shape.
+    InvalidJson {
+        path: String,
+        source: serde_json::Error,
+    },
+    /// The file has no (or an empty) `cloud.id` field.
+    MissingCloudId { path: String },
+}
+
+impl fmt::Display for ViamConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing { path, source } => write!(
+                f,
+                "could not read Viam cloud config \"{path}\" ({source}); ensure Viam cloud config is available at that location"
+            ),
+            Self::InvalidJson { path, source } => write!(
+                f,
+                "Viam cloud config \"{path}\" could not be parsed as a Viam cloud config ({source}); ensure cloud config is well formed"
+            ),
+            Self::MissingCloudId { path } => write!(
+                f,
+                "Viam cloud config \"{path}\" did not contain a `cloud.id` field; ensure cloud config is well formed"
+            ),
         }
-    };
-    let viam_cloud_config: ViamCloudConfig = match serde_json::from_reader(&viam_config_file) {
-        Ok(vcc) => vcc,
-        _ => {
-            return Err(anyhow!(
-                "contents of \"{VIAM_CONFIG_FP}\" did not contain a Viam cloud config with an `id` field; ensure cloud config is well formed"
-            ));
+    }
+}
+
+impl std::error::Error for ViamConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Missing { source, .. } => Some(source),
+            Self::InvalidJson { source, .. } => Some(source),
+            Self::MissingCloudId { .. } => None,
         }
-    };
-    Ok(viam_cloud_config.cloud.id)
+    }
+}
+
+/// Returns the path to the Viam cloud config: `VIAM_CONFIG_ENV_VAR` if set, else
+/// `VIAM_CONFIG_FP`.
+pub fn viam_config_fp() -> String {
+    config::current().settings.viam_config.clone()
+}
+
+/// Finds machine part ID from `VIAM_CONFIG_FILE`'s `cloud.id` field.
+pub async fn get_machine_part_id() -> Result<String, ViamConfigError> {
+    let path = viam_config_fp();
+    read_viam_cloud_config(&path)
+        .await?
+        .cloud
+        .and_then(|cloud| cloud.id)
+        .filter(|id| !id.is_empty())
+        .ok_or(ViamConfigError::MissingCloudId { path })
+}
+
+/// Waits for `VIAM_CONFIG_FILE` to exist and contain a machine part ID, and returns it. Rather
+/// than polling, the file is watched for changes with inotify. Each new reason the file cannot be
+/// used is logged and reported through `status`.
+pub async fn wait_for_machine_part_id(status: &StatusHandle) -> String {
+    let mut last_error = None;
+    let mut logged_watch_error = false;
+    loop {
+        // Watch before reading so that a change in between is not missed.
+        let path = viam_config_fp();
+        let watcher = FileWatcher::new(&[PathBuf::from(&path)]);
+        let e = match get_machine_part_id().await {
+            Ok(machine_part_id) => return machine_part_id,
+            Err(e) => e,
+        };
+        if last_error != Some(mem::discriminant(&e)) {
+            warn!("{e}");
+            warn!("SOCKS forwarder not functional until machine part ID can be fetched");
+            status.set_error(&e);
+            last_error = Some(mem::discriminant(&e));
+        }
+
+        let watched = match watcher {
+            Ok(mut watcher) => watcher.changed().await.map(|()| watcher),
+            Err(e) => Err(e),
+        };
+        match watched {
+            Ok(mut watcher) => watcher.settle().await,
+            Err(e) => {
+                if !logged_watch_error {
+                    warn!("Cannot watch \"{path}\" for changes: {e}; checking it every {VIAM_CONFIG_POLL_INTERVAL:?} instead");
+                    logged_watch_error = true;
+                }
+                sleep(VIAM_CONFIG_POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// Finds machine part secret from `VIAM_CONFIG_FILE`'s `secret` field.
+pub async fn get_machine_part_secret() -> Result<String> {
+    let path = viam_config_fp();
+    match read_viam_cloud_config(&path)
+        .await?
+        .cloud
+        .and_then(|cloud| cloud.secret)
+    {
+        Some(secret) if !secret.is_empty() => Ok(secret),
+        _ => Err(anyhow!(
+            "Viam cloud config at \"{path}\" did not contain a `secret` field; ensure cloud config is well formed"
+        )),
+    }
 }
 
-/// Finds name to advertise over BLE from `ADVERSTISED_BLE_NAME_FILE` or default value.
+/// Reads and parses the Viam cloud config at `path`.
+async fn read_viam_cloud_config(path: &str) -> Result<ViamCloudConfig, ViamConfigError> {
+    let contents = fs::read(path)
+        .await
+        .map_err(|source| ViamConfigError::Missing {
+            path: path.to_string(),
+            source,
+        })?;
+    serde_json::from_slice(&contents).map_err(|source| ViamConfigError::InvalidJson {
+        path: path.to_string(),
+        source,
+    })
+}
+
+/// Finds name to advertise over BLE from `ADVERTISED_NAME_ENV_VAR`, `ADVERSTISED_BLE_NAME_FILE`
+/// or default value.
 pub async fn get_advertised_ble_name() -> Result<String> {
+    if let Some(advertised_ble_name) = &config::current().settings.advertised_name {
+        return Ok(advertised_ble_name.clone());
+    }
+
     // Assume that advertised BLE name is present in first line of `ADVERTISED_BLE_NAME_FP`.
-    let advertised_ble_name_file = match File::open(Path::new(ADVERTISED_BLE_NAME_FP)) {
-        Ok(file) => file,
+    let contents = match fs::read_to_string(ADVERTISED_BLE_NAME_FP).await {
+        Ok(contents) => contents,
         _ => {
             warn!(
                 "Could not open file from file path {ADVERTISED_BLE_NAME_FP:#?}; \
@@ -65,11 +296,10 @@ pub async fn get_advertised_ble_name() -> Result<String> {
             return Ok(DEFAULT_ADVERTISED_BLE_NAME.to_string());
         }
     };
-    let mut lines = io::BufReader::new(advertised_ble_name_file).lines();
 
-    match lines.next() {
-        Some(Ok(line)) => Ok(line),
-        _ => {
+    match contents.lines().next() {
+        Some(line) => Ok(line.to_string()),
+        None => {
             warn!(
                 "Could not read name to advertise from file path {ADVERTISED_BLE_NAME_FP:#?}); \
                     defaulting to \"{DEFAULT_ADVERTISED_BLE_NAME}\" as advertised name"
@@ -78,3 +308,106 @@ pub async fn get_advertised_ble_name() -> Result<String> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tempfile::TempDir;
+    use tokio::sync::Mutex;
+
+    use super::*;
+
+    /// Held by each test while its configuration is in effect, as it is global.
+    static CONFIG: Mutex<()> = Mutex::const_new(());
+
+    /// Puts a configuration in effect whose `viam-config` setting is `viam_config`, through a
+    /// configuration file in `dir`.
+    async fn init_viam_config(dir: &TempDir, viam_config: &Path) {
+        let path = dir.path().join("socks-forwarder.toml");
+        fs::write(
+            &path,
+            format!("viam-config = {:?}\n", viam_config.display().to_string()),
+        )
+        .await
+        .unwrap();
+        let config = config::Config::load(path.to_str().unwrap(), &Default::default())
+            .await
+            .unwrap();
+        config::init(config);
+    }
+
+    /// Returns the error reading the machine part ID from a Viam cloud config with `contents`
+    /// (or none if `None`.)
+    async fn machine_part_id_error(contents: Option<&str>) -> (ViamConfigError, String) {
+        let dir = TempDir::new().unwrap();
+        let viam_config = dir.path().join("viam.json");
+        if let Some(contents) = contents {
+            fs::write(&viam_config, contents).await.unwrap();
+        }
+        let _config = CONFIG.lock().await;
+        init_viam_config(&dir, &viam_config).await;
+        let error = get_machine_part_id().await.unwrap_err();
+        (error, viam_config.display().to_string())
+    }
+
+    #[tokio::test]
+    async fn missing_viam_config_is_reported() {
+        let (error, path) = machine_part_id_error(None).await;
+        assert!(
+            matches!(&error, ViamConfigError::Missing { path: p, .. } if *p == path),
+            "{error:?}"
+        );
+        assert!(error.to_string().contains(&path));
+    }
+
+    #[tokio::test]
+    async fn invalid_json_is_reported() {
+        let (error, path) = machine_part_id_error(Some("{\"cloud\": {\"id\": ")).await;
+        assert!(
+            matches!(&error, ViamConfigError::InvalidJson { path: p, .. } if *p == path),
+            "{error:?}"
+        );
+        let (error, _) = machine_part_id_error(Some("{\"cloud\": \"part\"}")).await;
+        assert!(
+            matches!(error, ViamConfigError::InvalidJson { .. }),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_cloud_id_is_reported() {
+        for contents in ["{}", "{\"cloud\": {}}", "{\"cloud\": {\"secret\": \"s\"}}"] {
+            let (error, path) = machine_part_id_error(Some(contents)).await;
+            assert!(
+                matches!(&error, ViamConfigError::MissingCloudId { path: p } if *p == path),
+                "{contents}: {error:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_cloud_id_is_reported() {
+        let (error, _) = machine_part_id_error(Some("{\"cloud\": {\"id\": \"\"}}")).await;
+        assert!(
+            matches!(error, ViamConfigError::MissingCloudId { .. }),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn cloud_id_is_read_from_viam_config_setting() {
+        let dir = TempDir::new().unwrap();
+        let viam_config = dir.path().join("viam.json");
+        fs::write(
+            &viam_config,
+            "{\"cloud\": {\"id\": \"part\", \"secret\": \"s\"}}",
+        )
+        .await
+        .unwrap();
+        let _config = CONFIG.lock().await;
+        init_viam_config(&dir, &viam_config).await;
+        assert_eq!(get_machine_part_id().await.unwrap(), "part");
+        assert_eq!(get_machine_part_secret().await.unwrap(), "s");
+    }
+}
@@ -0,0 +1,45 @@
+//! Defines the identity the peripheral serves and advertises, which can change while the SOCKS
+//! forwarder runs (e.g. when the machine is reprovisioned; see `reload`.)
+
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// Machine part ID and name the peripheral serves and advertises.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub machine_part_id: String,
+    pub advertised_ble_name: String,
+}
+
+/// Shared, observable identity. Cheap to clone; clones share the identity.
+#[derive(Clone, Debug)]
+pub struct IdentityHandle(Arc<watch::Sender<Identity>>);
+
+impl IdentityHandle {
+    /// Returns a handle to `identity`.
+    pub fn new(identity: Identity) -> Self {
+        IdentityHandle(Arc::new(watch::Sender::new(identity)))
+    }
+
+    /// Returns the current identity.
+    pub fn get(&self) -> Identity {
+        self.0.borrow().clone()
+    }
+
+    /// Returns a receiver that is notified of every identity change.
+    pub fn subscribe(&self) -> watch::Receiver<Identity> {
+        self.0.subscribe()
+    }
+
+    /// Sets the identity and returns whether it changed.
+    pub fn set(&self, identity: Identity) -> bool {
+        self.0.send_if_modified(|current| {
+            if *current == identity {
+                return false;
+            }
+            *current = identity;
+            true
+        })
+    }
+}
@@ -1,15 +1,33 @@
 //! The Viam socks-forwarder process (runs as a systemd service.)
 
+mod advertising;
+mod allowlist;
+mod bonds;
+mod capabilities;
 mod central;
+mod cli;
+mod config;
+mod control;
 mod env;
+mod identity;
+mod pairing;
 mod peripheral;
+mod recovery;
+mod registration;
+mod reload;
+mod security;
 mod socks;
+mod state;
+mod status;
 
-use anyhow::Result;
-use bluer::agent::{Agent, AgentHandle, ReqResult};
-use futures::FutureExt;
+use anyhow::{anyhow, Result};
+use bluer::agent::AgentHandle;
+use clap::Parser;
+use futures::future::select_ok;
 use log::{debug, info, warn};
-use tokio::signal::unix::{signal, SignalKind};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time::{timeout, Duration};
 use uuid::uuid;
 
 /// BLE service UUID for all Viam characteristics (local and remote.)
@@ -21,142 +39,656 @@ const MACHINE_PART_ID_CHAR_UUID: uuid::Uuid = uuid!("918ce61c-199f-419e-b6d5-598
 /// BLE characteristic UUID to receive mobile device names on.
 const MOBILE_DEVICE_NAME_CHAR_UUID: uuid::Uuid = uuid!("918ce61c-199f-419e-b6d5-59883a0049d8");
 
+/// BLE characteristic UUID to hand out registration nonces on (only served when registration is
+/// authenticated.)
+const NONCE_CHAR_UUID: uuid::Uuid = uuid!("918ce61c-199f-419e-b6d5-59883a0049d9");
+
+/// BLE characteristic UUID to report the status of the SOCKS forwarder on.
+const STATUS_CHAR_UUID: uuid::Uuid = uuid!("918ce61c-199f-419e-b6d5-59883a0049da");
+
 /// BLE characteristic UUID for the remote PSM (seen by us as a central.)
 const PSM_CHARACTERISTIC_UUID: uuid::Uuid = uuid!("ab76ead2-b6e6-4f12-a053-61cd0eed19f9");
 
-/// Utility function to return ok from box.
-async fn return_ok() -> ReqResult<()> {
-    Ok(())
+/// Default for how long to try reconnecting directly to the last known mobile device before falling
+/// back to advertising and waiting for a mobile device name to be written.
+const DEFAULT_FAST_RECONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// How long to look for each additional registered mobile device to bridge through once one has
+/// been found (only when more than one mobile device may be bridged through at once.)
+const ADDITIONAL_MOBILE_DEVICE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A mobile device found to be waiting for L2CAP connections.
+struct FoundMobileDevice {
+    device: bluer::Device,
+    name: String,
+    capabilities: capabilities::Capabilities,
 }
 
-/// Utility function to return a hardcoded passkey from box.
-async fn return_hardcoded_passkey() -> ReqResult<u32> {
-    Ok(123456)
+/// Bluetooth session, agent, adapters and peripheral, all kept so that the machine stays visible
+/// (and mobile devices can register) even while bridged. Set up again (between bridges) when a
+/// setting it depends on changes (see `config::Applies::Bluetooth`.)
+struct Bluetooth {
+    session: bluer::Session,
+    _agent_handle: AgentHandle,
+    adapters: Vec<bluer::Adapter>,
+    peripheral: peripheral::Peripheral,
+    /// Machine part ID and name the peripheral serves and advertises, updated on reload.
+    identity: identity::IdentityHandle,
 }
 
-/// Advertises a BLE device with the Viam service UUID and two characteristics: one from which the
-/// machine part id of this device can be read, and one to which a mobile device name can be be
-/// written. Once a name is written, scans for another BLE device with that mobile device name and
-/// a corresponding Viam service UUID and PSM characteristic. It then returns the device, the
-/// discovered PSM, and the agent handle.
-async fn find_viam_mobile_device_and_psm() -> Result<(bluer::Device, u16, AgentHandle)> {
-    // Get the machine part id from `/etc/viam.json` and retry upon failure. A non-existent or
-    // corrupted `/etc/viam.json` likely means the machine has not yet been provisioned. There
-    // will be no traffic to forward until the device is provisioned.
-    let mut logged_no_machine_part_id_warning = false;
-    let machine_part_id = loop {
-        match env::get_machine_part_id().await {
-            Ok(name) => break name,
-            Err(e) => {
-                if !logged_no_machine_part_id_warning {
-                    warn!("{e}");
-                    warn!("SOCKS forwarder not functional until machine part ID can be fetched");
-                    logged_no_machine_part_id_warning = true;
-                }
+/// Sets up Bluetooth as `settings` describe: registers the agent for the pairing policy, powers on
+/// the selected adapters and starts advertising a BLE device with the Viam service UUID and
+/// characteristics from which the machine part ID of this device and the status of the SOCKS
+/// forwarder can be read, and to which a mobile device name can be written (see
+/// `peripheral::Peripheral`.) The peripheral serves `identity` if given (when setting Bluetooth up
+/// again), else a new one.
+///
+/// If registration is authenticated, mobile devices must prove knowledge of the machine part
+/// secret when writing their names (see `registration`.) Mobile devices must write their names
+/// over a link secured as the security level requires.
+async fn start_bluetooth(
+    state: &state::Store,
+    settings: &config::Settings,
+    identity: Option<identity::IdentityHandle>,
+    wake: &advertising::WakeHandle,
+    status: &status::StatusHandle,
+) -> Result<Bluetooth> {
+    status.set_state(status::State::Idle);
+    let pairing_policy = settings.pairing_policy;
+    pairing_policy.log();
+    let security_level = settings.security_level;
+    security_level.log();
+    if settings.authenticated_registration {
+        info!("Mobile devices must authenticate with the machine part secret to register");
+    }
+    settings.advertising.log();
 
-                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-            }
-        }
+    // Wait for the machine part id in the Viam cloud config. A non-existent or corrupted Viam
+    // cloud config likely means the machine has not yet been provisioned. There will be no
+    // traffic to forward until the device is provisioned.
+    let machine_part_id = env::wait_for_machine_part_id(status).await;
+    info!(
+        "Machine part ID fetched from `{}`: {machine_part_id}",
+        env::viam_config_fp()
+    );
+    let challenge = if settings.authenticated_registration {
+        let secret = env::get_machine_part_secret().await?;
+        Some(registration::Challenge::new(&secret))
+    } else {
+        None
     };
-    info!("Machine part ID fetched from `/etc/viam.json`: {machine_part_id}");
 
     debug!("Getting bluer session");
     let session = bluer::Session::new().await?;
 
     debug!("Registering custom agent");
-    let agent = Agent {
-        request_default: true,
-        request_pin_code: None,
-        display_pin_code: None,
-        request_passkey: Some(Box::new(move |_req| {
-            debug!("auto generating passkey 123456");
-            return_hardcoded_passkey().boxed()
-        })),
-        display_passkey: None,
-        request_confirmation: Some(Box::new(move |req| {
-            debug!("auto confirming passkey {}", req.passkey);
-            return_ok().boxed()
-        })),
-        request_authorization: Some(Box::new(|_| {
-            debug!("auto accepting pair");
-            return_ok().boxed()
-        })),
-        authorize_service: Some(Box::new(|_| return_ok().boxed())),
-        ..Default::default()
+    let pending_pairing = if security_level.requires_encryption() {
+        pairing::PendingPairing::before_registration(state.clone())
+    } else {
+        pairing::PendingPairing::new()
     };
-    let handle = session.register_agent(agent).await?;
-
-    debug!("Getting default adapter");
-    let adapter = session.default_adapter().await?;
-    if !adapter.is_powered().await? {
-        adapter.set_powered(true).await?;
-    }
-    log_adapter_info(&adapter).await?;
+    let agent = pairing::agent(pairing_policy, pending_pairing.clone());
+    let agent_handle = session.register_agent(agent).await?;
 
+    let adapters = select_adapters(&session, settings.adapter.as_deref()).await?;
     let advertised_ble_name = env::get_advertised_ble_name().await?;
-    // This alias is what shows up in pairing requests.
-    adapter.set_alias(advertised_ble_name.clone()).await?;
+    for adapter in &adapters {
+        if !adapter.is_powered().await? {
+            adapter.set_powered(true).await?;
+        }
+        log_adapter_info(adapter).await?;
+        // This alias is what shows up in pairing requests.
+        adapter.set_alias(advertised_ble_name.clone()).await?;
+    }
 
-    info!("Advertising self='{advertised_ble_name}' on service='{VIAM_SERVICE_UUID}' characteristic='{MOBILE_DEVICE_NAME_CHAR_UUID}'");
-    let mobile_device_name = peripheral::advertise_and_find_mobile_device_name(
-        &adapter,
+    info!(
+        "Advertising self='{advertised_ble_name}' service='{VIAM_SERVICE_UUID}' characteristic='{MOBILE_DEVICE_NAME_CHAR_UUID}'"
+    );
+    let new_identity = identity::Identity {
         machine_part_id,
         advertised_ble_name,
-        VIAM_SERVICE_UUID,
-        MACHINE_PART_ID_CHAR_UUID,
-        MOBILE_DEVICE_NAME_CHAR_UUID,
+    };
+    let identity = match identity {
+        Some(identity) => {
+            identity.set(new_identity);
+            identity
+        }
+        None => identity::IdentityHandle::new(new_identity),
+    };
+    let config = peripheral::PeripheralConfig {
+        svc_uuid: VIAM_SERVICE_UUID,
+        machine_part_id_char_uuid: MACHINE_PART_ID_CHAR_UUID,
+        mobile_device_name_char_uuid: MOBILE_DEVICE_NAME_CHAR_UUID,
+        nonce_char_uuid: NONCE_CHAR_UUID,
+        status_char_uuid: STATUS_CHAR_UUID,
+        challenge,
+        security_level,
+        advertising: settings.advertising.clone(),
+    };
+    let peripheral = peripheral::Peripheral::start(
+        &adapters,
+        config,
+        identity.clone(),
+        pending_pairing,
+        status.clone(),
+        state.clone(),
+        wake.clone(),
     )
     .await?;
-    info!("Mobile device name is '{mobile_device_name}'");
-
-    let (device, psm) = central::find_device_and_psm(
-        &adapter,
-        mobile_device_name,
-        VIAM_SERVICE_UUID,
-        MOBILE_DEVICE_NAME_CHAR_UUID,
-        PSM_CHARACTERISTIC_UUID,
+    Ok(Bluetooth {
+        session,
+        _agent_handle: agent_handle,
+        adapters,
+        peripheral,
+        identity,
+    })
+}
+
+/// Waits for a mobile device to register through `bluetooth`'s peripheral (by writing its name),
+/// then scans for a BLE device with that mobile device name and a corresponding Viam service UUID
+/// and PSM characteristic. It then records the device in `state` and returns it (along with any
+/// additional mobile devices found as described below.) In the central `registration_mode`,
+/// scans for a BLE device with any of the configured mobile device names right away instead.
+///
+/// If `state` knows of registered mobile devices, first tries to find any of them again directly
+/// (without waiting for a registration) for the `fast-reconnect-timeout` setting. This includes
+/// mobile devices that registered as standbys while bridged. To fail over after a lost bridge,
+/// `previous_mobile_device_name` is only tried if no other mobile device is registered.
+///
+/// If more than one mobile device may be bridged through at once (the `max-mobile-devices`
+/// setting), also looks for other registered mobile devices.
+///
+/// Mobile devices that repeatedly fail to connect or resolve GATT services are recovered through
+/// `recovery`. What is being done is reported through `status`.
+async fn find_viam_mobile_device_and_psm(
+    bluetooth: &Bluetooth,
+    registration_mode: &registration::RegistrationMode,
+    state: &state::Store,
+    previous_mobile_device_name: Option<&str>,
+    recovery: &recovery::Recovery,
+    status: &status::StatusHandle,
+) -> Result<Vec<FoundMobileDevice>> {
+    // Mobile devices that registered while bridged are already in `state` and are tried by the
+    // fast reconnect below.
+    bluetooth.peripheral.discard_registrations();
+
+    status.set_state(status::State::Scanning);
+    let found = match fast_reconnect(
+        &bluetooth.adapters,
+        state,
+        previous_mobile_device_name,
+        recovery,
     )
-    .await?;
-    info!(
-        "Found device at address '{}' that is waiting for l2cap connections on psm '{psm}'; connecting",
-        device.remote_address().await?
+    .await?
+    {
+        Some(found) => found,
+        None => {
+            let (device, name, capabilities) = match registration_mode {
+                registration::RegistrationMode::Peripheral => {
+                    status.set_state(status::State::Registering);
+                    let registration = bluetooth.peripheral.next_registration().await?;
+                    info!(
+                        "Mobile device name is '{}' (registered from address='{}' on adapter='{}')",
+                        registration.name, registration.address, registration.adapter_name
+                    );
+                    status.set_state(status::State::Scanning);
+
+                    let adapter = bluetooth.session.adapter(&registration.adapter_name)?;
+                    central::find_device_and_psm(
+                        &adapter,
+                        &[registration.name],
+                        VIAM_SERVICE_UUID,
+                        MOBILE_DEVICE_NAME_CHAR_UUID,
+                        PSM_CHARACTERISTIC_UUID,
+                        recovery,
+                    )
+                    .await?
+                }
+                registration::RegistrationMode::Central(names) => {
+                    // Scan on every adapter; the first to find a mobile device wins.
+                    let finds = bluetooth.adapters.iter().map(|adapter| {
+                        Box::pin(central::find_device_and_psm(
+                            adapter,
+                            names,
+                            VIAM_SERVICE_UUID,
+                            MOBILE_DEVICE_NAME_CHAR_UUID,
+                            PSM_CHARACTERISTIC_UUID,
+                            recovery,
+                        ))
+                    });
+                    select_ok(finds).await?.0
+                }
+            };
+            let address = device.remote_address().await?;
+            let psm = capabilities.psm;
+            info!("Found device at address '{address}' that is waiting for l2cap connections on psm '{psm}'; connecting");
+
+            state.lock().record_success(&name, address, psm);
+            state.save_or_warn().await;
+            FoundMobileDevice {
+                device,
+                name,
+                capabilities,
+            }
+        }
+    };
+
+    // Look for additional mobile devices on the adapter the first was found on.
+    let adapter = bluetooth.session.adapter(found.device.adapter_name())?;
+    let mut found = vec![found];
+    find_additional_mobile_devices(&adapter, state, &mut found, recovery).await?;
+    Ok(found)
+}
+
+/// Sets up Bluetooth (see `start_bluetooth`), retrying upon failure. Returns `None` if SIGTERM or
+/// SIGINT is received first.
+async fn start_bluetooth_until_stopped(
+    state: &state::Store,
+    settings: &config::Settings,
+    identity: Option<identity::IdentityHandle>,
+    wake: &advertising::WakeHandle,
+    status: &status::StatusHandle,
+    sigterm: &mut Signal,
+    sigint: &mut Signal,
+) -> Option<Bluetooth> {
+    loop {
+        tokio::select! {
+            start_result = start_bluetooth(state, settings, identity.clone(), wake, status) => {
+                match start_result {
+                    Ok(bluetooth) => return Some(bluetooth),
+                    Err(e) => {
+                        warn!("Error setting up Bluetooth: {e}; retrying");
+                        status.set_error(&e);
+                        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                    }
+                }
+            }
+            _ = sigterm.recv() => {
+                info!("Received SIGTERM signal while setting up Bluetooth; stopping the SOCKS forwarder");
+                return None;
+            },
+            _ = sigint.recv() => {
+                info!("Received SIGINT signal while setting up Bluetooth; stopping the SOCKS forwarder");
+                return None;
+            }
+        }
+    }
+}
+
+/// Returns the adapters selected by `selection` (the `adapter` setting): the adapter with the
+/// specified name or address, every adapter if it is "all", or the default adapter if it is not
+/// set.
+async fn select_adapters(
+    session: &bluer::Session,
+    selection: Option<&str>,
+) -> Result<Vec<bluer::Adapter>> {
+    let selection = match selection {
+        Some(selection) => selection,
+        None => {
+            debug!("Getting default adapter");
+            return Ok(vec![session.default_adapter().await?]);
+        }
+    };
+
+    let mut adapters = Vec::new();
+    for name in session.adapter_names().await? {
+        let adapter = session.adapter(&name)?;
+        if selection == "all"
+            || selection == name
+            || selection.eq_ignore_ascii_case(&adapter.address().await?.to_string())
+        {
+            adapters.push(adapter);
+        }
+    }
+    if adapters.is_empty() {
+        return Err(anyhow!("no Bluetooth adapter matches \"{selection}\""));
+    }
+    debug!(
+        "Using adapter(s) {:?}",
+        adapters.iter().map(|a| a.name()).collect::<Vec<_>>()
     );
+    Ok(adapters)
+}
 
-    Ok((device, psm, handle))
+/// Tries to find any registered mobile device (see `state::State::failover_candidates`) directly
+/// on any of `adapters` for up to the `fast-reconnect-timeout` setting, connecting to the address
+/// each was last found at before scanning for them. Returns `None` if none could be found.
+async fn fast_reconnect(
+    adapters: &[bluer::Adapter],
+    state: &state::Store,
+    previous_mobile_device_name: Option<&str>,
+    recovery: &recovery::Recovery,
+) -> Result<Option<FoundMobileDevice>> {
+    let candidates = state
+        .lock()
+        .failover_candidates(previous_mobile_device_name);
+    if candidates.is_empty() {
+        return Ok(None);
+    }
+
+    info!("Attempting fast reconnect to registered mobile device(s) {candidates:?}");
+    let fast_reconnect_timeout = config::current().settings.fast_reconnect_timeout;
+    let candidates_ref = &candidates;
+    let reconnects = adapters.iter().map(|adapter| {
+        Box::pin(async move {
+            let reconnect = async {
+                // Connect straight to the addresses the mobile devices were last found at; only
+                // scan if none of them is there any more.
+                for name in candidates_ref {
+                    let Some(address) = state.lock().address(name) else {
+                        continue;
+                    };
+                    match central::connect_known_device(
+                        adapter,
+                        address,
+                        std::slice::from_ref(name),
+                        VIAM_SERVICE_UUID,
+                        MOBILE_DEVICE_NAME_CHAR_UUID,
+                        PSM_CHARACTERISTIC_UUID,
+                    )
+                    .await
+                    {
+                        Ok(Some(found)) => return Ok(found),
+                        Ok(None) => {}
+                        Err(e) => debug!(
+                            "Could not connect directly to mobile device '{name}' at address '{address}': {e}"
+                        ),
+                    }
+                }
+                central::find_device_and_psm(
+                    adapter,
+                    candidates_ref,
+                    VIAM_SERVICE_UUID,
+                    MOBILE_DEVICE_NAME_CHAR_UUID,
+                    PSM_CHARACTERISTIC_UUID,
+                    recovery,
+                )
+                .await
+            };
+            match timeout(fast_reconnect_timeout, reconnect).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow!("did not succeed after {fast_reconnect_timeout:?}")),
+            }
+        })
+    });
+    match select_ok(reconnects).await {
+        Ok(((device, name, capabilities), _)) => {
+            let address = device.remote_address().await?;
+            let psm = capabilities.psm;
+            info!("Reconnected to mobile device '{name}' at address '{address}' that is waiting for l2cap connections on psm '{psm}'; connecting");
+            state.lock().record_success(&name, address, psm);
+            state.save_or_warn().await;
+            return Ok(Some(FoundMobileDevice {
+                device,
+                name,
+                capabilities,
+            }));
+        }
+        Err(e) => {
+            warn!("Fast reconnect to registered mobile devices failed: {e}; falling back to advertising");
+        }
+    }
+    for name in &candidates {
+        state.lock().record_failure(name);
+    }
+    state.save_or_warn().await;
+    Ok(None)
+}
+
+/// Adds other registered mobile devices to `found` (each found within
+/// `ADDITIONAL_MOBILE_DEVICE_TIMEOUT`) until the `max-mobile-devices` setting's number of mobile
+/// devices have been found or no more can be.
+async fn find_additional_mobile_devices(
+    adapter: &bluer::Adapter,
+    state: &state::Store,
+    found: &mut Vec<FoundMobileDevice>,
+    recovery: &recovery::Recovery,
+) -> Result<()> {
+    let max_mobile_devices = config::current().settings.max_mobile_devices;
+
+    while found.len() < max_mobile_devices {
+        let mut candidates = state.lock().failover_candidates(None);
+        candidates.retain(|name| !found.iter().any(|f| f.name == *name));
+        if candidates.is_empty() {
+            break;
+        }
+
+        info!("Looking for additional registered mobile device(s) {candidates:?}");
+        // Power cycling the adapter would disconnect the mobile devices already found on it.
+        let recovery = recovery.without_adapter_reset();
+        let find = central::find_device_and_psm(
+            adapter,
+            &candidates,
+            VIAM_SERVICE_UUID,
+            MOBILE_DEVICE_NAME_CHAR_UUID,
+            PSM_CHARACTERISTIC_UUID,
+            &recovery,
+        );
+        match timeout(ADDITIONAL_MOBILE_DEVICE_TIMEOUT, find).await {
+            Ok(Ok((device, name, capabilities))) => {
+                let address = device.remote_address().await?;
+                let psm = capabilities.psm;
+                info!("Found additional mobile device '{name}' at address '{address}' that is waiting for l2cap connections on psm '{psm}'");
+                state.lock().record_success(&name, address, psm);
+                state.save_or_warn().await;
+                found.push(FoundMobileDevice {
+                    device,
+                    name,
+                    capabilities,
+                });
+            }
+            Ok(Err(e)) => {
+                debug!("No additional mobile devices found: {e}");
+                break;
+            }
+            Err(_) => {
+                debug!(
+                    "No additional mobile devices found after {ADDITIONAL_MOBILE_DEVICE_TIMEOUT:?}"
+                );
+                break;
+            }
+        }
+    }
+    Ok(())
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
     env_logger::init();
+
+    let cli = cli::Cli::parse();
+    config::init(config::Config::load(&cli.config, &cli.overrides).await?);
+    if cli.check_config {
+        return cli::check_config().await;
+    }
+    if let Some(command) = cli.command {
+        return command.run().await;
+    }
+
     info!("Started the SOCKS forwarder");
 
     let mut sigterm = signal(SignalKind::terminate())?;
     let mut sigint = signal(SignalKind::interrupt())?;
+    // Handled from the start so that SIGHUP does not terminate the forwarder before it can reload.
+    let sighup = signal(SignalKind::hangup())?;
+
+    // Configuration Bluetooth was last set up with.
+    let mut bluetooth_config = config::current();
+    let mut registration_mode = bluetooth_config.settings.registration_mode.clone();
+    registration_mode.log();
+    let mut encryption = bluetooth_config.settings.encryption;
+    if encryption {
+        info!("L2CAP streams will be end-to-end encrypted with the machine part secret");
+    }
+
+    // Persisted across iterations (and restarts) so that a dropped bridge can be re-established
+    // without waiting for the mobile device to write its name again.
+    let state = state::Store::load().await;
+    // Kept across iterations so that failures with a stuck mobile device accumulate.
+    let recovery = recovery::Recovery::default();
+    let status = status::StatusHandle::default();
+    // Woken up by SIGUSR1 or through the control socket.
+    let wake = advertising::WakeHandle::default();
+    // Kept so that a random passkey stays the same until the pairing policy changes.
+    let (pairing_policy_send, pairing_policy) =
+        watch::channel(bluetooth_config.settings.pairing_policy);
+    let control_state = state.clone();
+    let control_wake = wake.clone();
+    tokio::spawn(async move {
+        if let Err(e) = control::serve(control_state, control_wake, pairing_policy).await {
+            warn!("Control socket unavailable: {e}");
+        }
+    });
+    let mut sigusr1 = signal(SignalKind::user_defined1())?;
+    let sigusr1_wake = wake.clone();
+    tokio::spawn(async move {
+        while sigusr1.recv().await.is_some() {
+            sigusr1_wake.wake();
+        }
+    });
+    let Some(mut bluetooth) = start_bluetooth_until_stopped(
+        &state,
+        &bluetooth_config.settings,
+        None,
+        &wake,
+        &status,
+        &mut sigterm,
+        &mut sigint,
+    )
+    .await
+    else {
+        return Ok(());
+    };
+
+    // Notified when reloaded settings are waiting for the main loop, so that they apply right
+    // away while no bridge is up.
+    let (settings_changed_send, mut settings_changed) = watch::channel(());
+    tokio::spawn(reload::reload_forever(
+        bluetooth.identity.clone(),
+        sighup,
+        settings_changed_send,
+    ));
+
+    // Name of the mobile device the last bridge was established with.
+    let mut previous_mobile_device_name: Option<String> = None;
 
     loop {
+        // Read again for each bridge so that reloaded settings apply once the last one drained
+        // (or right away while looking for mobile devices; see below.)
+        settings_changed.borrow_and_update();
+        let config = config::current();
+        let bluetooth_changes: Vec<&str> = bluetooth_config
+            .changes(&config)
+            .into_iter()
+            .filter(|(_, applies)| *applies == config::Applies::Bluetooth)
+            .map(|(key, _)| key)
+            .collect();
+        if !bluetooth_changes.is_empty() {
+            info!("Setting(s) {bluetooth_changes:?} changed; setting up Bluetooth again");
+            let mut settings = config.settings.clone();
+            if !bluetooth_changes.contains(&"pairing") && !bluetooth_changes.contains(&"passkey") {
+                // Keep a random passkey, which is generated anew each time the configuration is
+                // loaded.
+                settings.pairing_policy = *pairing_policy_send.borrow();
+            }
+            pairing_policy_send.send_replace(settings.pairing_policy);
+            let identity = bluetooth.identity.clone();
+            // Unregisters the agent, GATT applications and advertisements before they are
+            // registered again.
+            drop(bluetooth);
+            bluetooth = match start_bluetooth_until_stopped(
+                &state,
+                &settings,
+                Some(identity),
+                &wake,
+                &status,
+                &mut sigterm,
+                &mut sigint,
+            )
+            .await
+            {
+                Some(bluetooth) => bluetooth,
+                None => break,
+            };
+            bluetooth_config = config.clone();
+        }
+        let settings = &config.settings;
+        let security_level = bluetooth_config.settings.security_level;
+        if settings.registration_mode != registration_mode {
+            settings.registration_mode.log();
+            registration_mode = settings.registration_mode.clone();
+        }
+        if settings.encryption != encryption {
+            info!(
+                "End-to-end encryption of L2CAP streams is now {}",
+                if settings.encryption {
+                    "enabled"
+                } else {
+                    "disabled"
+                }
+            );
+            encryption = settings.encryption;
+        }
+
         tokio::select! {
-            find_result = find_viam_mobile_device_and_psm() => {
+            find_result = find_viam_mobile_device_and_psm(&bluetooth, &registration_mode, &state, previous_mobile_device_name.as_deref(), &recovery, &status) => {
                 match find_result {
-                    Ok((device, psm, handle)) => {
-                        match socks::start_forwarder(device, psm).await {
+                    Ok(found) => {
+                        previous_mobile_device_name = Some(found[0].name.clone());
+                        let devices: Vec<(bluer::Device, capabilities::Capabilities)> = found.into_iter().map(|f| (f.device, f.capabilities)).collect();
+                        let machine_part_secret = if encryption {
+                            match env::get_machine_part_secret().await {
+                                Ok(secret) => Some(secret),
+                                Err(e) => {
+                                    warn!("Cannot encrypt L2CAP streams: {e}; restarting the SOCKS forwarder");
+                                    status.set_error(&e);
+                                    // Left connected, they would block the next attempt.
+                                    socks::disconnect_devices(&devices).await;
+                                    continue;
+                                }
+                            }
+                        } else {
+                            None
+                        };
+                        // Sampled for as long as the bridge is up so that a mobile device
+                        // that is too far away shows up in the status.
+                        let rssi_monitor = tokio::spawn(central::monitor_rssi(
+                            devices.iter().map(|(device, _)| device.clone()).collect(),
+                            VIAM_SERVICE_UUID,
+                            settings.min_rssi,
+                            status.clone(),
+                        ));
+                        let forwarder_result = socks::start_forwarder(devices, machine_part_secret, security_level, &state, &status).await;
+                        rssi_monitor.abort();
+                        status.set_rssi(None);
+                        match forwarder_result {
                             Ok(true) => {
                                 continue
                             }
                             Ok(false) => {
-                                drop(handle);
                                 break;
                             }
                             Err(e) => {
                                 warn!("Error starting SOCKS forwarder: {e}; restarting the SOCKS forwarder");
+                                // Mobile devices that could not be bridged through were recorded
+                                // as failing by the SOCKS forwarder.
+                                status.set_error(&e);
                             }
                         }
                     },
                     Err(e) => {
                         warn!("Error while scanning for mobile device: {e}; restarting the SOCKS forwarder");
+                        status.set_error(&e);
                         continue;
                     }
                 }
             }
+            Ok(()) = settings_changed.changed() => {
+                // No bridge is up to drain, so stop looking for mobile devices and apply the
+                // settings (setting up Bluetooth again if need be.)
+                info!("Settings changed while looking for mobile devices; applying them right away");
+                continue;
+            }
             _ = sigterm.recv() => {
                 info!("Received SIGTERM signal while scanning for mobile device; stopping the SOCKS forwarder");
                 break;
@@ -242,7 +774,7 @@ async fn log_adapter_info(adapter: &bluer::Adapter) -> Result<()> {
         ));
     }
 
-    properties_log.push_str("}");
+    properties_log.push('}');
 
     debug!("{}", properties_log);
     Ok(())
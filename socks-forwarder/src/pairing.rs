@@ -0,0 +1,374 @@
+//! Defines the pairing policy and the bluez agent that enforces it.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use bluer::agent::{Agent, ReqError, ReqResult};
+use bluer::Address;
+use futures::FutureExt;
+use log::{debug, info, warn};
+use rand::Rng;
+
+use crate::allowlist::Allowlist;
+use crate::config::Values;
+use crate::env::{PAIRING_ENV_VAR, PASSKEY_ENV_VAR};
+use crate::state::Store;
+
+/// Passkey used by the static pairing policy if none is specified at `PASSKEY_ENV_VAR`.
+const DEFAULT_STATIC_PASSKEY: u32 = 123456;
+
+/// How pairing with mobile devices is authenticated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PairingPolicy {
+    /// Pair without a passkey (no MITM protection.)
+    JustWorks,
+    /// Pair with a passkey that is fixed in configuration.
+    StaticPasskey(u32),
+    /// Pair with a passkey that is randomly generated each time the forwarder starts (or the
+    /// pairing policy changes.)
+    RandomPasskey(u32),
+}
+
+impl PairingPolicy {
+    /// Reads the pairing policy from `PAIRING_ENV_VAR` ("just-works", "static" or "random";
+    /// defaults to "static") and, for the static policy, the passkey from `PASSKEY_ENV_VAR`.
+    pub fn from_values(values: &Values) -> Result<Self> {
+        let policy = values
+            .var(PAIRING_ENV_VAR)
+            .unwrap_or_else(|_| "static".to_string());
+        match policy.as_str() {
+            "just-works" => Ok(Self::JustWorks),
+            "static" => {
+                let passkey = match values.var(PASSKEY_ENV_VAR) {
+                    Ok(passkey) => parse_passkey(&passkey)?,
+                    Err(_) => DEFAULT_STATIC_PASSKEY,
+                };
+                Ok(Self::StaticPasskey(passkey))
+            }
+            "random" => Ok(Self::RandomPasskey(rand::thread_rng().gen_range(0..=999999))),
+            _ => Err(anyhow!(
+                "unknown pairing policy \"{policy}\"; expected \"just-works\", \"static\" or \"random\""
+            )),
+        }
+    }
+
+    /// Returns the passkey used by this policy, if any.
+    pub fn passkey(&self) -> Option<u32> {
+        match self {
+            Self::JustWorks => None,
+            Self::StaticPasskey(passkey) | Self::RandomPasskey(passkey) => Some(*passkey),
+        }
+    }
+
+    /// Returns whether the agent accepts numeric comparison confirmations with this policy. Only
+    /// Just Works does: with a passkey policy, accepting them would let bluez bond without the
+    /// passkey ever being entered.
+    pub fn accepts_confirmation(&self) -> bool {
+        *self == Self::JustWorks
+    }
+
+    /// Returns whether pairing with this policy is authenticated with a passkey that is not
+    /// publicly known (the default static passkey does not count), which the agent makes mobile
+    /// devices enter (see `accepts_confirmation`.)
+    pub fn authenticates(&self) -> bool {
+        if self.accepts_confirmation() {
+            return false;
+        }
+        match self {
+            Self::JustWorks => false,
+            Self::StaticPasskey(passkey) => *passkey != DEFAULT_STATIC_PASSKEY,
+            Self::RandomPasskey(_) => true,
+        }
+    }
+
+    /// Logs the policy (and, so it can be entered on the mobile device, a random passkey.)
+    pub fn log(&self) {
+        match self {
+            Self::JustWorks => info!("Pairing policy is Just Works (no passkey)"),
+            Self::StaticPasskey(_) => info!("Pairing policy is a static passkey"),
+            Self::RandomPasskey(passkey) => {
+                info!("Pairing policy is a random passkey; passkey for this boot is {passkey:06}")
+            }
+        }
+    }
+}
+
+/// Parses a passkey of at most six decimal digits.
+fn parse_passkey(passkey: &str) -> Result<u32> {
+    match passkey.parse::<u32>() {
+        Ok(passkey) if passkey <= 999999 => Ok(passkey),
+        _ => Err(anyhow!(
+            "passkey \"{passkey}\" is not a number between 000000 and 999999"
+        )),
+    }
+}
+
+/// The device (if any) that is currently allowed to pair: the one that most recently wrote to the
+/// mobile device name characteristic. Shared between the agent and the peripheral.
+#[derive(Clone, Debug, Default)]
+pub struct PendingPairing {
+    address: Arc<Mutex<Option<Address>>>,
+    // Set when the mobile device name characteristic requires an encrypted link, so that mobile
+    // devices pair before writing their names: registered mobile devices (and devices whose
+    // addresses are in the allowlist) may then pair too.
+    registered: Option<Store>,
+}
+
+impl PendingPairing {
+    /// Returns a `PendingPairing` that only allows the device most recently `set` to pair.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a `PendingPairing` for mobile devices that pair before writing their names: besides
+    /// the device most recently `set`, it allows mobile devices registered in `state` and devices
+    /// whose addresses are in the allowlist to pair.
+    pub fn before_registration(state: Store) -> Self {
+        PendingPairing {
+            address: Default::default(),
+            registered: Some(state),
+        }
+    }
+
+    /// Allows `address` (and only `address`) to pair.
+    pub fn set(&self, address: Address) {
+        *self.address.lock().unwrap() = Some(address);
+    }
+
+    /// Allows no device to pair.
+    pub fn clear(&self) {
+        *self.address.lock().unwrap() = None;
+    }
+
+    /// Returns whether `address` is allowed to pair.
+    async fn allows(&self, address: Address) -> bool {
+        if *self.address.lock().unwrap() == Some(address) {
+            return true;
+        }
+        let Some(state) = &self.registered else {
+            return false;
+        };
+        if state.lock().is_registered(address) {
+            return true;
+        }
+        match Allowlist::load().await {
+            Ok(allowlist) => allowlist.lists_address(address),
+            Err(e) => {
+                warn!("Could not check the allowlist ({e}); only allowing registered mobile devices to pair");
+                false
+            }
+        }
+    }
+
+    /// Returns `Ok` if `address` is allowed to pair and rejects the request otherwise.
+    async fn check(&self, address: Address, request: &str) -> ReqResult<()> {
+        if self.allows(address).await {
+            debug!("Accepting {request} from device {address}");
+            Ok(())
+        } else {
+            warn!("Rejecting {request} from device {address} that is not registering");
+            Err(ReqError::Rejected)
+        }
+    }
+}
+
+/// Answers a request from `device` to confirm `passkey` for numeric comparison as `policy`
+/// requires: accepted from the device allowed by `pending` with Just Works, and rejected with a
+/// passkey policy so that the passkey must be entered instead.
+async fn confirm(
+    policy: PairingPolicy,
+    pending: &PendingPairing,
+    device: Address,
+    passkey: u32,
+) -> ReqResult<()> {
+    let request = format!("confirmation of passkey {passkey:06}");
+    if !policy.accepts_confirmation() {
+        warn!("Rejecting {request} from device {device}; pairing requires entering the passkey");
+        return Err(ReqError::Rejected);
+    }
+    pending.check(device, &request).await
+}
+
+/// Returns an agent that enforces `policy` and only answers requests from the device allowed by
+/// `pending`.
+pub fn agent(policy: PairingPolicy, pending: PendingPairing) -> Agent {
+    let request_confirmation_pending = pending.clone();
+    let request_authorization_pending = pending.clone();
+    let authorize_service_pending = pending.clone();
+    let mut agent = Agent {
+        request_default: true,
+        request_pin_code: None,
+        display_pin_code: None,
+        // With Just Works these make bluez ask before pairing rather than pair silently, and the
+        // confirmation is accepted without comparing passkeys (no MITM protection.) With a
+        // passkey policy, confirmations are rejected so that the passkey must be entered.
+        request_confirmation: Some(Box::new(move |req| {
+            let pending = request_confirmation_pending.clone();
+            async move { confirm(policy, &pending, req.device, req.passkey).await }.boxed()
+        })),
+        request_authorization: Some(Box::new(move |req| {
+            let pending = request_authorization_pending.clone();
+            async move { pending.check(req.device, "pairing authorization").await }.boxed()
+        })),
+        authorize_service: Some(Box::new(move |req| {
+            let pending = authorize_service_pending.clone();
+            async move {
+                let request = format!("authorization of service {}", req.service);
+                pending.check(req.device, &request).await
+            }
+            .boxed()
+        })),
+        ..Default::default()
+    };
+
+    let Some(passkey) = policy.passkey() else {
+        return agent;
+    };
+    let request_passkey_pending = pending.clone();
+    let display_passkey_pending = pending;
+    agent.request_passkey = Some(Box::new(move |req| {
+        let pending = request_passkey_pending.clone();
+        async move {
+            pending.check(req.device, "passkey request").await?;
+            Ok(passkey)
+        }
+        .boxed()
+    }));
+    agent.display_passkey = Some(Box::new(move |req| {
+        let pending = display_passkey_pending.clone();
+        async move {
+            pending.check(req.device, "passkey display").await?;
+            info!("Passkey for device {} is {:06}", req.device, req.passkey);
+            Ok(())
+        }
+        .boxed()
+    }));
+    agent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Source;
+
+    const ADDRESS: Address = Address::new([0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+    const OTHER_ADDRESS: Address = Address::new([0xcb, 0xa9, 0x87, 0x65, 0x43, 0x21]);
+
+    fn pairing_policy(policy: Option<&str>, passkey: Option<&str>) -> Result<PairingPolicy> {
+        let mut values = Values::default();
+        if let Some(policy) = policy {
+            values.insert(PAIRING_ENV_VAR, policy.to_string(), Source::Cli);
+        }
+        if let Some(passkey) = passkey {
+            values.insert(PASSKEY_ENV_VAR, passkey.to_string(), Source::Cli);
+        }
+        PairingPolicy::from_values(&values)
+    }
+
+    #[test]
+    fn pairing_policy_defaults_to_the_default_static_passkey() {
+        assert_eq!(
+            pairing_policy(None, None).unwrap(),
+            PairingPolicy::StaticPasskey(DEFAULT_STATIC_PASSKEY)
+        );
+        assert_eq!(
+            pairing_policy(Some("static"), None).unwrap(),
+            PairingPolicy::StaticPasskey(DEFAULT_STATIC_PASSKEY)
+        );
+    }
+
+    #[test]
+    fn pairing_policies_are_read() {
+        assert_eq!(
+            pairing_policy(Some("just-works"), Some("000042")).unwrap(),
+            PairingPolicy::JustWorks
+        );
+        assert_eq!(
+            pairing_policy(Some("static"), Some("000042")).unwrap(),
+            PairingPolicy::StaticPasskey(42)
+        );
+        let random = pairing_policy(Some("random"), None).unwrap();
+        assert!(matches!(random, PairingPolicy::RandomPasskey(passkey) if passkey <= 999999));
+    }
+
+    #[test]
+    fn unknown_pairing_policy_is_rejected() {
+        assert!(pairing_policy(Some("Static"), None).is_err());
+        assert!(pairing_policy(Some(""), None).is_err());
+    }
+
+    #[test]
+    fn passkeys_are_bounded() {
+        assert_eq!(parse_passkey("0").unwrap(), 0);
+        assert_eq!(parse_passkey("999999").unwrap(), 999999);
+        for passkey in ["1000000", "-1", "12345a", ""] {
+            assert!(parse_passkey(passkey).is_err(), "{passkey}");
+            assert!(pairing_policy(Some("static"), Some(passkey)).is_err());
+        }
+    }
+
+    #[test]
+    fn default_static_passkey_does_not_authenticate() {
+        assert!(!PairingPolicy::JustWorks.authenticates());
+        assert!(!PairingPolicy::StaticPasskey(DEFAULT_STATIC_PASSKEY).authenticates());
+        assert!(PairingPolicy::StaticPasskey(42).authenticates());
+        assert!(PairingPolicy::RandomPasskey(DEFAULT_STATIC_PASSKEY).authenticates());
+    }
+
+    #[tokio::test]
+    async fn pending_device_is_allowed_to_pair() {
+        let pending = PendingPairing::new();
+        assert!(!pending.allows(ADDRESS).await);
+        assert!(pending.check(ADDRESS, "pairing").await.is_err());
+
+        pending.set(ADDRESS);
+        assert!(pending.allows(ADDRESS).await);
+        assert!(pending.check(ADDRESS, "pairing").await.is_ok());
+        assert!(!pending.allows(OTHER_ADDRESS).await);
+        assert!(pending.check(OTHER_ADDRESS, "pairing").await.is_err());
+
+        pending.set(OTHER_ADDRESS);
+        assert!(!pending.allows(ADDRESS).await);
+        pending.clear();
+        assert!(!pending.allows(OTHER_ADDRESS).await);
+    }
+
+    #[tokio::test]
+    async fn confirmations_are_only_accepted_with_just_works() {
+        let pending = PendingPairing::new();
+        pending.set(ADDRESS);
+        assert!(confirm(PairingPolicy::JustWorks, &pending, ADDRESS, 0)
+            .await
+            .is_ok());
+        assert!(
+            confirm(PairingPolicy::JustWorks, &pending, OTHER_ADDRESS, 0)
+                .await
+                .is_err()
+        );
+        for policy in [
+            PairingPolicy::StaticPasskey(DEFAULT_STATIC_PASSKEY),
+            PairingPolicy::StaticPasskey(42),
+            PairingPolicy::RandomPasskey(42),
+        ] {
+            assert!(confirm(policy, &pending, ADDRESS, 42).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn authenticating_policies_make_mobile_devices_enter_the_passkey() {
+        let pending = PendingPairing::new();
+        pending.set(ADDRESS);
+        for policy in [
+            PairingPolicy::JustWorks,
+            PairingPolicy::StaticPasskey(DEFAULT_STATIC_PASSKEY),
+            PairingPolicy::StaticPasskey(42),
+            PairingPolicy::RandomPasskey(42),
+        ] {
+            if policy.authenticates() {
+                assert!(policy.passkey().is_some(), "{policy:?}");
+                assert!(!policy.accepts_confirmation(), "{policy:?}");
+                assert!(confirm(policy, &pending, ADDRESS, 42).await.is_err());
+            }
+        }
+    }
+}
@@ -1,144 +1,617 @@
 //! Defines peripheral logic.
 
+use std::str::from_utf8;
+
 use anyhow::{anyhow, Result};
+use async_channel::{Receiver, Sender};
 use bluer::{
-    adv::Advertisement,
     gatt::local::{
-        characteristic_control, Application, Characteristic, CharacteristicControlEvent,
-        CharacteristicRead, CharacteristicWrite, CharacteristicWriteMethod, Service,
+        Application, ApplicationHandle, Characteristic, CharacteristicNotify,
+        CharacteristicNotifyMethod, CharacteristicRead, CharacteristicWrite,
+        CharacteristicWriteMethod, ReqError, ReqResult, Service,
     },
-    Adapter,
+    Adapter, AdapterEvent, AdapterProperty, Address,
 };
-use futures::{pin_mut, FutureExt, StreamExt};
-use log::{debug, info};
-use std::{str::from_utf8, time::Duration};
-use tokio::io::AsyncReadExt;
+use futures::{FutureExt, StreamExt};
+use log::{debug, info, warn};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
 use uuid::Uuid;
 
-/// Advertises a peripheral device:
+use crate::advertising::{AdvertisingConfig, WakeHandle};
+use crate::allowlist::Allowlist;
+use crate::identity::IdentityHandle;
+use crate::pairing::PendingPairing;
+use crate::registration::Challenge;
+use crate::security::SecurityLevel;
+use crate::state::Store;
+use crate::status::{State, StatusHandle};
+
+/// How long to wait before retrying a failed advertisement registration.
+const ADVERTISE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// What the peripheral advertises and serves.
+#[derive(Clone)]
+pub struct PeripheralConfig {
+    pub svc_uuid: Uuid,
+    pub machine_part_id_char_uuid: Uuid,
+    pub mobile_device_name_char_uuid: Uuid,
+    pub nonce_char_uuid: Uuid,
+    pub status_char_uuid: Uuid,
+    /// Challenge that mobile device name writes must answer, if any.
+    pub challenge: Option<Challenge>,
+    /// Link security required of mobile device name writes.
+    pub security_level: SecurityLevel,
+    pub advertising: AdvertisingConfig,
+}
+
+/// A mobile device that wrote its name and was paired with and trusted.
+pub struct Registration {
+    /// Name of the adapter the mobile device registered through.
+    pub adapter_name: String,
+    pub address: Address,
+    pub name: String,
+}
+
+/// A peripheral device advertised (and served) on a set of adapters for as long as it lives:
 ///
-/// - with adapter `adapter`
 /// - with a service IDed as `svc_uuid`
-/// - with a read characteristic IDed as `machine_part_id_char_uuid` with `machine_part_id`
+/// - with a read characteristic IDed as `machine_part_id_char_uuid` with the machine part ID
 /// - with a write characteristic IDed as `mobile_device_name_char_uuid`
+/// - if `challenge` is set, with a read characteristic IDed as `nonce_char_uuid` that hands out a
+///   fresh nonce to each reader
+/// - with a read/notify characteristic IDed as `status_char_uuid` with the status as JSON
+/// - as `advertising` configures, with a record of the machine part ID and the current state of
+///   the SOCKS forwarder (see `advertising::record`)
 ///
-/// Waits for a BLE central to write a UTF8-encoded string to that characteristic and returns the
-/// written value (or an error.)
-pub async fn advertise_and_find_mobile_device_name(
-    adapter: &Adapter,
-    machine_part_id: String,
-    advertised_ble_name: String,
-    svc_uuid: Uuid,
-    machine_part_id_uuid: Uuid,
-    mobile_device_name_char_uuid: Uuid,
-) -> Result<String> {
-    let le_advertisement = Advertisement {
-        advertisement_type: bluer::adv::Type::Peripheral,
-        service_uuids: vec![svc_uuid].into_iter().collect(),
-        discoverable: Some(true),
-        min_interval: Some(Duration::from_millis(20)),
-        max_interval: Some(Duration::from_millis(100)),
-        local_name: Some(advertised_ble_name),
-        ..Default::default()
+/// The machine part ID and advertised name are those of the current identity, and are served and
+/// advertised anew whenever it changes.
+///
+/// Each BLE central that writes a UTF8-encoded string to the mobile device name characteristic is
+/// paired with and trusted (allowing only it to pair through `pending_pairing` meanwhile),
+/// recorded as a registered mobile device, and made available through `next_registration`. This
+/// happens even while bridged, so that other mobile devices can register as standbys. Writes from
+/// mobile devices not permitted by the allowlist are rejected with a GATT error and cause no
+/// pairing or trust changes. If `challenge` is set, so are writes that do not carry a valid HMAC
+/// over the writer's nonce.
+///
+/// Writes must be made over a link secured as `security_level` requires. If that requires
+/// encryption, mobile devices pair before writing (so registered and allowlisted devices may pair
+/// through `pending_pairing`), and the bond with a mobile device whose write is rejected is
+/// removed unless it is registered or its address is in the allowlist.
+pub struct Peripheral {
+    registration_receive: Receiver<Registration>,
+    // Serving (which keeps the GATT applications registered) and registration tasks, aborted on
+    // drop.
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Peripheral {
+    /// Starts advertising and serving the peripheral described by `config` as `identity` on each
+    /// of `adapters`. Advertising is woken up from its slow or quiet phase through `wake`.
+    pub async fn start(
+        adapters: &[Adapter],
+        config: PeripheralConfig,
+        identity: IdentityHandle,
+        pending_pairing: PendingPairing,
+        status: StatusHandle,
+        state: Store,
+        wake: WakeHandle,
+    ) -> Result<Self> {
+        let (name_send, name_receive) = async_channel::unbounded::<NameWrite>();
+        let (registration_send, registration_receive) = async_channel::unbounded();
+
+        let mut tasks = Vec::new();
+        for adapter in adapters {
+            let app = application(adapter, &config, &identity, name_send.clone(), &status);
+            let app_handle = adapter.serve_gatt_application(app).await?;
+            tasks.push(tokio::spawn(serve_forever(
+                adapter.clone(),
+                app_handle,
+                config.clone(),
+                identity.clone(),
+                name_send.clone(),
+                status.clone(),
+                wake.clone(),
+            )));
+        }
+        tasks.push(tokio::spawn(register_forever(
+            name_receive,
+            registration_send,
+            pending_pairing,
+            state,
+        )));
+
+        info!(
+            "Advertising mobile device name char to be written to on {} adapter(s)",
+            adapters.len()
+        );
+        info!(
+            "Local machine part ID is: {}",
+            identity.get().machine_part_id
+        );
+        Ok(Peripheral {
+            registration_receive,
+            tasks,
+        })
+    }
+
+    /// Waits for the next mobile device to register.
+    pub async fn next_registration(&self) -> Result<Registration> {
+        info!("In healthy and idle state. Waiting for mobile device name to be written");
+        self.registration_receive
+            .recv()
+            .await
+            .map_err(|e| anyhow!("failed to collect a mobile device name: {e}"))
+    }
+
+    /// Discards registrations that have not been waited for (they are already recorded as
+    /// registered mobile devices.)
+    pub fn discard_registrations(&self) {
+        while let Ok(registration) = self.registration_receive.try_recv() {
+            debug!(
+                "Discarding earlier registration of mobile device '{}'",
+                registration.name
+            );
+        }
+    }
+}
+
+impl Drop for Peripheral {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// A write to the mobile device name characteristic by the device at an address on an adapter:
+/// the written name, or `None` if the write was rejected after the device had to pair to make it.
+type NameWrite = (Adapter, Address, Option<String>);
+
+/// Advertises on `adapter` (see `advertise_forever`) while keeping the GATT application of
+/// `app_handle` registered, and registers both again whenever the adapter is power cycled (e.g. by
+/// `recovery`), as that drops them.
+async fn serve_forever(
+    adapter: Adapter,
+    mut app_handle: ApplicationHandle,
+    config: PeripheralConfig,
+    identity: IdentityHandle,
+    name_send: Sender<NameWrite>,
+    status: StatusHandle,
+    wake: WakeHandle,
+) {
+    loop {
+        let advertise = advertise_forever(
+            adapter.clone(),
+            config.advertising.clone(),
+            config.svc_uuid,
+            identity.clone(),
+            status.clone(),
+            wake.clone(),
+        );
+        tokio::select! {
+            () = advertise => return,
+            () = wait_for_power_cycle(&adapter) => {}
+        }
+        info!(
+            "Adapter {} was power cycled; registering GATT application and advertisement again",
+            adapter.name()
+        );
+        drop(app_handle);
+        app_handle = loop {
+            let app = application(&adapter, &config, &identity, name_send.clone(), &status);
+            match adapter.serve_gatt_application(app).await {
+                Ok(app_handle) => break app_handle,
+                Err(e) => {
+                    warn!(
+                        "Could not register GATT application on adapter {}: {e}",
+                        adapter.name()
+                    );
+                    sleep(ADVERTISE_RETRY_DELAY).await;
+                }
+            }
+        };
+    }
+}
+
+/// Waits for `adapter` to be powered off and on again. Never returns if its events cannot be
+/// watched.
+async fn wait_for_power_cycle(adapter: &Adapter) {
+    let mut events = match adapter.events().await {
+        Ok(events) => events,
+        Err(e) => {
+            warn!(
+                "Cannot watch adapter {} for power cycles: {e}",
+                adapter.name()
+            );
+            return std::future::pending().await;
+        }
     };
-    let _adv_handle = Some(adapter.advertise(le_advertisement).await?);
-    info!("Registered advertisement");
+    let mut powered_off = false;
+    while let Some(event) = events.next().await {
+        if let AdapterEvent::PropertyChanged(AdapterProperty::Powered(powered)) = event {
+            if !powered {
+                powered_off = true;
+            } else if powered_off {
+                return;
+            }
+        }
+    }
+    std::future::pending().await
+}
 
-    let machine_part_id_copy = machine_part_id.clone();
-    let (char_control, char_handle) = characteristic_control();
-    let app = Application {
+/// Returns the GATT application described by `config` for `adapter`, serving the machine part ID
+/// of `identity`. Accepted mobile device name writes are sent to `name_send`, as are rejected ones
+/// if `config` requires encryption.
+fn application(
+    adapter: &Adapter,
+    config: &PeripheralConfig,
+    identity: &IdentityHandle,
+    name_send: Sender<NameWrite>,
+    status: &StatusHandle,
+) -> Application {
+    let identity = identity.clone();
+    let security_level = config.security_level;
+    let write_challenge = config.challenge.clone();
+    let write_adapter = adapter.clone();
+    let mut characteristics = vec![
+        Characteristic {
+            uuid: config.machine_part_id_char_uuid,
+            read: Some(CharacteristicRead {
+                read: true,
+                // this is public info
+                encrypt_read: false,
+                encrypt_authenticated_read: false,
+                secure_read: false,
+                fun: Box::new(move |_| {
+                    let machine_part_id = identity.get().machine_part_id;
+                    async move { Ok(machine_part_id.into_bytes()) }.boxed()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        Characteristic {
+            uuid: config.mobile_device_name_char_uuid,
+            write: Some(CharacteristicWrite {
+                write: true,
+                encrypt_write: security_level == SecurityLevel::Encrypted,
+                encrypt_authenticated_write: security_level == SecurityLevel::Authenticated,
+                secure_write: false,
+                method: CharacteristicWriteMethod::Fun(Box::new(move |value, req| {
+                    let name_send = name_send.clone();
+                    let challenge = write_challenge.clone();
+                    let adapter = write_adapter.clone();
+                    async move {
+                        let device_addr = req.device_address;
+                        let name = match check_name_write(device_addr, value, challenge).await {
+                            Ok(name) => name,
+                            Err(e) => {
+                                // The rejected mobile device had to pair to write; undo that
+                                // (see `register_forever`.)
+                                if security_level.requires_encryption() {
+                                    let _ = name_send.send((adapter, device_addr, None)).await;
+                                }
+                                return Err(e);
+                            }
+                        };
+                        name_send
+                            .send((adapter, device_addr, Some(name)))
+                            .await
+                            .map_err(|_| ReqError::Failed)
+                    }
+                    .boxed()
+                })),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        status_characteristic(config.status_char_uuid, status.clone()),
+    ];
+    if let Some(challenge) = config.challenge.clone() {
+        characteristics.push(Characteristic {
+            uuid: config.nonce_char_uuid,
+            read: Some(CharacteristicRead {
+                read: true,
+                fun: Box::new(move |req| {
+                    let nonce = challenge.issue_nonce(req.device_address);
+                    debug!("Issued registration nonce to device {}", req.device_address);
+                    async move { Ok(nonce.to_vec()) }.boxed()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+    }
+    Application {
         services: vec![Service {
-            uuid: svc_uuid,
+            uuid: config.svc_uuid,
             primary: true,
-            characteristics: vec![
-                Characteristic {
-                    uuid: machine_part_id_uuid,
-                    read: Some(CharacteristicRead {
-                        read: true,
-                        // this is public info
-                        encrypt_read: false,
-                        encrypt_authenticated_read: false,
-                        secure_read: false,
-                        fun: Box::new(move |_| {
-                            let device_name_clone = machine_part_id_copy.clone();
-                            async move { Ok(device_name_clone.as_bytes().to_vec()) }.boxed()
-                        }),
-                        ..Default::default()
-                    }),
-                    ..Default::default()
-                },
-                Characteristic {
-                    uuid: mobile_device_name_char_uuid,
-                    write: Some(CharacteristicWrite {
-                        write: true,
-                        encrypt_write: false,
-                        encrypt_authenticated_write: false,
-                        secure_write: false,
-                        method: CharacteristicWriteMethod::Io,
-                        ..Default::default()
-                    }),
-                    control_handle: char_handle,
-                    ..Default::default()
-                },
-            ],
+            characteristics,
             ..Default::default()
         }],
         ..Default::default()
-    };
-    let _app_handle = Some(adapter.serve_gatt_application(app).await?);
-
-    info!("Advertising mobile device name char to be written to");
-    info!("Local machine part ID is: {machine_part_id}");
-    info!("In healthy and idle state. Waiting for mobile device name to be written");
-
-    pin_mut!(char_control);
+    }
+}
 
+/// Advertises the name of `identity` and `svc_uuid` on `adapter` as `advertising` configures,
+/// along with the record of its machine part ID and the current state from `status`.
+/// Re-advertises whenever the identity, state or advertising phase changes (also renaming
+/// `adapter` when the name changes.) The fast window restarts when a bridge is lost or `wake` is
+/// woken. If the adapter rejects the advertisement as too long, the record is left out.
+async fn advertise_forever(
+    adapter: Adapter,
+    advertising: AdvertisingConfig,
+    svc_uuid: Uuid,
+    identity: IdentityHandle,
+    status: StatusHandle,
+    wake: WakeHandle,
+) {
+    let mut advertising = advertising;
+    let mut identity_receive = identity.subscribe();
+    let mut status_receive = status.subscribe();
+    let mut wake_receive = wake.subscribe();
+    let mut fast_window_start = Instant::now();
     loop {
-        let evt = char_control.next().await;
-        match evt {
-            Some(CharacteristicControlEvent::Write(req)) => {
-                debug!("Accepting write request event with MTU {}", req.mtu());
-                let device_addr = req.device_address();
-
-                let mut read_buf = vec![0; req.mtu()];
-                let mut reader = req.accept()?;
-                let num_bytes = reader.read(&mut read_buf).await?;
-                let trimmed_read_buf = &read_buf[0..num_bytes];
-                match from_utf8(trimmed_read_buf) {
-                    Ok(mobile_device_name_str) => {
-                        // Attempt to pair with the device that wrote its name to our characteristic.
-                        let device = adapter.device(device_addr)?;
-                        if !device.is_paired().await? {
-                            info!(
-                                "Pairing with device {} that wrote its proxy name",
-                                device_addr
-                            );
-                            device.pair().await?;
-                        }
-                        if !device.is_trusted().await? {
-                            // Trusting should also resolve any addresses that require resolution.
-                            info!("Trusting device {} that wrote its proxy name", device_addr);
-                            device.set_trusted(true).await?;
-                        }
+        let identity = identity_receive.borrow_and_update().clone();
+        let state = status_receive.borrow_and_update().state;
+        let (phase, phase_remaining) = advertising.phase(state, fast_window_start.elapsed());
+        let le_advertisement = advertising.advertisement(
+            phase,
+            &identity.advertised_ble_name,
+            svc_uuid,
+            &identity.machine_part_id,
+            state,
+        );
+        let _adv_handle = match le_advertisement {
+            Some(le_advertisement) => match adapter.advertise(le_advertisement).await {
+                Ok(handle) => Some(handle),
+                Err(e)
+                    if e.kind == bluer::ErrorKind::InvalidLength
+                        && advertising.carries_record() =>
+                {
+                    warn!(
+                        "Advertisement is too long for adapter {} (it may not support extended advertising); advertising without the record",
+                        adapter.name()
+                    );
+                    advertising.manufacturer_data = false;
+                    advertising.service_data = false;
+                    continue;
+                }
+                Err(e) => {
+                    warn!(
+                        "Could not register advertisement on adapter {}: {e}",
+                        adapter.name()
+                    );
+                    sleep(ADVERTISE_RETRY_DELAY).await;
+                    continue;
+                }
+            },
+            None => None,
+        };
+        debug!(
+            "Advertising in phase {phase:?} with state {state:?} on adapter {}",
+            adapter.name()
+        );
 
-                        return Ok(mobile_device_name_str.to_string());
+        // Keep the advertisement until the identity, state or phase changes.
+        let phase_end = async {
+            match phase_remaining {
+                Some(phase_remaining) => sleep(phase_remaining).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(phase_end);
+        loop {
+            tokio::select! {
+                changed = status_receive.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    let new_state = status_receive.borrow_and_update().state;
+                    if new_state == state {
+                        continue;
+                    }
+                    if state == State::Bridged {
+                        fast_window_start = Instant::now();
+                    }
+                    break;
+                }
+                changed = identity_receive.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    let name = identity_receive.borrow().advertised_ble_name.clone();
+                    if name != identity.advertised_ble_name {
+                        // This alias is what shows up in pairing requests.
+                        if let Err(e) = adapter.set_alias(name).await {
+                            warn!("Could not rename adapter {}: {e}", adapter.name());
+                        }
                     }
-                    Err(e) => {
-                        return Err(anyhow!(
-                            "written mobile device name is not a UT8-encoded string: {e}"
-                        ));
+                    break;
+                }
+                woken = wake_receive.changed() => {
+                    if woken.is_err() {
+                        return;
                     }
+                    fast_window_start = Instant::now();
+                    break;
                 }
+                _ = &mut phase_end => break,
             }
-            Some(CharacteristicControlEvent::Notify(notifier)) => {
-                debug!(
-                    "Should not happen: accepting notify request event with MTU {}",
-                    notifier.mtu()
-                );
+        }
+    }
+}
+
+/// Pairs with and trusts each mobile device whose name write is received on `name_receive`,
+/// records it as registered in `state`, and sends it to `registration_send`. Mobile devices whose
+/// writes were rejected are forgotten, unless they are registered in `state` or their addresses
+/// are in the allowlist: only bonds made to write are undone.
+async fn register_forever(
+    name_receive: Receiver<NameWrite>,
+    registration_send: Sender<Registration>,
+    pending_pairing: PendingPairing,
+    state: Store,
+) {
+    while let Ok((adapter, device_addr, mobile_device_name)) = name_receive.recv().await {
+        let Some(mobile_device_name) = mobile_device_name else {
+            if is_known(&state, device_addr).await {
+                debug!("Keeping bond with known device {device_addr} whose write was rejected");
+            } else {
+                forget_device(adapter, device_addr);
+            }
+            continue;
+        };
+        debug!("Device {device_addr} wrote mobile device name '{mobile_device_name}'");
+
+        // Attempt to pair with the device that wrote its name to our characteristic.
+        let device = match adapter.device(device_addr) {
+            Ok(device) => device,
+            Err(e) => {
+                warn!("Could not get device {device_addr} that wrote its name: {e}");
+                continue;
             }
-            None => break,
+        };
+        pending_pairing.set(device_addr);
+        let paired = pair_and_trust(&device).await;
+        pending_pairing.clear();
+        if let Err(e) = paired {
+            warn!("Could not pair with and trust device {device_addr}: {e}");
+            continue;
+        }
+
+        info!("Mobile device '{mobile_device_name}' at address '{device_addr}' registered");
+        state
+            .lock()
+            .record_registration(&mobile_device_name, device_addr);
+        state.save_or_warn().await;
+        let registration = Registration {
+            adapter_name: adapter.name().to_string(),
+            address: device_addr,
+            name: mobile_device_name,
+        };
+        if registration_send.send(registration).await.is_err() {
+            break;
         }
     }
+}
 
-    Err(anyhow!("failed to collect a mobile device name"))
+/// Returns a characteristic IDed as `status_char_uuid` that can be read for `status` as JSON, and
+/// that notifies subscribers of every change to it.
+fn status_characteristic(status_char_uuid: Uuid, status: StatusHandle) -> Characteristic {
+    let notify_status = status.clone();
+    Characteristic {
+        uuid: status_char_uuid,
+        read: Some(CharacteristicRead {
+            read: true,
+            fun: Box::new(move |req| {
+                let json = status.get().to_json();
+                // Long reads are made in pieces at increasing offsets.
+                let value = json.get(req.offset as usize..).unwrap_or_default().to_vec();
+                async move { Ok(value) }.boxed()
+            }),
+            ..Default::default()
+        }),
+        notify: Some(CharacteristicNotify {
+            notify: true,
+            method: CharacteristicNotifyMethod::Fun(Box::new(move |mut notifier| {
+                let mut status_receive = notify_status.subscribe();
+                async move {
+                    debug!("Status notification session started");
+                    while status_receive.changed().await.is_ok() {
+                        let json = status_receive.borrow_and_update().to_json();
+                        if let Err(e) = notifier.notify(json).await {
+                            debug!("Status notification session stopped: {e}");
+                            break;
+                        }
+                    }
+                }
+                .boxed()
+            })),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Checks a write of `value` to the mobile device name characteristic by the device at
+/// `device_addr` against `challenge` (if any) and the allowlist, and returns the written name.
+async fn check_name_write(
+    device_addr: Address,
+    value: Vec<u8>,
+    challenge: Option<Challenge>,
+) -> ReqResult<String> {
+    let value = match challenge {
+        Some(challenge) => match challenge.verify(device_addr, &value) {
+            Ok(name) => name,
+            Err(e) => {
+                warn!("Rejecting unauthenticated mobile device name write from {device_addr}: {e}");
+                return Err(ReqError::NotAuthorized);
+            }
+        },
+        None => value,
+    };
+    let name = match from_utf8(&value) {
+        Ok(name) => name.to_string(),
+        Err(e) => {
+            warn!("Mobile device name written by {device_addr} is not a UTF8-encoded string: {e}");
+            return Err(ReqError::Failed);
+        }
+    };
+    match Allowlist::load().await {
+        Ok(allowlist) if allowlist.permits(&name, device_addr) => Ok(name),
+        Ok(_) => {
+            warn!("Rejecting mobile device name '{name}' written by {device_addr} that is not in the allowlist");
+            Err(ReqError::NotAuthorized)
+        }
+        Err(e) => {
+            warn!("Rejecting mobile device name '{name}' written by {device_addr}: {e}");
+            Err(ReqError::NotAuthorized)
+        }
+    }
+}
+
+/// Returns whether the device at `device_addr` is registered in `state` or its address is in the
+/// allowlist (or the allowlist cannot be read, to err on the side of keeping bonds.)
+async fn is_known(state: &Store, device_addr: Address) -> bool {
+    if state.lock().is_registered(device_addr) {
+        return true;
+    }
+    match Allowlist::load().await {
+        Ok(allowlist) => allowlist.lists_address(device_addr),
+        Err(e) => {
+            warn!("Could not check the allowlist ({e}); keeping bond with device {device_addr}");
+            true
+        }
+    }
+}
+
+/// Removes the device at `device_addr` (and any bond with it) from `adapter` in the background,
+/// so that the GATT request it is in the middle of can be answered first.
+fn forget_device(adapter: Adapter, device_addr: Address) {
+    tokio::spawn(async move {
+        info!("Removing bond with rejected device {device_addr}");
+        if let Err(e) = adapter.remove_device(device_addr).await {
+            warn!("Could not remove rejected device {device_addr}: {e}");
+        }
+    });
+}
+
+/// Pairs with and trusts `device` if it is not already paired and trusted.
+async fn pair_and_trust(device: &bluer::Device) -> Result<()> {
+    let device_addr = device.address();
+    if !device.is_paired().await? {
+        info!(
+            "Pairing with device {} that wrote its proxy name",
+            device_addr
+        );
+        device.pair().await?;
+    }
+    if !device.is_trusted().await? {
+        // Trusting should also resolve any addresses that require resolution.
+        info!("Trusting device {} that wrote its proxy name", device_addr);
+        device.set_trusted(true).await?;
+    }
+    Ok(())
 }
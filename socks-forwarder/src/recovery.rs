@@ -0,0 +1,168 @@
+//! Defines automatic recovery from devices that repeatedly fail to connect or resolve GATT
+//! services (e.g. a mobile device stuck "connecting".) Consecutive failures with the same device
+//! escalate through disconnecting it, removing its bond and power cycling the adapter.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use bluer::{Adapter, Address};
+use log::{info, warn};
+use tokio::time::{sleep, Duration};
+
+/// Number of consecutive failures with a device after which it is disconnected.
+const FAILURES_BEFORE_DISCONNECT: u32 = 3;
+
+/// Number of consecutive failures with a device after which its bond is removed.
+const FAILURES_BEFORE_REMOVE_BOND: u32 = 6;
+
+/// Number of consecutive failures with a device after which the adapter is power cycled.
+const FAILURES_BEFORE_ADAPTER_RESET: u32 = 9;
+
+/// How long to leave the adapter powered off when power cycling it.
+const ADAPTER_RESET_DELAY: Duration = Duration::from_secs(2);
+
+/// A recovery step taken after a failure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// Nothing was done; the device may be retried as is.
+    None,
+    /// The device was disconnected.
+    Disconnect,
+    /// The device (and its bond) was removed; it must be discovered again.
+    RemoveBond,
+    /// The adapter was power cycled; any discovery on it has stopped.
+    ResetAdapter,
+}
+
+/// Consecutive failures per device. Cheap to clone; clones share counts.
+#[derive(Clone, Debug, Default)]
+pub struct Recovery {
+    failures: Arc<Mutex<HashMap<Address, u32>>>,
+    // Whether power cycling the adapter is ruled out (see `without_adapter_reset`.)
+    no_adapter_reset: bool,
+}
+
+impl Recovery {
+    /// Returns a `Recovery` sharing failure counts with this one that never power cycles the
+    /// adapter, for when other mobile devices already found on it would be disconnected.
+    pub fn without_adapter_reset(&self) -> Self {
+        Recovery {
+            failures: self.failures.clone(),
+            no_adapter_reset: true,
+        }
+    }
+
+    /// Records a success with the device at `address`, resetting its failure count.
+    pub fn record_success(&self, address: Address) {
+        self.failures.lock().unwrap().remove(&address);
+    }
+
+    /// Records a failure with the device at `address` on `adapter`, takes the recovery step (if
+    /// any) that the number of consecutive failures calls for, and returns it.
+    pub async fn record_failure(&self, adapter: &Adapter, address: Address) -> Step {
+        let failures = {
+            let mut failures = self.failures.lock().unwrap();
+            let count = failures.entry(address).or_default();
+            *count += 1;
+            *count
+        };
+
+        let step = step_for(failures, !self.no_adapter_reset);
+        if step == Step::None {
+            if failures == FAILURES_BEFORE_ADAPTER_RESET {
+                warn!(
+                    "Device {address} failed {failures} times in a row; not power cycling adapter {} as other mobile devices depend on it",
+                    adapter.name()
+                );
+            }
+            return step;
+        }
+
+        warn!("Device {address} failed {failures} times in a row; recovering with {step:?}");
+        let result = match step {
+            Step::Disconnect => match adapter.device(address) {
+                Ok(device) => device.disconnect().await,
+                Err(e) => Err(e),
+            },
+            Step::RemoveBond => adapter.remove_device(address).await,
+            Step::ResetAdapter => {
+                // Start over with a fresh count once the adapter has been reset.
+                self.record_success(address);
+                reset_adapter(adapter).await
+            }
+            Step::None => Ok(()),
+        };
+        match result {
+            Ok(()) => info!("Recovered device {address} with {step:?}"),
+            Err(e) => warn!("Error recovering device {address} with {step:?}: {e}"),
+        }
+        step
+    }
+}
+
+/// Returns the recovery step called for after `failures` consecutive failures with a device,
+/// never power cycling the adapter unless `may_reset_adapter` is set.
+fn step_for(failures: u32, may_reset_adapter: bool) -> Step {
+    match failures {
+        FAILURES_BEFORE_DISCONNECT => Step::Disconnect,
+        FAILURES_BEFORE_REMOVE_BOND => Step::RemoveBond,
+        FAILURES_BEFORE_ADAPTER_RESET if may_reset_adapter => Step::ResetAdapter,
+        _ => Step::None,
+    }
+}
+
+/// Power cycles `adapter`.
+async fn reset_adapter(adapter: &Adapter) -> bluer::Result<()> {
+    info!("Powering off adapter {}", adapter.name());
+    adapter.set_powered(false).await?;
+    sleep(ADAPTER_RESET_DELAY).await;
+    info!("Powering on adapter {}", adapter.name());
+    adapter.set_powered(true).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_escalate_at_thresholds() {
+        let steps: Vec<(u32, Step)> = (1..=12)
+            .map(|failures| (failures, step_for(failures, true)))
+            .filter(|(_, step)| *step != Step::None)
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                (3, Step::Disconnect),
+                (6, Step::RemoveBond),
+                (9, Step::ResetAdapter)
+            ]
+        );
+    }
+
+    #[test]
+    fn adapter_is_not_reset_when_ruled_out() {
+        assert_eq!(step_for(FAILURES_BEFORE_ADAPTER_RESET, false), Step::None);
+        assert_eq!(
+            step_for(FAILURES_BEFORE_DISCONNECT, false),
+            Step::Disconnect
+        );
+        assert_eq!(
+            step_for(FAILURES_BEFORE_REMOVE_BOND, false),
+            Step::RemoveBond
+        );
+    }
+
+    #[test]
+    fn recovery_without_adapter_reset_shares_failure_counts() {
+        let recovery = Recovery::default();
+        let without_adapter_reset = recovery.without_adapter_reset();
+        assert!(!recovery.no_adapter_reset);
+        assert!(without_adapter_reset.no_adapter_reset);
+
+        let address = Address::any();
+        recovery.failures.lock().unwrap().insert(address, 2);
+        without_adapter_reset.record_success(address);
+        assert!(recovery.failures.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,243 @@
+//! Defines how mobile devices register with this forwarder, and the optional challenge-response
+//! that they must complete to register by writing their names: the forwarder hands out a random
+//! nonce, and the mobile device's name write must carry an HMAC-SHA256, keyed by the machine part
+//! secret, over that nonce and the name.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use bluer::Address;
+use hmac::{Hmac, Mac};
+use log::info;
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::config::Values;
+use crate::env::{MOBILE_DEVICE_NAMES_ENV_VAR, REGISTRATION_MODE_ENV_VAR};
+
+/// Length in bytes of the nonces handed out to mobile devices.
+pub const NONCE_LEN: usize = 16;
+
+/// Length in bytes of the HMAC appended to a mobile device name write.
+pub const MAC_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How mobile devices register with this forwarder.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum RegistrationMode {
+    /// Mobile devices find the advertised peripheral and write their names to it.
+    #[default]
+    Peripheral,
+    /// The forwarder scans for mobile devices advertising the Viam service and connects to any
+    /// whose name is one of these, without waiting for a name write.
+    Central(Vec<String>),
+}
+
+impl RegistrationMode {
+    /// Reads the registration mode from `REGISTRATION_MODE_ENV_VAR` ("peripheral" or "central";
+    /// defaults to "peripheral".) The central mode takes its mobile device names from
+    /// `MOBILE_DEVICE_NAMES_ENV_VAR` (comma-separated), which must name at least one.
+    pub fn from_values(values: &Values) -> Result<Self> {
+        match values.var(REGISTRATION_MODE_ENV_VAR).as_deref() {
+            Err(_) | Ok("peripheral") => Ok(Self::Peripheral),
+            Ok("central") => {
+                let names = values.list(MOBILE_DEVICE_NAMES_ENV_VAR);
+                if names.is_empty() {
+                    return Err(anyhow!(
+                        "registration mode \"central\" requires mobile device names in {MOBILE_DEVICE_NAMES_ENV_VAR}"
+                    ));
+                }
+                Ok(Self::Central(names))
+            }
+            Ok(mode) => Err(anyhow!(
+                "unknown registration mode \"{mode}\"; expected \"peripheral\" or \"central\""
+            )),
+        }
+    }
+
+    /// Logs the registration mode.
+    pub fn log(&self) {
+        match self {
+            Self::Peripheral => info!("Mobile devices register by writing their names"),
+            Self::Central(names) => {
+                info!("Scanning for mobile devices {names:?} without waiting for them to register")
+            }
+        }
+    }
+}
+
+/// Nonces handed out to mobile devices and the secret their responses are checked against.
+/// Shared between the nonce and mobile device name characteristics.
+#[derive(Clone)]
+pub struct Challenge {
+    secret: Arc<Vec<u8>>,
+    // Most recent nonce handed out to each device; each nonce may be used at most once.
+    nonces: Arc<Mutex<HashMap<Address, [u8; NONCE_LEN]>>>,
+}
+
+impl Challenge {
+    /// Creates a challenge whose responses are keyed by `secret`.
+    pub fn new(secret: &str) -> Self {
+        Challenge {
+            secret: Arc::new(secret.as_bytes().to_vec()),
+            nonces: Default::default(),
+        }
+    }
+
+    /// Generates a fresh nonce for the device at `address`, replacing any previous one.
+    pub fn issue_nonce(&self, address: Address) -> [u8; NONCE_LEN] {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        self.nonces.lock().unwrap().insert(address, nonce);
+        nonce
+    }
+
+    /// Checks a mobile device name write from the device at `address`, which must be the name
+    /// followed by HMAC-SHA256(secret, nonce || name). Returns the name on success. The device's
+    /// nonce is consumed either way.
+    pub fn verify(&self, address: Address, value: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self
+            .nonces
+            .lock()
+            .unwrap()
+            .remove(&address)
+            .ok_or_else(|| anyhow!("no nonce was issued to device {address}"))?;
+        if value.len() < MAC_LEN {
+            return Err(anyhow!(
+                "write of {} bytes is too short to carry an HMAC",
+                value.len()
+            ));
+        }
+        let (name, tag) = value.split_at(value.len() - MAC_LEN);
+
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(&nonce);
+        mac.update(name);
+        mac.verify_slice(tag)
+            .map_err(|_| anyhow!("HMAC does not match"))?;
+        Ok(name.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Source;
+
+    const ADDRESS: Address = Address::new([0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+
+    /// Returns a name write for `name` answering `nonce`, keyed by `secret`.
+    fn response(secret: &str, nonce: &[u8], name: &[u8]) -> Vec<u8> {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(nonce);
+        mac.update(name);
+        [name, &mac.finalize().into_bytes()[..]].concat()
+    }
+
+    #[test]
+    fn response_keyed_by_secret_is_accepted() {
+        let challenge = Challenge::new("secret");
+        let nonce = challenge.issue_nonce(ADDRESS);
+        let name = challenge
+            .verify(ADDRESS, &response("secret", &nonce, b"phone"))
+            .unwrap();
+        assert_eq!(name, b"phone");
+    }
+
+    #[test]
+    fn response_keyed_by_another_secret_is_rejected() {
+        let challenge = Challenge::new("secret");
+        let nonce = challenge.issue_nonce(ADDRESS);
+        assert!(challenge
+            .verify(ADDRESS, &response("other", &nonce, b"phone"))
+            .is_err());
+    }
+
+    #[test]
+    fn response_to_a_replaced_nonce_is_rejected() {
+        let challenge = Challenge::new("secret");
+        let nonce = challenge.issue_nonce(ADDRESS);
+        challenge.issue_nonce(ADDRESS);
+        assert!(challenge
+            .verify(ADDRESS, &response("secret", &nonce, b"phone"))
+            .is_err());
+    }
+
+    #[test]
+    fn nonce_is_used_at_most_once() {
+        let challenge = Challenge::new("secret");
+        let nonce = challenge.issue_nonce(ADDRESS);
+        let value = response("secret", &nonce, b"phone");
+        assert!(challenge.verify(ADDRESS, &value).is_ok());
+        assert!(challenge.verify(ADDRESS, &value).is_err());
+
+        // A failed attempt consumes the nonce as well.
+        let nonce = challenge.issue_nonce(ADDRESS);
+        assert!(challenge.verify(ADDRESS, b"phone").is_err());
+        assert!(challenge
+            .verify(ADDRESS, &response("secret", &nonce, b"phone"))
+            .is_err());
+    }
+
+    #[test]
+    fn nonce_is_bound_to_the_device_it_was_issued_to() {
+        let challenge = Challenge::new("secret");
+        let nonce = challenge.issue_nonce(ADDRESS);
+        assert!(challenge
+            .verify(Address::any(), &response("secret", &nonce, b"phone"))
+            .is_err());
+    }
+
+    #[test]
+    fn tampered_name_is_rejected() {
+        let challenge = Challenge::new("secret");
+        let nonce = challenge.issue_nonce(ADDRESS);
+        let mut value = response("secret", &nonce, b"phone");
+        value[0] ^= 1;
+        assert!(challenge.verify(ADDRESS, &value).is_err());
+    }
+
+    fn registration_mode(mode: &str, names: Option<&str>) -> Result<RegistrationMode> {
+        let mut values = Values::default();
+        values.insert(REGISTRATION_MODE_ENV_VAR, mode.to_string(), Source::Cli);
+        if let Some(names) = names {
+            values.insert(MOBILE_DEVICE_NAMES_ENV_VAR, names.to_string(), Source::Cli);
+        }
+        RegistrationMode::from_values(&values)
+    }
+
+    #[test]
+    fn registration_mode_defaults_to_peripheral() {
+        assert_eq!(
+            RegistrationMode::from_values(&Values::default()).unwrap(),
+            RegistrationMode::Peripheral
+        );
+        assert_eq!(
+            registration_mode("peripheral", None).unwrap(),
+            RegistrationMode::Peripheral
+        );
+    }
+
+    #[test]
+    fn central_registration_mode_takes_mobile_device_names() {
+        assert_eq!(
+            registration_mode("central", Some(" phone, tablet ,,")).unwrap(),
+            RegistrationMode::Central(vec!["phone".to_string(), "tablet".to_string()])
+        );
+    }
+
+    #[test]
+    fn central_registration_mode_requires_mobile_device_names() {
+        assert!(registration_mode("central", None).is_err());
+        assert!(registration_mode("central", Some(" , ")).is_err());
+    }
+
+    #[test]
+    fn unknown_registration_mode_is_rejected() {
+        assert!(registration_mode("Central", Some("phone")).is_err());
+        assert!(registration_mode("", None).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+//! Defines hot reloading: on SIGHUP, or when the configuration file, the Viam cloud config or the
+//! advertised BLE name file changes, the configuration is loaded again and the machine part ID
+//! and advertised name are re-read, without restarting the SOCKS forwarder or dropping a healthy
+//! bridge.
+//!
+//! A new machine part ID or advertised name is served and advertised right away (see `identity`.)
+//! Other settings take effect as described by `config::Applies`; the main loop is notified of
+//! those that wait for the active bridge to drain, so that they apply right away if there is
+//! none.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use inotify::{EventStream, Inotify, WatchDescriptor, WatchMask};
+use log::{debug, info, warn};
+use tokio::signal::unix::Signal;
+use tokio::sync::watch;
+use tokio::time::{sleep, timeout, Duration};
+
+use crate::config::{self, Applies};
+use crate::env;
+use crate::identity::IdentityHandle;
+
+/// How long watched files must go unchanged before reloading, so that a burst of writes (e.g. an
+/// editor saving) causes a single reload.
+const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+/// How long to wait before watching files again after watching them failed.
+const WATCH_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Watches files for changes with inotify. Their directories are watched rather than the files
+/// themselves, so that files that do not exist yet or are replaced (as atomic writes do) are
+/// still noticed.
+pub struct FileWatcher {
+    events: EventStream<[u8; 4096]>,
+    files: Vec<(WatchDescriptor, OsString)>,
+}
+
+impl FileWatcher {
+    /// Starts watching `paths`. Files in directories that cannot be watched are logged and left
+    /// out.
+    pub fn new(paths: &[PathBuf]) -> Result<Self> {
+        let inotify = Inotify::init()?;
+        let mut files = Vec::new();
+        for path in paths {
+            let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+                warn!("Cannot watch {path:?} for changes");
+                continue;
+            };
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            let mask = WatchMask::CLOSE_WRITE
+                | WatchMask::CREATE
+                | WatchMask::DELETE
+                | WatchMask::MOVED_FROM
+                | WatchMask::MOVED_TO;
+            match inotify.watches().add(dir, mask) {
+                Ok(wd) => files.push((wd, name.to_os_string())),
+                Err(e) => warn!("Cannot watch {path:?} for changes: {e}"),
+            }
+        }
+        if files.is_empty() {
+            return Err(anyhow!("none of {paths:?} can be watched"));
+        }
+        Ok(FileWatcher {
+            events: inotify.into_event_stream([0; 4096])?,
+            files,
+        })
+    }
+
+    /// Waits for any watched file to be written, created, removed or renamed.
+    pub async fn changed(&mut self) -> Result<()> {
+        while let Some(event) = self.events.next().await {
+            let event = event?;
+            let Some(name) = event.name else {
+                continue;
+            };
+            if self
+                .files
+                .iter()
+                .any(|(wd, file)| *wd == event.wd && *file == name)
+            {
+                debug!("Watched file {name:?} changed ({:?})", event.mask);
+                return Ok(());
+            }
+        }
+        Err(anyhow!("inotify event stream ended"))
+    }
+
+    /// Waits for the watched files to go unchanged for `SETTLE_DELAY`.
+    pub async fn settle(&mut self) {
+        while let Ok(Ok(())) = timeout(SETTLE_DELAY, self.changed()).await {}
+    }
+}
+
+/// Reloads on each SIGHUP received through `sighup` and each change to the configuration file, the
+/// Viam cloud config or the advertised BLE name file, updating `identity` and notifying
+/// `settings_changed` (see `reload`.)
+pub async fn reload_forever(
+    identity: IdentityHandle,
+    mut sighup: Signal,
+    settings_changed: watch::Sender<()>,
+) {
+    loop {
+        // Watched anew after each reload, as the paths may have changed.
+        let paths = watched_paths();
+        let mut watcher = match FileWatcher::new(&paths) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                warn!(
+                    "Cannot watch configuration files for changes: {e}; reloading only on SIGHUP"
+                );
+                None
+            }
+        };
+        let changed = async {
+            match watcher.as_mut() {
+                Some(watcher) => {
+                    watcher.changed().await?;
+                    watcher.settle().await;
+                    anyhow::Ok(())
+                }
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            _ = sighup.recv() => info!("Received SIGHUP signal; reloading"),
+            changed = changed => match changed {
+                Ok(()) => info!("Configuration files changed; reloading"),
+                Err(e) => {
+                    warn!("Error watching configuration files for changes: {e}");
+                    sleep(WATCH_RETRY_DELAY).await;
+                    continue;
+                }
+            },
+        }
+        reload(&identity, &settings_changed).await;
+    }
+}
+
+/// Reloads the configuration and re-reads the machine part ID and advertised name into `identity`.
+/// Anything that cannot be reloaded is logged and kept as it was. `settings_changed` is notified
+/// if any setting changed that the main loop applies (see `log_changes`.)
+pub async fn reload(identity: &IdentityHandle, settings_changed: &watch::Sender<()>) {
+    match config::reload().await {
+        Ok(changes) => {
+            if log_changes(&changes) {
+                settings_changed.send_replace(());
+            }
+        }
+        Err(e) => {
+            warn!("Could not reload the configuration: {e}; keeping the current configuration")
+        }
+    }
+
+    let mut new_identity = identity.get();
+    match env::get_machine_part_id().await {
+        Ok(machine_part_id) => new_identity.machine_part_id = machine_part_id,
+        Err(e) => warn!(
+            "{e}; keeping machine part ID {}",
+            new_identity.machine_part_id
+        ),
+    }
+    match env::get_advertised_ble_name().await {
+        Ok(advertised_ble_name) => new_identity.advertised_ble_name = advertised_ble_name,
+        Err(e) => warn!(
+            "{e}; keeping advertised name '{}'",
+            new_identity.advertised_ble_name
+        ),
+    }
+    if identity.set(new_identity.clone()) {
+        info!(
+            "Now serving machine part ID {} and advertising self='{}'",
+            new_identity.machine_part_id, new_identity.advertised_ble_name
+        );
+    }
+}
+
+/// Logs `changes` and returns whether any of them is applied by the main loop: right away if no
+/// bridge is up, or else once the active bridge has drained.
+fn log_changes(changes: &[(&'static str, Applies)]) -> bool {
+    for (key, applies) in changes {
+        match applies {
+            Applies::Now => info!("Setting \"{key}\" changed; applying it right away"),
+            Applies::NextBridge => info!(
+                "Setting \"{key}\" changed; applying it right away if no bridge is up, or else once the active bridge has drained"
+            ),
+            Applies::Bluetooth => info!(
+                "Setting \"{key}\" changed; setting up Bluetooth again with it right away if no bridge is up, or else once the active bridge has drained"
+            ),
+        }
+    }
+    changes.iter().any(|(_, applies)| *applies != Applies::Now)
+}
+
+/// Returns the paths of the files whose changes cause a reload.
+fn watched_paths() -> Vec<PathBuf> {
+    vec![
+        PathBuf::from(config::current().path()),
+        PathBuf::from(env::viam_config_fp()),
+        PathBuf::from(env::ADVERTISED_BLE_NAME_FP),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_applied_by_the_main_loop_notify_it() {
+        assert!(!log_changes(&[]));
+        assert!(!log_changes(&[
+            ("scan-timeout", Applies::Now),
+            ("min-rssi", Applies::Now)
+        ]));
+        assert!(log_changes(&[
+            ("scan-timeout", Applies::Now),
+            ("registration-mode", Applies::NextBridge)
+        ]));
+        assert!(log_changes(&[("pairing", Applies::Bluetooth)]));
+    }
+}
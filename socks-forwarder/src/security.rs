@@ -0,0 +1,141 @@
+//! Defines the Bluetooth link security level required of mobile devices.
+
+use anyhow::{anyhow, Result};
+use bluer::l2cap::{Security, SecurityLevel as L2capSecurityLevel};
+use log::info;
+
+use crate::config::Values;
+use crate::env::SECURITY_LEVEL_ENV_VAR;
+use crate::pairing::PairingPolicy;
+
+/// Link security required for the mobile device name write and the L2CAP stream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SecurityLevel {
+    /// No encryption is required.
+    #[default]
+    Open,
+    /// The link must be encrypted (pairing may be unauthenticated.)
+    Encrypted,
+    /// The link must be encrypted with keys from authenticated (MITM-protected) pairing.
+    Authenticated,
+}
+
+impl SecurityLevel {
+    /// Reads the security level from `SECURITY_LEVEL_ENV_VAR` ("open", "encrypted" or
+    /// "authenticated"; defaults to "open") and checks that `pairing_policy` can satisfy it (see
+    /// `PairingPolicy::authenticates`.)
+    pub fn from_values(values: &Values, pairing_policy: PairingPolicy) -> Result<Self> {
+        let level = match values.var(SECURITY_LEVEL_ENV_VAR).as_deref() {
+            Err(_) | Ok("open") => Self::Open,
+            Ok("encrypted") => Self::Encrypted,
+            Ok("authenticated") => Self::Authenticated,
+            Ok(level) => {
+                return Err(anyhow!(
+                    "unknown security level \"{level}\"; expected \"open\", \"encrypted\" or \"authenticated\""
+                ));
+            }
+        };
+        if level == Self::Authenticated && !pairing_policy.authenticates() {
+            return Err(anyhow!(
+                "security level \"authenticated\" requires the \"random\" pairing policy or a static passkey other than the default"
+            ));
+        }
+        Ok(level)
+    }
+
+    /// Logs the security level.
+    pub fn log(&self) {
+        info!("Security level is {self:?}");
+    }
+
+    /// Returns whether the link must be encrypted before a mobile device name can be written, so
+    /// that mobile devices pair before writing rather than after.
+    pub fn requires_encryption(&self) -> bool {
+        *self != Self::Open
+    }
+
+    /// Returns the L2CAP socket security for this level.
+    pub fn l2cap_security(&self) -> Security {
+        let level = match self {
+            Self::Open => L2capSecurityLevel::Low,
+            Self::Encrypted => L2capSecurityLevel::Medium,
+            Self::Authenticated => L2capSecurityLevel::High,
+        };
+        Security { level, key_size: 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Source;
+
+    fn security_level(level: &str, pairing_policy: PairingPolicy) -> Result<SecurityLevel> {
+        let mut values = Values::default();
+        values.insert(SECURITY_LEVEL_ENV_VAR, level.to_string(), Source::Cli);
+        SecurityLevel::from_values(&values, pairing_policy)
+    }
+
+    #[test]
+    fn security_level_defaults_to_open() {
+        let level =
+            SecurityLevel::from_values(&Values::default(), PairingPolicy::JustWorks).unwrap();
+        assert_eq!(level, SecurityLevel::Open);
+        assert!(!level.requires_encryption());
+    }
+
+    #[test]
+    fn security_levels_are_parsed() {
+        let policy = PairingPolicy::RandomPasskey(1);
+        assert_eq!(security_level("open", policy).unwrap(), SecurityLevel::Open);
+        assert_eq!(
+            security_level("encrypted", policy).unwrap(),
+            SecurityLevel::Encrypted
+        );
+        assert_eq!(
+            security_level("authenticated", policy).unwrap(),
+            SecurityLevel::Authenticated
+        );
+        assert!(security_level("Encrypted", policy).is_err());
+        assert!(security_level("high", policy).is_err());
+    }
+
+    #[test]
+    fn authenticated_level_requires_a_secret_passkey() {
+        assert!(security_level("authenticated", PairingPolicy::JustWorks).is_err());
+        assert!(security_level("authenticated", PairingPolicy::StaticPasskey(123456)).is_err());
+        assert!(security_level("authenticated", PairingPolicy::StaticPasskey(654321)).is_ok());
+        assert!(security_level("encrypted", PairingPolicy::JustWorks).is_ok());
+    }
+
+    #[test]
+    fn authenticated_level_requires_passkey_entry() {
+        for policy in [
+            PairingPolicy::JustWorks,
+            PairingPolicy::StaticPasskey(123456),
+            PairingPolicy::StaticPasskey(654321),
+            PairingPolicy::RandomPasskey(42),
+        ] {
+            if security_level("authenticated", policy).is_ok() {
+                // Otherwise numeric comparison would bond without the passkey.
+                assert!(!policy.accepts_confirmation(), "{policy:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn l2cap_security_matches_level() {
+        assert_eq!(
+            SecurityLevel::Open.l2cap_security().level,
+            L2capSecurityLevel::Low
+        );
+        assert_eq!(
+            SecurityLevel::Encrypted.l2cap_security().level,
+            L2capSecurityLevel::Medium
+        );
+        assert_eq!(
+            SecurityLevel::Authenticated.l2cap_security().level,
+            L2capSecurityLevel::High
+        );
+    }
+}
@@ -0,0 +1,189 @@
+//! Defines static forwards: local TCP ports that always tunnel to a fixed destination through the
+//! mobile device (like `ssh -L`), so that programs that cannot use a SOCKS proxy can still reach
+//! fixed endpoints over the bridge.
+//!
+//! The SOCKS forwarder performs the SOCKS handshake with the remote side on behalf of each local
+//! client of a static forward (see `handshake::connect_preamble`), so local clients send and
+//! receive only their own data.
+
+use anyhow::{anyhow, Result};
+use futures::future::select_all;
+use log::{info, warn};
+use tokio::net::{TcpListener, TcpStream};
+
+use super::handshake::{self, Preamble};
+use crate::config::Values;
+use crate::env::FORWARDS_ENV_VAR;
+
+/// A local TCP port that tunnels to a fixed destination.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Forward {
+    pub(crate) local_port: u16,
+    pub(crate) host: String,
+    pub(crate) port: u16,
+}
+
+impl Forward {
+    /// Parses a static forward declared as "<local port>=<host>:<port>" (IPv6 hosts in
+    /// brackets.) The local port may not be 0, which would listen on a random port.
+    fn parse(forward: &str) -> Result<Self> {
+        let invalid = || anyhow!("expected \"<local port>=<host>:<port>\"");
+        let (local_port, destination) = forward.split_once('=').ok_or_else(invalid)?;
+        let (host, port) = destination.trim().rsplit_once(':').ok_or_else(invalid)?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let local_port = match local_port.trim().parse() {
+            Ok(local_port) if local_port != 0 => local_port,
+            _ => return Err(invalid()),
+        };
+        Ok(Forward {
+            local_port,
+            host: host.to_string(),
+            port: port.parse().map_err(|_| invalid())?,
+        })
+    }
+
+    /// Returns the destination as `host:port` (`[host]:port` for IPv6 hosts.)
+    pub(crate) fn destination(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    /// Returns the preamble that connects a local client to the destination.
+    pub(crate) fn preamble(&self) -> Result<Preamble> {
+        handshake::connect_preamble(&self.host, self.port)
+    }
+}
+
+/// Reads the static forwards from `FORWARDS_ENV_VAR` (see its documentation; defaults to none.)
+/// Fails on an invalid forward or on two forwards from the same local port.
+pub(crate) fn from_values(values: &Values) -> Result<Vec<Forward>> {
+    let mut forwards: Vec<Forward> = Vec::new();
+    for forward in values.list(FORWARDS_ENV_VAR) {
+        let parsed = Forward::parse(&forward)
+            .map_err(|e| anyhow!("invalid forward \"{forward}\" in {FORWARDS_ENV_VAR}: {e}"))?;
+        if forwards.iter().any(|f| f.local_port == parsed.local_port) {
+            return Err(anyhow!(
+                "more than one forward from local port {} in {FORWARDS_ENV_VAR}",
+                parsed.local_port
+            ));
+        }
+        forwards.push(parsed);
+    }
+    Ok(forwards)
+}
+
+/// Listeners on the local ports of static forwards.
+pub(crate) struct ForwardListeners {
+    listeners: Vec<(TcpListener, Forward)>,
+}
+
+impl ForwardListeners {
+    /// Listens on 127.0.0.1 at the local port of each of `forwards`. Forwards whose local port
+    /// cannot be listened on are logged and left out.
+    pub(crate) async fn bind(forwards: Vec<Forward>) -> Self {
+        let mut listeners = Vec::new();
+        for forward in forwards {
+            let bind_address = format!("127.0.0.1:{}", forward.local_port);
+            match TcpListener::bind(&bind_address).await {
+                Ok(listener) => {
+                    info!(
+                        "Forwarding {bind_address} to {} through the bridge",
+                        forward.destination()
+                    );
+                    listeners.push((listener, forward));
+                }
+                Err(e) => warn!(
+                    "Could not listen on {bind_address} to forward to {}: {e}",
+                    forward.destination()
+                ),
+            }
+        }
+        ForwardListeners { listeners }
+    }
+
+    /// Waits for a local client to connect to any static forward. Returns the client's TCP stream
+    /// and its forward. Never returns if there are no static forwards.
+    pub(crate) async fn accept(&self) -> (TcpStream, &Forward) {
+        if self.listeners.is_empty() {
+            return futures::future::pending().await;
+        }
+
+        loop {
+            let (accepted, _, _) = select_all(self.listeners.iter().map(|(listener, forward)| {
+                Box::pin(async move { (listener.accept().await, forward) })
+            }))
+            .await;
+            match accepted {
+                (Ok((stream, _addr)), forward) => return (stream, forward),
+                (Err(e), forward) => warn!(
+                    "Error accepting TCP stream on local port {}: {e}",
+                    forward.local_port
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Source;
+
+    fn forward(local_port: u16, host: &str, port: u16) -> Forward {
+        Forward {
+            local_port,
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    fn forwards(forwards: &str) -> Result<Vec<Forward>> {
+        let mut values = Values::default();
+        values.insert(FORWARDS_ENV_VAR, forwards.to_string(), Source::Cli);
+        from_values(&values)
+    }
+
+    #[test]
+    fn forwards_are_parsed() {
+        assert_eq!(
+            forwards(" 8080 = example.com:80, 5432=[::1]:5432 ,").unwrap(),
+            vec![forward(8080, "example.com", 80), forward(5432, "::1", 5432)]
+        );
+        assert_eq!(
+            forward(8080, "example.com", 80).destination(),
+            "example.com:80"
+        );
+        assert_eq!(forward(5432, "::1", 5432).destination(), "[::1]:5432");
+        assert!(from_values(&Values::default()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_forwards_are_rejected() {
+        for forward in [
+            "8080",
+            "8080=example.com",
+            "8080=:80",
+            "8080=[]:80",
+            "x=example.com:80",
+            "70000=example.com:80",
+            "0=example.com:80",
+            "8080=example.com:x",
+            "8080=example.com:70000",
+        ] {
+            assert!(Forward::parse(forward).is_err(), "{forward}");
+            assert!(forwards(forward).is_err(), "{forward}");
+        }
+    }
+
+    #[test]
+    fn forwards_from_the_same_local_port_are_rejected() {
+        assert!(forwards("8080=example.com:80,8080=example.org:80").is_err());
+        assert!(forwards("8080=example.com:80,8081=example.com:80").is_ok());
+    }
+}
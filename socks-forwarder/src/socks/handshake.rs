@@ -0,0 +1,203 @@
+//! Defines just enough of a SOCKS5 server handshake (RFC 1928) to learn the destination of a
+//! local client's connection before it is handed to a multiplexer, and of a SOCKS5 client
+//! handshake to connect static forwards (see `forward`) on behalf of local clients.
+
+use anyhow::{anyhow, Result};
+use byteorder::{BigEndian, ByteOrder};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// SOCKS protocol version handled here.
+const SOCKS5_VERSION: u8 = 5;
+
+/// SOCKS5 "no authentication required" method.
+const NO_AUTH_METHOD: u8 = 0;
+
+/// SOCKS5 CONNECT command.
+const CONNECT_COMMAND: u8 = 1;
+
+/// SOCKS5 "succeeded" reply.
+const SUCCEEDED_REPLY: u8 = 0;
+
+/// SOCKS5 address types.
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN_NAME: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Bytes already read from a local client that must be replayed to the remote side, and how many
+/// bytes of the remote side's replies must not reach the local client (because they answer a
+/// part of the handshake that was already answered locally.) If `connect_reply` is set, the
+/// remote side's reply to a CONNECT request sent on the local client's behalf must not reach it
+/// either.
+#[derive(Debug, Default)]
+pub(crate) struct Preamble {
+    pub(crate) data: Vec<u8>,
+    pub(crate) reply_bytes_to_skip: usize,
+    pub(crate) connect_reply: Option<ConnectReply>,
+}
+
+/// The remote side's reply to a CONNECT request, received so far.
+#[derive(Debug, Default)]
+pub(crate) struct ConnectReply {
+    received: Vec<u8>,
+}
+
+impl ConnectReply {
+    /// Adds `data` received from the remote side to the reply. Returns the data following the
+    /// reply once it is complete (`None` until then), or an error if the CONNECT request failed.
+    pub(crate) fn receive(&mut self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.received.extend_from_slice(data);
+
+        // +-----+-----+-------+------+----------+----------+
+        // | VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
+        // +-----+-----+-------+------+----------+----------+
+        // |  1  |  1  | X'00' |  1   | Variable |    2     |
+        // +-----+-----+-------+------+----------+----------+
+        if self.received.len() < 5 {
+            return Ok(None);
+        }
+        if self.received[0] != SOCKS5_VERSION {
+            return Err(anyhow!(
+                "unexpected SOCKS version {} in CONNECT reply",
+                self.received[0]
+            ));
+        }
+        if self.received[1] != SUCCEEDED_REPLY {
+            return Err(anyhow!(
+                "CONNECT request failed with reply {}",
+                self.received[1]
+            ));
+        }
+        let addr_len = match self.received[3] {
+            ATYP_IPV4 => 4,
+            ATYP_DOMAIN_NAME => 1 + self.received[4] as usize,
+            ATYP_IPV6 => 16,
+            atyp => return Err(anyhow!("unknown SOCKS5 address type {atyp}")),
+        };
+        let len = 4 + addr_len + 2;
+        if self.received.len() < len {
+            return Ok(None);
+        }
+        Ok(Some(self.received.split_off(len)))
+    }
+}
+
+/// Returns the preamble that connects a local client that does not speak SOCKS to `host:port`:
+/// a greeting offering only "no authentication required" and a CONNECT request, neither of whose
+/// replies reach the local client.
+pub(crate) fn connect_preamble(host: &str, port: u16) -> Result<Preamble> {
+    let mut data = vec![SOCKS5_VERSION, 1, NO_AUTH_METHOD];
+    data.extend_from_slice(&[SOCKS5_VERSION, CONNECT_COMMAND, 0]);
+    match host.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(addr)) => {
+            data.push(ATYP_IPV4);
+            data.extend_from_slice(&addr.octets());
+        }
+        Ok(std::net::IpAddr::V6(addr)) => {
+            data.push(ATYP_IPV6);
+            data.extend_from_slice(&addr.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len())
+                .map_err(|_| anyhow!("host \"{host}\" is longer than 255 bytes"))?;
+            data.push(ATYP_DOMAIN_NAME);
+            data.push(len);
+            data.extend_from_slice(host.as_bytes());
+        }
+    }
+    data.extend_from_slice(&port.to_be_bytes());
+    Ok(Preamble {
+        data,
+        reply_bytes_to_skip: 2,
+        connect_reply: Some(ConnectReply::default()),
+    })
+}
+
+/// Reads the SOCKS5 greeting and request from `stream`, answering the greeting locally with "no
+/// authentication required". Returns the bytes to replay to the remote side and the requested
+/// destination as `host:port`. If the client is not speaking SOCKS5 or requires authentication,
+/// no destination is returned and the remote side is left to answer the greeting itself.
+pub(crate) async fn read_destination(stream: &mut TcpStream) -> Result<(Preamble, Option<String>)> {
+    let mut greeting = vec![0u8; 2];
+    stream.read_exact(&mut greeting).await?;
+    if greeting[0] != SOCKS5_VERSION {
+        return Ok((
+            Preamble {
+                data: greeting,
+                reply_bytes_to_skip: 0,
+                connect_reply: None,
+            },
+            None,
+        ));
+    }
+
+    let mut methods = vec![0u8; greeting[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTH_METHOD) {
+        greeting.extend_from_slice(&methods);
+        return Ok((
+            Preamble {
+                data: greeting,
+                reply_bytes_to_skip: 0,
+                connect_reply: None,
+            },
+            None,
+        ));
+    }
+    stream.write_all(&[SOCKS5_VERSION, NO_AUTH_METHOD]).await?;
+
+    // +-----+-----+-------+------+----------+----------+
+    // | VER | CMD |  RSV  | ATYP | DST.ADDR | DST.PORT |
+    // +-----+-----+-------+------+----------+----------+
+    // |  1  |  1  | X'00' |  1   | Variable |    2     |
+    // +-----+-----+-------+------+----------+----------+
+    let mut request = vec![0u8; 4];
+    stream.read_exact(&mut request).await?;
+    let host = match request[3] {
+        ATYP_IPV4 => {
+            let mut addr = [0u8; 4];
+            stream.read_exact(&mut addr).await?;
+            request.extend_from_slice(&addr);
+            std::net::Ipv4Addr::from(addr).to_string()
+        }
+        ATYP_DOMAIN_NAME => {
+            let len = stream.read_u8().await?;
+            let mut name = vec![0u8; len as usize];
+            stream.read_exact(&mut name).await?;
+            request.push(len);
+            request.extend_from_slice(&name);
+            String::from_utf8_lossy(&name).into_owned()
+        }
+        ATYP_IPV6 => {
+            let mut addr = [0u8; 16];
+            stream.read_exact(&mut addr).await?;
+            request.extend_from_slice(&addr);
+            std::net::Ipv6Addr::from(addr).to_string()
+        }
+        atyp => {
+            return Err(anyhow!("unknown SOCKS5 address type {atyp}"));
+        }
+    };
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port).await?;
+    request.extend_from_slice(&port);
+    let port = BigEndian::read_u16(&port);
+
+    // Replay a greeting offering only "no authentication required" so that the remote side's
+    // two-byte method selection reply can be skipped.
+    let mut data = vec![SOCKS5_VERSION, 1, NO_AUTH_METHOD];
+    data.extend_from_slice(&request);
+    Ok((
+        Preamble {
+            data,
+            reply_bytes_to_skip: 2,
+            connect_reply: None,
+        },
+        // Formatted like `Forward::destination`, so that both hash alike.
+        Some(if host.contains(':') {
+            format!("[{host}]:{port}")
+        } else {
+            format!("{host}:{port}")
+        }),
+    ))
+}
@@ -1,58 +1,225 @@
 //! Defines SOCKS forwarding logic.
 
 mod chunker;
+pub(crate) mod forward;
+mod handshake;
 mod mux;
+pub(crate) mod noise;
+mod pool;
+pub(crate) mod reverse;
+
+use std::collections::HashMap;
 
 use anyhow::{anyhow, Result};
 use bluer::l2cap;
 use log::{debug, error, info, warn};
-use std::env;
 use tokio::net::TcpListener;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::time::{self, timeout, Duration};
 
-use crate::env::RECV_MTU_OVERRIDE_ENV_VAR;
+use crate::capabilities::{Capabilities, FEATURE_NOISE};
+use crate::config;
+use crate::security::SecurityLevel;
+use crate::state::Store;
+use crate::status::{State, StatusHandle};
+
+pub(crate) use pool::LoadBalancing;
 
-/// The port on which to start the listening for traffic to forward.
-const PORT: u16 = 1080;
+/// The default port on which to start the listening for traffic to forward.
+/// Can be overridden with the `socks-port` setting.
+pub(crate) const DEFAULT_PORT: u16 = 1080;
 
 /// Value to set for incoming maximum-transmission-unit on created L2CAP streams.
-/// Can be overridden with SOCKS_FOWARDER_RECV_MTU environment variable.
+/// Can be overridden with the `recv-mtu` setting.
 /// In our testing, Rock4C+ on Debian 11 performs best around 32K
 /// and RPI 4B with Debian 12 is best around 8K.
 const DEFAULT_RECV_MTU: u16 = 32768;
 
-/// Starts a forwarder that accepts incoming requests and forwards them over an L2CAP stream
-/// created against the `device` on `psm`. Returns true if main program should go back to
-/// `find_viam_mobile_device_and_psm` and false otherwise (only returns false when a SIGTERM or
-/// SIGINT is received.)
-pub async fn start_forwarder(device: bluer::Device, psm: u16) -> Result<bool> {
-    let bind_address = format!("127.0.0.1:{PORT}");
+/// How often to refresh the number of local SOCKS clients reported in the status.
+const CLIENT_COUNT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of times a multiplexer dropped from the pool is replaced with a new L2CAP stream to
+/// the same mobile device during one bridge.
+const MAX_REPLACEMENTS: u32 = 3;
+
+/// Starts a forwarder that accepts incoming requests and forwards them over L2CAP streams
+/// created against each of the `devices` on the PSM in its capabilities, spreading requests
+/// across them. Returns true if main program should go back to `find_viam_mobile_device_and_psm`
+/// and false otherwise (only returns false when a SIGTERM or SIGINT is received.)
+///
+/// If `machine_part_secret` is provided, each L2CAP stream is end-to-end encrypted with a key
+/// derived from it (see `noise`); mobile devices that fail the handshake (or whose capabilities
+/// rule it out) are not used. L2CAP streams are secured as `security_level` requires. Reports the
+/// bridge and its number of local SOCKS clients through `status`.
+///
+/// A mobile device whose L2CAP stream cannot be created or is lost counts as a failure in
+/// `state`; a lost L2CAP stream is replaced with a new one to the same mobile device (up to
+/// `MAX_REPLACEMENTS` times.)
+pub async fn start_forwarder(
+    devices: Vec<(bluer::Device, Capabilities)>,
+    machine_part_secret: Option<String>,
+    security_level: SecurityLevel,
+    state: &Store,
+    status: &StatusHandle,
+) -> Result<bool> {
+    let settings = config::current().settings.clone();
+    let bind_address = format!("127.0.0.1:{}", settings.socks_port);
     let listener = TcpListener::bind(bind_address.clone()).await?;
+    let forward_listeners = forward::ForwardListeners::bind(settings.forwards).await;
 
-    let l2cap_stream = match connect_l2cap(&device, psm).await {
-        Ok(stream) => stream,
-        Err(e) => {
-            return Err(anyhow!("Error creating L2CAP stream: {e}"));
+    let load_balancing = settings.load_balancing;
+    let mut pool = pool::L2CAPStreamMuxPool::new(load_balancing);
+    let reverse_targets = settings.reverse_targets;
+    reverse_targets.log();
+    for (device, capabilities) in &devices {
+        if machine_part_secret.is_some() && capabilities.supports(FEATURE_NOISE) == Some(false) {
+            warn!(
+                "Mobile device {} does not support end-to-end encryption; not bridging through it",
+                device.address()
+            );
+            continue;
         }
-    };
-    let mut mux = mux::L2CAPStreamMux::create_and_start(l2cap_stream);
+        match connect_mux(
+            device,
+            capabilities,
+            machine_part_secret.as_deref(),
+            security_level,
+            &reverse_targets,
+        )
+        .await
+        {
+            Ok(mux) => pool.add_mux(device.remote_address().await?, mux),
+            Err(e) => {
+                warn!(
+                    "Error bridging through mobile device {}: {e}",
+                    device.address()
+                );
+                state.lock().record_failure_at(device.address());
+            }
+        }
+    }
+    state.save_or_warn().await;
+    if pool.len() == 0 {
+        disconnect_devices(&devices).await;
+        return Err(anyhow!(
+            "Error creating L2CAP stream to any of {} mobile device(s)",
+            devices.len()
+        ));
+    }
+    if pool.len() > 1 {
+        info!(
+            "Spreading traffic across {} mobile devices with {load_balancing:?} load balancing",
+            pool.len()
+        );
+    }
 
     info!("BLE-SOCKS bridge established and ready to handle traffic");
+    status.set_state(State::Bridged);
+    let mut client_count_interval = time::interval(CLIENT_COUNT_INTERVAL);
 
     let mut sigterm = signal(SignalKind::terminate())?;
     let mut sigint = signal(SignalKind::interrupt())?;
 
+    // TCP streams whose SOCKS handshake was peeked at in a task of their own.
+    let (peeked_send, peeked_receive) = async_channel::unbounded();
+    // Multiplexers replacing ones dropped from the pool, connected in tasks of their own.
+    let (replacement_send, replacement_receive) = async_channel::unbounded();
+    let mut pending_replacements = 0;
+    let mut replacements: HashMap<bluer::Address, u32> = HashMap::new();
+
     let mut should_restart_main_program = true;
     loop {
         tokio::select! {
             Ok((tcp_stream, _addr)) = listener.accept() => {
-                if let Err(e) = mux.add_tcp_stream(tcp_stream).await {
+                if pool.balances_by_destination() {
+                    let peeked_send = peeked_send.clone();
+                    tokio::spawn(async move {
+                        if let Some(peeked) = pool::peek_destination(tcp_stream).await {
+                            // Only fails once the forwarder has stopped.
+                            let _ = peeked_send.send(peeked).await;
+                        }
+                    });
+                    continue;
+                }
+                if let Err(e) = pool.add_tcp_stream(tcp_stream).await {
+                    status.set_client_count(0);
                     return Err(anyhow!("could not add mux TCP stream: {e}"));
                 }
+                status.set_client_count(pool.client_count());
             },
-            _ = mux.wait_for_stop_due_to_disconnect() => {
-                break;
+            Ok(peeked) = peeked_receive.recv() => {
+                if let Err(e) = pool.add_peeked_tcp_stream(peeked).await {
+                    status.set_client_count(0);
+                    return Err(anyhow!("could not add mux TCP stream: {e}"));
+                }
+                status.set_client_count(pool.client_count());
+            },
+            (tcp_stream, forward) = forward_listeners.accept() => {
+                let preamble = match forward.preamble() {
+                    Ok(preamble) => preamble,
+                    Err(e) => {
+                        warn!("Could not forward TCP stream to {}; dropping TCP stream: {e}", forward.destination());
+                        continue;
+                    }
+                };
+                if let Err(e) = pool.add_forwarded_tcp_stream(tcp_stream, preamble, &forward.destination()).await {
+                    status.set_client_count(0);
+                    return Err(anyhow!("could not add mux TCP stream: {e}"));
+                }
+                status.set_client_count(pool.client_count());
+            },
+            _ = client_count_interval.tick() => {
+                status.set_client_count(pool.client_count());
+            },
+            address = pool.wait_for_stop_due_to_disconnect() => {
+                state.lock().record_failure_at(address);
+                state.save_or_warn().await;
+                let count = replacements.entry(address).or_default();
+                let device = devices.iter().find(|(device, _)| device.address() == address);
+                match device {
+                    Some((device, capabilities)) if *count < MAX_REPLACEMENTS => {
+                        *count += 1;
+                        info!("Replacing L2CAP stream to mobile device {address}");
+                        pending_replacements += 1;
+                        let (device, capabilities) = (device.clone(), capabilities.clone());
+                        let machine_part_secret = machine_part_secret.clone();
+                        let reverse_targets = reverse_targets.clone();
+                        let replacement_send = replacement_send.clone();
+                        tokio::spawn(async move {
+                            let mux = connect_mux(
+                                &device,
+                                &capabilities,
+                                machine_part_secret.as_deref(),
+                                security_level,
+                                &reverse_targets,
+                            )
+                            .await;
+                            // Only fails once the forwarder has stopped.
+                            let _ = replacement_send.send((address, mux)).await;
+                        });
+                    }
+                    _ => warn!("Not replacing L2CAP stream to mobile device {address}"),
+                }
+                if pool.len() == 0 && pending_replacements == 0 {
+                    break;
+                }
+            }
+            Ok((address, mux)) = replacement_receive.recv() => {
+                pending_replacements -= 1;
+                match mux {
+                    Ok(mux) => {
+                        info!("Replaced L2CAP stream to mobile device {address}");
+                        pool.add_mux(address, mux);
+                    }
+                    Err(e) => {
+                        warn!("Could not replace L2CAP stream to mobile device {address}: {e}");
+                        state.lock().record_failure_at(address);
+                        state.save_or_warn().await;
+                    }
+                }
+                if pool.len() == 0 && pending_replacements == 0 {
+                    break;
+                }
             }
             _ = sigterm.recv() => {
                 info!("Received SIGTERM signal while handling traffic; stopping the SOCKS forwarder");
@@ -67,11 +234,46 @@ pub async fn start_forwarder(device: bluer::Device, psm: u16) -> Result<bool> {
         }
     }
 
+    status.set_client_count(0);
     debug!("Sleeping for a couple seconds to potentially allow manual disconnect");
     time::sleep(Duration::from_secs(2)).await;
 
-    // Disconnect device if still connected after forwarder is done running.
-    if device.is_connected().await? {
+    disconnect_devices(&devices).await;
+    Ok(should_restart_main_program)
+}
+
+/// Opens a new L2CAP stream to `device` (see `connect_l2cap`), encrypts it with a key derived
+/// from `machine_part_secret` (if any) and starts a multiplexer on it.
+async fn connect_mux(
+    device: &bluer::Device,
+    capabilities: &Capabilities,
+    machine_part_secret: Option<&str>,
+    security_level: SecurityLevel,
+    reverse_targets: &reverse::ReverseTargets,
+) -> Result<mux::L2CAPStreamMux> {
+    let mut l2cap_stream = connect_l2cap(device, capabilities, security_level).await?;
+    let cipher = match machine_part_secret {
+        Some(secret) => Some(
+            noise::handshake(&mut l2cap_stream, secret)
+                .await
+                .map_err(|e| anyhow!("could not encrypt L2CAP stream: {e}"))?,
+        ),
+        None => None,
+    };
+    Ok(mux::L2CAPStreamMux::create_and_start(
+        l2cap_stream,
+        cipher,
+        reverse_targets.clone(),
+    ))
+}
+
+/// Disconnects each of `devices` that is still connected.
+pub async fn disconnect_devices(devices: &[(bluer::Device, Capabilities)]) {
+    for (device, _) in devices {
+        // Disconnect device if still connected after forwarder is done running.
+        if !device.is_connected().await.unwrap_or_default() {
+            continue;
+        }
         let disconnect_future = device.disconnect();
         let disconnect_timeout = Duration::from_secs(5);
 
@@ -88,30 +290,53 @@ pub async fn start_forwarder(device: bluer::Device, psm: u16) -> Result<bool> {
             }
         }
     }
-    Ok(should_restart_main_program)
 }
 
-/// Opens a new L2CAP stream to `Device` on `psm`.
-pub async fn connect_l2cap(device: &bluer::Device, psm: u16) -> Result<l2cap::Stream> {
+/// Opens a new L2CAP stream to `Device` on the PSM in its `capabilities`, secured as
+/// `security_level` requires. The recv MTU is the overridden one, else the one the mobile device
+/// prefers, else `DEFAULT_RECV_MTU`.
+pub async fn connect_l2cap(
+    device: &bluer::Device,
+    capabilities: &Capabilities,
+    security_level: SecurityLevel,
+) -> Result<l2cap::Stream> {
     let addr_type = device.address_type().await?;
-    let target_sa = l2cap::SocketAddr::new(device.remote_address().await?, addr_type, psm);
+    let target_sa =
+        l2cap::SocketAddr::new(device.remote_address().await?, addr_type, capabilities.psm);
 
     let stream = l2cap::Socket::<l2cap::Stream>::new_stream()?;
 
-    let recv_mtu = env::var(RECV_MTU_OVERRIDE_ENV_VAR)
-        .ok()
-        .and_then(|mtu| mtu.parse::<u16>().ok()) // max is 65535
+    let recv_mtu = config::current()
+        .settings
+        .recv_mtu
+        .or(capabilities.preferred_mtu)
         .unwrap_or(DEFAULT_RECV_MTU);
     if let Err(e) = stream.set_recv_mtu(recv_mtu) {
         error!("Error setting recv mtu value of {recv_mtu}: {e}");
     }
+    stream
+        .set_security(security_level.l2cap_security())
+        .map_err(|e| anyhow!("error setting L2CAP security for {security_level:?}: {e}"))?;
 
-    debug!("Binding socket");
-    stream.bind(l2cap::SocketAddr::any_le())?;
+    // Bind to the adapter the device is known through, as there may be several.
+    let session = bluer::Session::new().await?;
+    let adapter = session.adapter(device.adapter_name())?;
+    let local_sa =
+        l2cap::SocketAddr::new(adapter.address().await?, adapter.address_type().await?, 0);
+    debug!("Binding socket to {local_sa:?}");
+    stream.bind(local_sa)?;
 
     info!("Connecting to L2CAP CoC at {:?}", &target_sa);
-    stream
+    let stream = stream
         .connect(target_sa)
         .await
-        .map_err(|e| anyhow!("error creating L2CAP stream: {e}"))
+        .map_err(|e| anyhow!("error creating L2CAP stream: {e}"))?;
+    match stream.as_ref().security() {
+        Ok(security) => info!(
+            "L2CAP stream security level is {:?} (key size {})",
+            security.level, security.key_size
+        ),
+        Err(e) => warn!("Could not read negotiated L2CAP stream security: {e}"),
+    }
+    Ok(stream)
 }
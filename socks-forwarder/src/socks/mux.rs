@@ -1,15 +1,21 @@
 //! Implements one side of the multiplexing protocol defined in the following specification.
 //! https://github.com/viamrobotics/flutter-ble/blob/bbe7e2a511c452f932c52e3784d7dca3751a03bd/doc/sockets.md
+//!
+//! Beyond the specification, the mobile device may open reverse streams (see `reverse`.)
 
 use std::{
+    collections::HashMap,
     io::Write,
     sync::{
-        atomic::{AtomicU16, Ordering::Relaxed},
-        Arc,
+        atomic::{AtomicU16, AtomicUsize, Ordering::Relaxed},
+        Arc, Mutex,
     },
 };
 
 use super::chunker::Chunker;
+use super::handshake::{ConnectReply, Preamble};
+use super::noise::{FrameDecryptor, FrameEncryptor};
+use super::reverse::{ReverseTargets, FIRST_REVERSE_PORT, TARGET_PORT_LEN};
 
 use anyhow::{anyhow, Result};
 use async_channel::{self, Receiver, Sender};
@@ -21,11 +27,17 @@ use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
     net::TcpStream,
     task::JoinHandle,
+    time::{timeout, Duration},
 };
 
 /// Value to set for incoming maximum-transmission-unit on created L2CAP streams.
 const RECV_MTU: u16 = 65535;
 
+/// How long to wait for any packet (including a keepalive) from the remote side before
+/// considering the L2CAP connection lost. Only enforced once the remote side has sent a
+/// keepalive, as older mobile apps do not send them.
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// A multiplexer that allows sharing one L2CAP stream between multiple TCP streams.
 pub(crate) struct L2CAPStreamMux {
     // Next "port" to assign to an incoming TCP stream.
@@ -37,17 +49,27 @@ pub(crate) struct L2CAPStreamMux {
     tcp_to_l2cap_send: Arc<Sender<Packet>>,
     // Group of tasks.
     tasks: Vec<JoinHandle<()>>,
+    // Tasks reading from TCP streams of reverse streams (spawned by the `pipe_out_tcp` task.)
+    reverse_tasks: ReverseTasks,
     // Stopped or not (mux can be stopped when L2CAP is disconnected or when mux is dropped).
     stopped: bool,
     // Channel to send stop requests due to L2CAP disconnection.
     stop_due_to_disconnect_send: Arc<Sender<bool>>,
     // Channel to receive stop requests due to L2CAP disconnection.
     stop_due_to_disconnect_receive: Receiver<bool>,
+    // Number of bytes of data read from TCP streams but not yet written to the L2CAP stream.
+    outstanding_bytes: Arc<AtomicUsize>,
 }
 
 impl L2CAPStreamMux {
-    /// Creates new mux from an L2CAP stream.
-    pub(crate) fn create_and_start(stream: l2cap::Stream) -> Self {
+    /// Creates new mux from an L2CAP stream. If a `cipher` is provided (see `noise::handshake`),
+    /// everything written to and read from the L2CAP stream is encrypted with it. The mobile
+    /// device may open reverse streams to `reverse_targets`.
+    pub(crate) fn create_and_start(
+        stream: l2cap::Stream,
+        cipher: Option<(FrameEncryptor, FrameDecryptor)>,
+        reverse_targets: ReverseTargets,
+    ) -> Self {
         info!("Starting L2CAP stream multiplexer...");
         let next_port = AtomicU16::new(1); // Start at 1 to distinguish between control packets.
         let port_to_tcp_stream = Arc::new(DashMap::default());
@@ -63,9 +85,11 @@ impl L2CAPStreamMux {
             port_to_tcp_stream,
             tcp_to_l2cap_send: Arc::new(tcp_to_l2cap_send),
             tasks,
+            reverse_tasks: Default::default(),
             stopped: false,
             stop_due_to_disconnect_send: Arc::new(stop_due_to_disconnect_send),
             stop_due_to_disconnect_receive,
+            outstanding_bytes: Arc::new(AtomicUsize::new(0)),
         };
 
         // Before splitting stream into read and write halves, log MTUs.
@@ -74,9 +98,11 @@ impl L2CAPStreamMux {
         let (l2cap_stream_read, l2cap_stream_write) = tokio::io::split(stream);
         let (l2cap_to_tcp_send, l2cap_to_tcp_receive) = async_channel::unbounded::<Vec<u8>>();
 
-        mux.pipe_in_l2cap(l2cap_stream_read, l2cap_to_tcp_send);
-        mux.pipe_out_tcp(Chunker::new(l2cap_to_tcp_receive));
-        mux.pipe_in_tcp(l2cap_stream_write, tcp_to_l2cap_receive);
+        let (encryptor, decryptor) = cipher.unzip();
+
+        mux.pipe_in_l2cap(l2cap_stream_read, l2cap_to_tcp_send, decryptor);
+        mux.pipe_out_tcp(Chunker::new(l2cap_to_tcp_receive), reverse_targets);
+        mux.pipe_in_tcp(l2cap_stream_write, tcp_to_l2cap_receive, encryptor);
         mux.send_keepalive_frames_forever();
 
         info!("Started L2CAP stream multiplexer");
@@ -105,12 +131,29 @@ impl L2CAPStreamMux {
         };
     }
 
-    /// Incorporates a new TCP stream into the multiplexer.
-    pub(crate) async fn add_tcp_stream(&mut self, stream: TcpStream) -> Result<()> {
+    /// Returns the number of bytes of data read from TCP streams but not yet written to the L2CAP
+    /// stream.
+    pub(crate) fn outstanding_bytes(&self) -> usize {
+        self.outstanding_bytes.load(Relaxed)
+    }
+
+    /// Returns the number of TCP streams currently multiplexed.
+    pub(crate) fn client_count(&self) -> usize {
+        self.port_to_tcp_stream.len()
+    }
+
+    /// Incorporates a new TCP stream into the multiplexer. If a `preamble` is provided, its data
+    /// is sent before anything read from the TCP stream.
+    pub(crate) async fn add_tcp_stream(
+        &mut self,
+        stream: TcpStream,
+        preamble: Option<Preamble>,
+    ) -> Result<()> {
         debug!("Adding new TCP stream to multiplexer...");
 
-        // Get new "port" value from atomic (start at 1 if overflow).
-        if self.next_port.load(Relaxed) > 65534 {
+        // Get new "port" value from atomic (start at 1 if overflow into the ports reserved for
+        // reverse streams).
+        if self.next_port.load(Relaxed) >= FIRST_REVERSE_PORT {
             self.next_port.store(1, Relaxed);
         }
         let port = self.next_port.fetch_add(1, Relaxed);
@@ -118,9 +161,12 @@ impl L2CAPStreamMux {
             return Err(anyhow!("too many open connections"));
         }
 
-        let (mut tcp_stream_read, tcp_stream_write) = tokio::io::split(stream);
+        let preamble = preamble.unwrap_or_default();
+        let (tcp_stream_read, tcp_stream_write) = tokio::io::split(stream);
         let muxed_stream = MuxedTCPStream {
             writer: tcp_stream_write,
+            reply_bytes_to_skip: preamble.reply_bytes_to_skip,
+            connect_reply: preamble.connect_reply,
         };
         self.port_to_tcp_stream.insert(port, muxed_stream);
 
@@ -128,77 +174,36 @@ impl L2CAPStreamMux {
         let control_packet = Packet::control_socket_open(port)?;
         self.tcp_to_l2cap_send.send(control_packet).await?;
 
-        let tcp_to_l2cap_send = self.tcp_to_l2cap_send.clone();
+        if !preamble.data.is_empty() {
+            self.outstanding_bytes
+                .fetch_add(preamble.data.len(), Relaxed);
+            let data_packet = Packet::Data {
+                port,
+                data: preamble.data,
+            };
+            self.tcp_to_l2cap_send.send(data_packet).await?;
+        }
+
         // Spawn coroutine (and track it) to continue reading from TCP stream and writing to
         // 'tcp_to_l2cap' channel.
-        let handler = tokio::spawn(async move {
-            loop {
-                // TODO: use a non-arbitrary cap here.
-                let mut data = vec![0u8; 1024];
-                let n = match tcp_stream_read.read(&mut data).await {
-                    Ok(n) if n > 0 => n,
-                    Ok(_) => {
-                        debug!("TCP stream closed for 'port' {port}");
-                        // Send a close control packet.
-                        let control_packet = match Packet::control_socket_closed(port) {
-                            Ok(control_packet) => control_packet,
-                            Err(e) => {
-                                error!(
-                                    "Could not create 'close' control packet for 'port' {port}: {e}"
-                                );
-                                break;
-                            }
-                        };
-                        if let Err(e) = tcp_to_l2cap_send.send(control_packet).await {
-                            error!("Could not send 'close' control packet for 'port' {port}: {e}");
-                        }
-                        break;
-                    }
-                    Err(e) => {
-                        info!("Could not read from TCP stream (likely closed); closing for 'port' {port}: {e}");
-                        // Send a close control packet.
-                        let control_packet = match Packet::control_socket_closed(port) {
-                            Ok(control_packet) => control_packet,
-                            Err(e) => {
-                                error!(
-                                    "Could not create 'close' control packet for 'port' {port}: {e}"
-                                );
-                                break;
-                            }
-                        };
-                        if let Err(e) = tcp_to_l2cap_send.send(control_packet).await {
-                            error!("Could not send 'close' control packet for 'port' {port}: {e}");
-                        }
-                        break;
-                    }
-                };
-
-                // Truncate message.
-                data.truncate(n);
-                debug!(
-                    "Writing data packet for 'port' {port} from TCP stream of length {}...",
-                    data.len()
-                );
-                trace!("Data in packet to be written is {:?}", data);
-
-                let data_packet = Packet::Data { port, data };
-                if let Err(e) = tcp_to_l2cap_send.send(data_packet).await {
-                    error!("Error sending data packet to 'tcp_to_l2cap_send' channel; dropping data packet: {e}");
-                    continue;
-                }
-            }
-        });
+        let handler = spawn_tcp_reader(
+            port,
+            tcp_stream_read,
+            self.tcp_to_l2cap_send.as_ref().clone(),
+            self.outstanding_bytes.clone(),
+        );
         self.tasks.push(handler);
 
         debug!("Added new TCP stream with 'port' {port} to multiplexer");
         Ok(())
     }
 
-    /// Reads from `l2cap_stream_read` into `l2cap_to_tcp`.
+    /// Reads from `l2cap_stream_read` (decrypting with `decryptor`, if any) into `l2cap_to_tcp`.
     fn pipe_in_l2cap(
         &mut self,
         mut l2cap_stream_read: ReadHalf<l2cap::Stream>,
         l2cap_to_tcp_send: Sender<Vec<u8>>,
+        mut decryptor: Option<FrameDecryptor>,
     ) {
         let handler = tokio::spawn(async move {
             loop {
@@ -216,6 +221,18 @@ impl L2CAPStreamMux {
                 };
                 chunk_buf.truncate(n);
 
+                if let Some(decryptor) = &mut decryptor {
+                    chunk_buf = match decryptor.decrypt(&chunk_buf) {
+                        Ok(plaintext) if plaintext.is_empty() => continue,
+                        Ok(plaintext) => plaintext,
+                        Err(e) => {
+                            // Tampered or corrupted data; treat the L2CAP stream as lost.
+                            warn!("Error decrypting L2CAP stream: {e}");
+                            break;
+                        }
+                    };
+                }
+
                 if let Err(e) = l2cap_to_tcp_send.send(chunk_buf).await {
                     error!("Error sending to 'l2cap_to_tcp' channel; dropping chunk: {e}");
                     continue;
@@ -225,15 +242,33 @@ impl L2CAPStreamMux {
         self.tasks.push(handler);
     }
 
-    /// Reads from `l2cap_to_tcp_chunker` to TCP streams.
-    fn pipe_out_tcp(&mut self, mut l2cap_to_tcp_chunker: Chunker) {
+    /// Reads from `l2cap_to_tcp_chunker` to TCP streams, opening reverse streams to
+    /// `reverse_targets` as the mobile device requests.
+    fn pipe_out_tcp(&mut self, mut l2cap_to_tcp_chunker: Chunker, reverse_targets: ReverseTargets) {
         let port_to_tcp_stream = self.port_to_tcp_stream.clone();
         let stop_due_to_disconnect_send = self.stop_due_to_disconnect_send.clone();
+        let tcp_to_l2cap_send = self.tcp_to_l2cap_send.as_ref().clone();
+        let outstanding_bytes = self.outstanding_bytes.clone();
+        let reverse_tasks = self.reverse_tasks.clone();
         let handler = tokio::spawn(async move {
+            // Reverse streams opened by the mobile device whose target port has not been fully
+            // received yet, with the bytes received so far.
+            let mut pending_reverse: HashMap<u16, Vec<u8>> = HashMap::new();
+            // Reverse streams whose TCP stream is being connected (see `spawn_reverse_connect`),
+            // with the bytes received for them meanwhile.
+            let connecting_reverse: ConnectingReverse = Default::default();
+            // Whether the remote side has sent a keepalive (and so `KEEPALIVE_TIMEOUT` applies.)
+            let mut remote_sends_keepalives = false;
             loop {
-                let pkt = match Packet::deserialize(&mut l2cap_to_tcp_chunker).await {
-                    Ok(pkt) => pkt,
-                    Err(e) => {
+                let deserialize = Packet::deserialize(&mut l2cap_to_tcp_chunker);
+                let received = if remote_sends_keepalives {
+                    timeout(KEEPALIVE_TIMEOUT, deserialize).await
+                } else {
+                    Ok(deserialize.await)
+                };
+                let pkt = match received {
+                    Ok(Ok(pkt)) => pkt,
+                    Ok(Err(e)) => {
                         // Inability to deserialize a packet indicates degradation or disconnection
                         // of the L2CAP connection; send to stop_due_to_disconnect channel.
                         warn!("Error deserializing packet; dropping data packet: {e}");
@@ -242,15 +277,56 @@ impl L2CAPStreamMux {
                         }
                         break;
                     }
+                    Err(_) => {
+                        // A silent remote side has likely gone away without the L2CAP stream
+                        // closing; send to stop_due_to_disconnect channel.
+                        warn!(
+                            "No packets (including keepalives) received for {KEEPALIVE_TIMEOUT:?}"
+                        );
+                        if let Err(e) = stop_due_to_disconnect_send.send(true).await {
+                            error!("Error sending to 'stop_due_to_disconnect' channel: {e}");
+                        }
+                        break;
+                    }
                 };
 
                 match pkt {
                     Packet::Data { port, data } => {
-                        if data.len() == 0 {
+                        if data.is_empty() {
                             warn!("Empty packet; dropping data packet");
                             continue;
                         }
 
+                        if let Some(mut received) = pending_reverse.remove(&port) {
+                            received.extend_from_slice(&data);
+                            if received.len() < TARGET_PORT_LEN {
+                                pending_reverse.insert(port, received);
+                                continue;
+                            }
+                            let target_port = LittleEndian::read_u16(&received[..TARGET_PORT_LEN]);
+                            // Connect without holding up the other streams.
+                            connecting_reverse
+                                .lock()
+                                .unwrap()
+                                .insert(port, received.split_off(TARGET_PORT_LEN));
+                            let connect = spawn_reverse_connect(
+                                port,
+                                target_port,
+                                reverse_targets.clone(),
+                                connecting_reverse.clone(),
+                                port_to_tcp_stream.clone(),
+                                tcp_to_l2cap_send.clone(),
+                                outstanding_bytes.clone(),
+                                reverse_tasks.clone(),
+                            );
+                            track_reverse_task(&reverse_tasks, connect);
+                            continue;
+                        }
+                        if let Some(buffered) = connecting_reverse.lock().unwrap().get_mut(&port) {
+                            buffered.extend_from_slice(&data);
+                            continue;
+                        }
+
                         let mut muxed_stream = match port_to_tcp_stream.get_mut(&port) {
                             Some(muxed_stream) => muxed_stream,
                             None => {
@@ -259,13 +335,51 @@ impl L2CAPStreamMux {
                             }
                         };
 
+                        // Drop replies to any part of the handshake already answered locally.
+                        let mut data = &data[..];
+                        if muxed_stream.reply_bytes_to_skip > 0 {
+                            let n = muxed_stream.reply_bytes_to_skip.min(data.len());
+                            muxed_stream.reply_bytes_to_skip -= n;
+                            data = &data[n..];
+                            if data.is_empty() {
+                                continue;
+                            }
+                        }
+                        let rest;
+                        if let Some(connect_reply) = muxed_stream.connect_reply.as_mut() {
+                            match connect_reply.receive(data) {
+                                Ok(None) => continue,
+                                Ok(Some(received)) => {
+                                    debug!("Connected TCP stream for 'port' {port}");
+                                    muxed_stream.connect_reply = None;
+                                    rest = received;
+                                    data = &rest[..];
+                                    if data.is_empty() {
+                                        continue;
+                                    }
+                                }
+                                Err(e) => {
+                                    warn!("Could not connect TCP stream for 'port' {port}; closing it: {e}");
+                                    if let Err(e) = muxed_stream.writer.shutdown().await {
+                                        debug!(
+                                            "Error shutting down TCP stream for 'port' {port}: {e}"
+                                        );
+                                    }
+                                    drop(muxed_stream);
+                                    port_to_tcp_stream.remove(&port);
+                                    send_close(&tcp_to_l2cap_send, port).await;
+                                    continue;
+                                }
+                            }
+                        }
+
                         debug!(
                             "Received data packet for 'port' {port} from L2CAP stream of length {}...",
                             data.len()
                         );
                         trace!("Data in received packet is {:?}", data);
 
-                        if let Err(e) = muxed_stream.writer.write(&data).await {
+                        if let Err(e) = muxed_stream.writer.write(data).await {
                             info!(
                                 "Could not write to TCP stream for 'port' {port} (stream may be closed); dropping data packet: {e}",
                             );
@@ -280,6 +394,10 @@ impl L2CAPStreamMux {
                     } => {
                         if msg_type == 0 {
                             trace!("Received keepalive control packet");
+                            if !remote_sends_keepalives {
+                                debug!("Remote side sends keepalives; expecting one at least every {KEEPALIVE_TIMEOUT:?}");
+                                remote_sends_keepalives = true;
+                            }
                             continue;
                         }
                         if msg_type != 1 {
@@ -292,6 +410,19 @@ impl L2CAPStreamMux {
                         match status {
                             0 => {
                                 // Closed.
+                                if pending_reverse.remove(&for_port).is_some() {
+                                    debug!("Reverse stream on 'port' {for_port} closed before selecting a target");
+                                    continue;
+                                }
+                                if connecting_reverse
+                                    .lock()
+                                    .unwrap()
+                                    .remove(&for_port)
+                                    .is_some()
+                                {
+                                    debug!("Reverse stream on 'port' {for_port} closed while connecting");
+                                    continue;
+                                }
                                 if !port_to_tcp_stream.contains_key(&for_port) {
                                     error!("Unknown 'port' {for_port}; dropping control packet");
                                     continue;
@@ -302,9 +433,18 @@ impl L2CAPStreamMux {
                                 port_to_tcp_stream.remove(&for_port);
                             }
                             1 => {
-                                // Open.
-                                error!("Cannot accept request to open a TCP stream");
-                                continue;
+                                // Opened by the mobile device (a reverse stream).
+                                if !reverse_targets.accepts(for_port)
+                                    || port_to_tcp_stream.contains_key(&for_port)
+                                    || pending_reverse.contains_key(&for_port)
+                                    || connecting_reverse.lock().unwrap().contains_key(&for_port)
+                                {
+                                    warn!("Rejecting request to open a reverse stream on 'port' {for_port}");
+                                    send_close(&tcp_to_l2cap_send, for_port).await;
+                                    continue;
+                                }
+                                debug!("Mobile device opened reverse stream on 'port' {for_port}; waiting for its target port");
+                                pending_reverse.insert(for_port, Vec::new());
                             }
                             _ => {
                                 error!(
@@ -320,25 +460,46 @@ impl L2CAPStreamMux {
         self.tasks.push(handler);
     }
 
-    /// Reads from `tcp_to_l2cap_receive` into `l2cap_stream_write`.
+    /// Reads from `tcp_to_l2cap_receive` into `l2cap_stream_write` (encrypting with `encryptor`,
+    /// if any.)
     fn pipe_in_tcp(
         &mut self,
         mut l2cap_stream_write: WriteHalf<l2cap::Stream>,
         tcp_to_l2cap_receive: Receiver<Packet>,
+        mut encryptor: Option<FrameEncryptor>,
     ) {
+        let outstanding_bytes = self.outstanding_bytes.clone();
         let handler = tokio::spawn(async move {
             loop {
                 match tcp_to_l2cap_receive.recv().await {
                     Ok(packet) => {
+                        let data_len = match &packet {
+                            Packet::Data { data, .. } => data.len(),
+                            Packet::Control { .. } => 0,
+                        };
                         let serialized_packet = match packet.serialize() {
                             Ok(serialized_packet) => serialized_packet,
                             Err(e) => {
                                 error!("Error serializing packet; dropping packet: {e}");
+                                outstanding_bytes.fetch_sub(data_len, Relaxed);
                                 continue;
                             }
                         };
+                        let serialized_packet = match &mut encryptor {
+                            Some(encryptor) => match encryptor.encrypt(&serialized_packet) {
+                                Ok(frames) => frames,
+                                Err(e) => {
+                                    error!("Error encrypting packet; dropping packet: {e}");
+                                    outstanding_bytes.fetch_sub(data_len, Relaxed);
+                                    continue;
+                                }
+                            },
+                            None => serialized_packet,
+                        };
 
-                        if let Err(e) = l2cap_stream_write.write_all(&serialized_packet).await {
+                        let write_result = l2cap_stream_write.write_all(&serialized_packet).await;
+                        outstanding_bytes.fetch_sub(data_len, Relaxed);
+                        if let Err(e) = write_result {
                             error!("Error writing to L2CAP stream; dropping packet: {e}");
                             continue;
                         }
@@ -398,12 +559,156 @@ impl L2CAPStreamMux {
             while let Some(task) = self.tasks.pop() {
                 task.abort();
             }
+            for task in self.reverse_tasks.lock().unwrap().drain(..) {
+                task.abort();
+            }
             self.stopped = true;
             info!("Multiplexer stopped");
         }
     }
 }
 
+/// Spawns a task that reads from `tcp_stream_read` (the TCP stream multiplexed on `port`) into
+/// `tcp_to_l2cap_send`, sending a close control packet once the TCP stream closes.
+fn spawn_tcp_reader(
+    port: u16,
+    mut tcp_stream_read: ReadHalf<TcpStream>,
+    tcp_to_l2cap_send: Sender<Packet>,
+    outstanding_bytes: Arc<AtomicUsize>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            // TODO: use a non-arbitrary cap here.
+            let mut data = vec![0u8; 1024];
+            let n = match tcp_stream_read.read(&mut data).await {
+                Ok(n) if n > 0 => n,
+                Ok(_) => {
+                    debug!("TCP stream closed for 'port' {port}");
+                    // Send a close control packet.
+                    send_close(&tcp_to_l2cap_send, port).await;
+                    break;
+                }
+                Err(e) => {
+                    info!("Could not read from TCP stream (likely closed); closing for 'port' {port}: {e}");
+                    // Send a close control packet.
+                    send_close(&tcp_to_l2cap_send, port).await;
+                    break;
+                }
+            };
+
+            // Truncate message.
+            data.truncate(n);
+            debug!(
+                "Writing data packet for 'port' {port} from TCP stream of length {}...",
+                data.len()
+            );
+            trace!("Data in packet to be written is {:?}", data);
+
+            let data_len = data.len();
+            outstanding_bytes.fetch_add(data_len, Relaxed);
+            let data_packet = Packet::Data { port, data };
+            if let Err(e) = tcp_to_l2cap_send.send(data_packet).await {
+                error!("Error sending data packet to 'tcp_to_l2cap_send' channel; dropping data packet: {e}");
+                outstanding_bytes.fetch_sub(data_len, Relaxed);
+                continue;
+            }
+        }
+    })
+}
+
+/// Tasks connecting reverse streams or reading from their TCP streams, aborted when the mux
+/// stops. Shared between the mux, the `pipe_out_tcp` task and the tasks connecting reverse
+/// streams.
+type ReverseTasks = Arc<Mutex<Vec<JoinHandle<()>>>>;
+
+/// Adds `task` to `reverse_tasks`, dropping the handles of tasks that have finished so that a
+/// long-lived mux does not accumulate them.
+fn track_reverse_task(reverse_tasks: &ReverseTasks, task: JoinHandle<()>) {
+    let mut reverse_tasks = reverse_tasks.lock().unwrap();
+    reverse_tasks.retain(|task| !task.is_finished());
+    reverse_tasks.push(task);
+}
+
+/// Reverse streams whose TCP stream is being connected, with the bytes received for them
+/// meanwhile. Shared between the `pipe_out_tcp` task and the tasks connecting them.
+type ConnectingReverse = Arc<Mutex<HashMap<u16, Vec<u8>>>>;
+
+/// Spawns a task that connects reverse stream `port` to local `target_port` and, once connected,
+/// writes what was buffered for it in `connecting` and moves it to `port_to_tcp_stream`. Holding
+/// the `connecting` lock while moving it keeps its data in order. If the mobile device closes the
+/// stream meanwhile (removing it from `connecting`), the TCP stream is dropped.
+#[allow(clippy::too_many_arguments)]
+fn spawn_reverse_connect(
+    port: u16,
+    target_port: u16,
+    reverse_targets: ReverseTargets,
+    connecting: ConnectingReverse,
+    port_to_tcp_stream: Arc<DashMap<u16, MuxedTCPStream>>,
+    tcp_to_l2cap_send: Sender<Packet>,
+    outstanding_bytes: Arc<AtomicUsize>,
+    reverse_tasks: ReverseTasks,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let tcp_stream = match reverse_targets.connect(target_port).await {
+            Ok(tcp_stream) => tcp_stream,
+            Err(e) => {
+                warn!("Could not open reverse stream on 'port' {port}: {e}");
+                if connecting.lock().unwrap().remove(&port).is_some() {
+                    send_close(&tcp_to_l2cap_send, port).await;
+                }
+                return;
+            }
+        };
+
+        let (tcp_stream_read, mut tcp_stream_write) = tokio::io::split(tcp_stream);
+        loop {
+            let buffered = {
+                let mut connecting = connecting.lock().unwrap();
+                match connecting.get_mut(&port) {
+                    None => {
+                        debug!("Reverse stream on 'port' {port} closed while connecting; dropping TCP stream");
+                        return;
+                    }
+                    Some(buffered) if buffered.is_empty() => {
+                        connecting.remove(&port);
+                        port_to_tcp_stream.insert(
+                            port,
+                            MuxedTCPStream {
+                                writer: tcp_stream_write,
+                                reply_bytes_to_skip: 0,
+                                connect_reply: None,
+                            },
+                        );
+                        break;
+                    }
+                    Some(buffered) => std::mem::take(buffered),
+                }
+            };
+            if let Err(e) = tcp_stream_write.write_all(&buffered).await {
+                info!("Could not write to TCP stream for 'port' {port} (stream may be closed); dropping data packet: {e}");
+            }
+        }
+        info!("Opened reverse stream on 'port' {port} to local port {target_port}");
+
+        let reader = spawn_tcp_reader(port, tcp_stream_read, tcp_to_l2cap_send, outstanding_bytes);
+        track_reverse_task(&reverse_tasks, reader);
+    })
+}
+
+/// Sends a close control packet for `port` to `tcp_to_l2cap_send`.
+async fn send_close(tcp_to_l2cap_send: &Sender<Packet>, port: u16) {
+    let control_packet = match Packet::control_socket_closed(port) {
+        Ok(control_packet) => control_packet,
+        Err(e) => {
+            error!("Could not create 'close' control packet for 'port' {port}: {e}");
+            return;
+        }
+    };
+    if let Err(e) = tcp_to_l2cap_send.send(control_packet).await {
+        error!("Could not send 'close' control packet for 'port' {port}: {e}");
+    }
+}
+
 impl Drop for L2CAPStreamMux {
     fn drop(&mut self) {
         self.stop();
@@ -444,7 +749,7 @@ impl Packet {
             };
             let msg_type = msg_type_byte[0];
             if msg_type == 0 {
-                return Ok(Self::keepalive()?);
+                return Self::keepalive();
             }
             if msg_type != 1 {
                 return Err(anyhow!("do not know how to handle 'msg_type' {msg_type}"));
@@ -468,11 +773,10 @@ impl Packet {
 
             match status {
                 0 => {
-                    return Ok(Self::control_socket_closed(for_port)?);
+                    return Self::control_socket_closed(for_port);
                 }
                 1 => {
-                    error!("Did not expect to receive an 'open' request from this side");
-                    return Ok(Self::control_socket_open(for_port)?);
+                    return Self::control_socket_open(for_port);
                 }
                 _ => {
                     return Err(anyhow!(
@@ -606,4 +910,34 @@ impl Packet {
 struct MuxedTCPStream {
     // ReadHalf is owned by thread in `add_socket`.
     writer: WriteHalf<TcpStream>,
+    // Number of bytes received from the L2CAP stream yet to be dropped instead of written.
+    reply_bytes_to_skip: usize,
+    // Reply to a CONNECT request sent on the TCP stream's behalf, to be dropped instead of written
+    // once complete.
+    connect_reply: Option<ConnectReply>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn finished_reverse_tasks_are_dropped() {
+        let reverse_tasks = ReverseTasks::default();
+        let finished = tokio::spawn(async {});
+        while !finished.is_finished() {
+            tokio::task::yield_now().await;
+        }
+        track_reverse_task(&reverse_tasks, finished);
+        let running = tokio::spawn(std::future::pending());
+        track_reverse_task(&reverse_tasks, running);
+        assert_eq!(reverse_tasks.lock().unwrap().len(), 1);
+
+        let running = tokio::spawn(std::future::pending());
+        track_reverse_task(&reverse_tasks, running);
+        assert_eq!(reverse_tasks.lock().unwrap().len(), 2);
+        for task in reverse_tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
 }
@@ -0,0 +1,296 @@
+//! Defines optional end-to-end encryption of the multiplexed L2CAP stream with the Noise protocol
+//! framework (http://noiseprotocol.org/noise.html), independent of the BLE link's security.
+//!
+//! Before the multiplexer starts, the forwarder (as initiator) runs a `NNpsk0` handshake with the
+//! mobile device. The pre-shared key is SHA-256 of the machine part secret, so only a mobile
+//! device provisioned with that machine's secret can complete it. Afterwards every chunk of the
+//! stream is sent as a frame: a 2-byte big-endian length followed by a ciphertext of that length
+//! (the encrypted and authenticated chunk.) Handshake messages use the same framing.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use byteorder::{BigEndian, ByteOrder};
+use log::{debug, info};
+use sha2::{Digest, Sha256};
+use snow::{Builder, StatelessTransportState};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{timeout, Duration};
+
+/// Noise protocol used for the handshake and transport.
+const NOISE_PARAMS: &str = "Noise_NNpsk0_25519_ChaChaPoly_SHA256";
+
+/// Maximum length of a Noise message (and so of a frame's ciphertext.)
+const MAX_MESSAGE_LEN: usize = 65535;
+
+/// Length of the authentication tag appended to each ciphertext.
+const TAG_LEN: usize = 16;
+
+/// Maximum number of plaintext bytes carried in one frame.
+const MAX_PLAINTEXT_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+
+/// Length of the length prefix of each frame.
+const FRAME_HEADER_LEN: usize = 2;
+
+/// How long to wait for the mobile device to answer the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs the handshake as initiator over `stream` with a pre-shared key derived from
+/// `machine_part_secret`, and returns the encryptor and decryptor for the rest of the stream.
+pub(crate) async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    machine_part_secret: &str,
+) -> Result<(FrameEncryptor, FrameDecryptor)> {
+    timeout(
+        HANDSHAKE_TIMEOUT,
+        run_handshake(stream, machine_part_secret),
+    )
+    .await
+    .map_err(|_| anyhow!("no Noise handshake response after {HANDSHAKE_TIMEOUT:?}"))?
+}
+
+async fn run_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    machine_part_secret: &str,
+) -> Result<(FrameEncryptor, FrameDecryptor)> {
+    let psk = Sha256::digest(machine_part_secret.as_bytes());
+    let mut initiator = Builder::new(NOISE_PARAMS.parse()?)
+        .psk(0, &psk)
+        .build_initiator()?;
+    let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+
+    // -> psk, e
+    let len = initiator.write_message(&[], &mut buf)?;
+    write_frame(stream, &buf[..len]).await?;
+    debug!("Sent Noise handshake initiation");
+
+    // <- e, ee
+    let response = read_frame(stream).await?;
+    initiator
+        .read_message(&response, &mut buf)
+        .map_err(|e| anyhow!("invalid Noise handshake response (wrong machine secret?): {e}"))?;
+    info!("Completed Noise handshake; multiplexed stream is encrypted");
+
+    let transport = Arc::new(initiator.into_stateless_transport_mode()?);
+    Ok((
+        FrameEncryptor {
+            transport: transport.clone(),
+            nonce: 0,
+        },
+        FrameDecryptor {
+            transport,
+            nonce: 0,
+            buf: Vec::new(),
+        },
+    ))
+}
+
+/// Writes `message` to `stream` as one frame.
+async fn write_frame<S: AsyncWrite + Unpin>(stream: &mut S, message: &[u8]) -> Result<()> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    BigEndian::write_u16(&mut header, message.len() as u16);
+    stream.write_all(&header).await?;
+    stream.write_all(message).await?;
+    Ok(())
+}
+
+/// Reads one frame from `stream` and returns its message.
+async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    stream.read_exact(&mut header).await?;
+    let mut message = vec![0u8; BigEndian::read_u16(&header) as usize];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+/// Encrypts data written to the L2CAP stream.
+pub(crate) struct FrameEncryptor {
+    transport: Arc<StatelessTransportState>,
+    // Nonce of the next frame to send.
+    nonce: u64,
+}
+
+impl FrameEncryptor {
+    /// Returns `plaintext` encrypted into as many frames as needed.
+    pub(crate) fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut frames = Vec::with_capacity(plaintext.len() + FRAME_HEADER_LEN + TAG_LEN);
+        let mut ciphertext = vec![0u8; MAX_MESSAGE_LEN];
+        for chunk in plaintext.chunks(MAX_PLAINTEXT_LEN) {
+            let len = self
+                .transport
+                .write_message(self.nonce, chunk, &mut ciphertext)?;
+            self.nonce += 1;
+
+            let mut header = [0u8; FRAME_HEADER_LEN];
+            BigEndian::write_u16(&mut header, len as u16);
+            frames.extend_from_slice(&header);
+            frames.extend_from_slice(&ciphertext[..len]);
+        }
+        Ok(frames)
+    }
+}
+
+/// Decrypts data read from the L2CAP stream.
+pub(crate) struct FrameDecryptor {
+    transport: Arc<StatelessTransportState>,
+    // Nonce of the next frame expected.
+    nonce: u64,
+    // Bytes read that do not yet make up a complete frame.
+    buf: Vec<u8>,
+}
+
+impl FrameDecryptor {
+    /// Takes bytes read from the L2CAP stream and returns the plaintext of every frame they
+    /// complete (possibly none.) Returns an error if any frame fails authentication.
+    pub(crate) fn decrypt(&mut self, bytes: &[u8]) -> Result<Vec<u8>> {
+        self.buf.extend_from_slice(bytes);
+
+        let mut plaintext = Vec::new();
+        let mut payload = vec![0u8; MAX_MESSAGE_LEN];
+        let mut consumed = 0;
+        while self.buf.len() - consumed >= FRAME_HEADER_LEN {
+            let frame_len =
+                BigEndian::read_u16(&self.buf[consumed..consumed + FRAME_HEADER_LEN]) as usize;
+            let frame_end = consumed + FRAME_HEADER_LEN + frame_len;
+            if self.buf.len() < frame_end {
+                break;
+            }
+            let len = self
+                .transport
+                .read_message(
+                    self.nonce,
+                    &self.buf[consumed + FRAME_HEADER_LEN..frame_end],
+                    &mut payload,
+                )
+                .map_err(|e| anyhow!("could not decrypt frame {}: {e}", self.nonce))?;
+            self.nonce += 1;
+            plaintext.extend_from_slice(&payload[..len]);
+            consumed = frame_end;
+        }
+        self.buf.drain(..consumed);
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    /// Runs the handshake as responder (as the mobile device does) over `stream` with a pre-shared
+    /// key derived from `machine_part_secret`.
+    async fn respond<S: AsyncRead + AsyncWrite + Unpin>(
+        stream: &mut S,
+        machine_part_secret: &str,
+    ) -> Result<(FrameEncryptor, FrameDecryptor)> {
+        let psk = Sha256::digest(machine_part_secret.as_bytes());
+        let mut responder = Builder::new(NOISE_PARAMS.parse()?)
+            .psk(0, &psk)
+            .build_responder()?;
+        let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+        let initiation = read_frame(stream).await?;
+        responder.read_message(&initiation, &mut buf)?;
+        let len = responder.write_message(&[], &mut buf)?;
+        write_frame(stream, &buf[..len]).await?;
+
+        let transport = Arc::new(responder.into_stateless_transport_mode()?);
+        Ok((
+            FrameEncryptor {
+                transport: transport.clone(),
+                nonce: 0,
+            },
+            FrameDecryptor {
+                transport,
+                nonce: 0,
+                buf: Vec::new(),
+            },
+        ))
+    }
+
+    /// Returns the initiator's and responder's encryptors and decryptors after a handshake with
+    /// `initiator_secret` and `responder_secret` respectively.
+    async fn handshakes(
+        initiator_secret: &str,
+        responder_secret: &str,
+    ) -> (
+        Result<(FrameEncryptor, FrameDecryptor)>,
+        Result<(FrameEncryptor, FrameDecryptor)>,
+    ) {
+        let (mut initiator_stream, mut responder_stream) = duplex(MAX_MESSAGE_LEN);
+        let responder = async move {
+            let result = respond(&mut responder_stream, responder_secret).await;
+            // Closes the stream so that the initiator does not wait for a response that is not
+            // coming.
+            drop(responder_stream);
+            result
+        };
+        tokio::join!(
+            handshake(&mut initiator_stream, initiator_secret),
+            responder
+        )
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip_in_both_directions() {
+        let (initiator, responder) = handshakes("secret", "secret").await;
+        let (mut initiator_encryptor, mut initiator_decryptor) = initiator.unwrap();
+        let (mut responder_encryptor, mut responder_decryptor) = responder.unwrap();
+
+        let frames = initiator_encryptor.encrypt(b"hello").unwrap();
+        assert_eq!(frames.len(), FRAME_HEADER_LEN + 5 + TAG_LEN);
+        assert_eq!(responder_decryptor.decrypt(&frames).unwrap(), b"hello");
+
+        let frames = responder_encryptor.encrypt(b"world").unwrap();
+        assert_eq!(initiator_decryptor.decrypt(&frames).unwrap(), b"world");
+    }
+
+    #[tokio::test]
+    async fn frames_are_decrypted_as_they_complete() {
+        let (initiator, responder) = handshakes("secret", "secret").await;
+        let (mut encryptor, _) = initiator.unwrap();
+        let (_, mut decryptor) = responder.unwrap();
+
+        // Spans more than one frame.
+        let plaintext: Vec<u8> = (0..MAX_PLAINTEXT_LEN + 100).map(|i| i as u8).collect();
+        let mut frames = encryptor.encrypt(&plaintext).unwrap();
+        frames.extend(encryptor.encrypt(b"more").unwrap());
+
+        let mut decrypted = Vec::new();
+        for chunk in frames.chunks(1000) {
+            decrypted.extend(decryptor.decrypt(chunk).unwrap());
+        }
+        assert_eq!(decrypted[..plaintext.len()], plaintext);
+        assert_eq!(&decrypted[plaintext.len()..], b"more");
+        assert!(decryptor.buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn handshake_with_another_secret_fails() {
+        let (initiator, responder) = handshakes("secret", "other").await;
+        assert!(initiator.is_err());
+        assert!(responder.is_err());
+    }
+
+    #[tokio::test]
+    async fn tampered_frame_fails_authentication() {
+        let (initiator, responder) = handshakes("secret", "secret").await;
+        let (mut encryptor, _) = initiator.unwrap();
+        let (_, mut decryptor) = responder.unwrap();
+
+        let mut frames = encryptor.encrypt(b"hello").unwrap();
+        frames[FRAME_HEADER_LEN] ^= 1;
+        assert!(decryptor.decrypt(&frames).is_err());
+    }
+
+    #[tokio::test]
+    async fn replayed_frame_fails_authentication() {
+        let (initiator, responder) = handshakes("secret", "secret").await;
+        let (mut encryptor, _) = initiator.unwrap();
+        let (_, mut decryptor) = responder.unwrap();
+
+        let frames = encryptor.encrypt(b"hello").unwrap();
+        assert_eq!(decryptor.decrypt(&frames).unwrap(), b"hello");
+        assert!(decryptor.decrypt(&frames).is_err());
+    }
+}
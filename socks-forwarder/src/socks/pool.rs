@@ -0,0 +1,256 @@
+//! Defines a pool of multiplexers (one per connected mobile device) that new TCP streams are
+//! spread across.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Result};
+use futures::future::select_all;
+use log::{debug, info, warn};
+use tokio::{
+    net::TcpStream,
+    time::{timeout, Duration},
+};
+
+use super::handshake::{self, Preamble};
+use super::mux::L2CAPStreamMux;
+
+/// How long to wait for a local client to send its SOCKS greeting and request when balancing by
+/// destination.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How new TCP streams are spread across the multiplexers in a pool.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum LoadBalancing {
+    /// Each new TCP stream goes to the next multiplexer in turn.
+    #[default]
+    RoundRobin,
+    /// Each new TCP stream goes to the multiplexer with the fewest bytes waiting to be written to
+    /// its L2CAP stream.
+    LeastOutstandingBytes,
+    /// Each new TCP stream goes to a multiplexer chosen by a hash of its SOCKS destination, so
+    /// that connections to the same destination share a mobile device.
+    DestinationHash,
+}
+
+impl FromStr for LoadBalancing {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "round-robin" => Ok(Self::RoundRobin),
+            "least-outstanding-bytes" => Ok(Self::LeastOutstandingBytes),
+            "destination-hash" => Ok(Self::DestinationHash),
+            _ => Err(anyhow!(
+                "unknown load balancing strategy \"{s}\"; expected \"round-robin\", \"least-outstanding-bytes\" or \"destination-hash\""
+            )),
+        }
+    }
+}
+
+/// A new TCP stream whose SOCKS handshake has been read (see `peek_destination`.)
+pub(crate) struct PeekedTcpStream {
+    stream: TcpStream,
+    // The handshake read, to be sent on once the TCP stream is multiplexed.
+    preamble: Preamble,
+    destination: Option<String>,
+}
+
+/// Reads the SOCKS greeting and request from `stream` for balancing by destination. Returns
+/// `None` (dropping the TCP stream) if no valid handshake is received within
+/// `HANDSHAKE_TIMEOUT`. Slow clients should only delay themselves, so this is meant to run in a
+/// task of its own.
+pub(crate) async fn peek_destination(mut stream: TcpStream) -> Option<PeekedTcpStream> {
+    let read = handshake::read_destination(&mut stream);
+    match timeout(HANDSHAKE_TIMEOUT, read).await {
+        Ok(Ok((preamble, destination))) => Some(PeekedTcpStream {
+            stream,
+            preamble,
+            destination,
+        }),
+        Ok(Err(e)) => {
+            warn!("Could not read SOCKS handshake from TCP stream; dropping TCP stream: {e}");
+            None
+        }
+        Err(_) => {
+            warn!("No SOCKS handshake from TCP stream after {HANDSHAKE_TIMEOUT:?}; dropping TCP stream");
+            None
+        }
+    }
+}
+
+/// A multiplexer in a pool and the address of the mobile device it is connected to.
+struct PooledMux {
+    address: bluer::Address,
+    mux: L2CAPStreamMux,
+}
+
+/// A pool of multiplexers that new TCP streams are spread across.
+pub(crate) struct L2CAPStreamMuxPool {
+    muxes: Vec<PooledMux>,
+    load_balancing: LoadBalancing,
+    // Index of the next multiplexer to use for round-robin balancing.
+    next: usize,
+}
+
+impl L2CAPStreamMuxPool {
+    /// Creates an empty pool that balances with `load_balancing`.
+    pub(crate) fn new(load_balancing: LoadBalancing) -> Self {
+        L2CAPStreamMuxPool {
+            muxes: Vec::new(),
+            load_balancing,
+            next: 0,
+        }
+    }
+
+    /// Adds a started multiplexer connected to the mobile device at `address` to the pool.
+    pub(crate) fn add_mux(&mut self, address: bluer::Address, mux: L2CAPStreamMux) {
+        self.muxes.push(PooledMux { address, mux });
+    }
+
+    /// Returns the number of multiplexers in the pool.
+    pub(crate) fn len(&self) -> usize {
+        self.muxes.len()
+    }
+
+    /// Returns the number of TCP streams currently multiplexed across the pool.
+    pub(crate) fn client_count(&self) -> usize {
+        self.muxes
+            .iter()
+            .map(|pooled| pooled.mux.client_count())
+            .sum()
+    }
+
+    /// Returns whether new TCP streams must have their SOCKS handshake peeked at (see
+    /// `peek_destination`) and be added with `add_peeked_tcp_stream` rather than
+    /// `add_tcp_stream`.
+    pub(crate) fn balances_by_destination(&self) -> bool {
+        self.load_balancing == LoadBalancing::DestinationHash && self.muxes.len() > 1
+    }
+
+    /// Incorporates a new TCP stream into one of the multiplexers in the pool.
+    pub(crate) async fn add_tcp_stream(&mut self, stream: TcpStream) -> Result<()> {
+        if self.muxes.is_empty() {
+            return Err(anyhow!("no multiplexers in pool"));
+        }
+        // Only balance with more than one choice.
+        if self.muxes.len() == 1 {
+            return self.muxes[0].mux.add_tcp_stream(stream, None).await;
+        }
+
+        let idx = match self.load_balancing {
+            LoadBalancing::RoundRobin => self.next_round_robin(),
+            LoadBalancing::LeastOutstandingBytes => self.least_outstanding_bytes(),
+            // Only reached if the pool grew since the caller checked `balances_by_destination`.
+            LoadBalancing::DestinationHash => self.next_round_robin(),
+        };
+
+        let pooled = &mut self.muxes[idx];
+        debug!(
+            "Adding TCP stream to multiplexer for mobile device {}",
+            pooled.address
+        );
+        pooled.mux.add_tcp_stream(stream, None).await
+    }
+
+    /// Incorporates a new TCP stream whose SOCKS handshake was peeked at into the multiplexer
+    /// chosen by a hash of its destination.
+    pub(crate) async fn add_peeked_tcp_stream(&mut self, peeked: PeekedTcpStream) -> Result<()> {
+        if self.muxes.is_empty() {
+            return Err(anyhow!("no multiplexers in pool"));
+        }
+        let idx = match &peeked.destination {
+            Some(destination) => self.destination_hash(destination),
+            None => self.next_round_robin(),
+        };
+        debug!(
+            "Balancing TCP stream to {:?} by destination hash",
+            peeked.destination
+        );
+
+        let pooled = &mut self.muxes[idx];
+        debug!(
+            "Adding TCP stream to multiplexer for mobile device {}",
+            pooled.address
+        );
+        pooled
+            .mux
+            .add_tcp_stream(peeked.stream, Some(peeked.preamble))
+            .await
+    }
+
+    /// Incorporates a new TCP stream from a static forward to `destination` into one of the
+    /// multiplexers in the pool, sending `preamble` to connect it.
+    pub(crate) async fn add_forwarded_tcp_stream(
+        &mut self,
+        stream: TcpStream,
+        preamble: Preamble,
+        destination: &str,
+    ) -> Result<()> {
+        if self.muxes.is_empty() {
+            return Err(anyhow!("no multiplexers in pool"));
+        }
+        let idx = match self.load_balancing {
+            LoadBalancing::RoundRobin => self.next_round_robin(),
+            LoadBalancing::LeastOutstandingBytes => self.least_outstanding_bytes(),
+            LoadBalancing::DestinationHash => self.destination_hash(destination),
+        };
+
+        let pooled = &mut self.muxes[idx];
+        debug!(
+            "Adding TCP stream forwarded to {destination} to multiplexer for mobile device {}",
+            pooled.address
+        );
+        pooled.mux.add_tcp_stream(stream, Some(preamble)).await
+    }
+
+    /// Waits for any multiplexer in the pool to stop due to L2CAP disconnection and removes it
+    /// from the pool. Returns the address of the mobile device that disconnected. Never returns
+    /// if the pool is empty.
+    pub(crate) async fn wait_for_stop_due_to_disconnect(&mut self) -> bluer::Address {
+        if self.muxes.is_empty() {
+            return futures::future::pending().await;
+        }
+
+        let (_, idx, _) = select_all(
+            self.muxes
+                .iter_mut()
+                .map(|pooled| Box::pin(pooled.mux.wait_for_stop_due_to_disconnect())),
+        )
+        .await;
+        let pooled = self.muxes.remove(idx);
+        info!(
+            "Removed multiplexer for mobile device {} from pool; {} remaining",
+            pooled.address,
+            self.muxes.len()
+        );
+        pooled.address
+    }
+
+    /// Returns the index of the multiplexer with the fewest outstanding bytes.
+    fn least_outstanding_bytes(&self) -> usize {
+        self.muxes
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, pooled)| pooled.mux.outstanding_bytes())
+            .map(|(idx, _)| idx)
+            .unwrap_or_default()
+    }
+
+    /// Returns the index of the multiplexer chosen by a hash of `destination`.
+    fn destination_hash(&self, destination: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        destination.hash(&mut hasher);
+        (hasher.finish() % self.muxes.len() as u64) as usize
+    }
+
+    /// Returns the index of the next multiplexer to use for round-robin balancing.
+    fn next_round_robin(&mut self) -> usize {
+        let idx = self.next % self.muxes.len();
+        self.next = self.next.wrapping_add(1);
+        idx
+    }
+}
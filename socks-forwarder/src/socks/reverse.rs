@@ -0,0 +1,129 @@
+//! Defines reverse streams: TCP connections that the mobile device opens over the multiplexed
+//! L2CAP stream into local services on this machine (e.g. viam-server or an SSH daemon), so that
+//! they can be reached without any network.
+//!
+//! The mobile device opens a reverse stream with an open control packet for a "port" of at least
+//! `FIRST_REVERSE_PORT` (ports below it are assigned by this side.) The first 2 bytes of data on
+//! the stream are the local TCP port to connect to (little-endian), which must be one of the
+//! configured target ports; the rest is forwarded to the connection. If the connection cannot be
+//! made, the stream is closed with a close control packet.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use log::info;
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+
+use crate::config::Values;
+use crate::env::REVERSE_PORTS_ENV_VAR;
+
+/// Lowest "port" the mobile device may open a reverse stream on.
+pub(crate) const FIRST_REVERSE_PORT: u16 = 0x8000;
+
+/// Number of bytes at the start of a reverse stream that select its target port.
+pub(crate) const TARGET_PORT_LEN: usize = 2;
+
+/// How long to wait for a local service to accept a reverse stream's connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Local TCP ports the mobile device may open reverse streams to. Cheap to clone.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ReverseTargets(Arc<Vec<u16>>);
+
+impl ReverseTargets {
+    /// Reads the target ports from `REVERSE_PORTS_ENV_VAR` (comma-separated; defaults to none,
+    /// which rejects all reverse streams.) Fails on an invalid port.
+    pub(crate) fn from_values(values: &Values) -> Result<Self> {
+        let ports = values
+            .list(REVERSE_PORTS_ENV_VAR)
+            .iter()
+            .map(|port| match port.parse::<u16>() {
+                Ok(port) if port != 0 => Ok(port),
+                _ => Err(anyhow!(
+                    "invalid port \"{port}\" in {REVERSE_PORTS_ENV_VAR}; expected an integer from 1 to 65535"
+                )),
+            })
+            .collect::<Result<Vec<u16>>>()?;
+        Ok(ReverseTargets(Arc::new(ports)))
+    }
+
+    /// Logs the target ports, if any.
+    pub(crate) fn log(&self) {
+        if !self.0.is_empty() {
+            info!(
+                "Mobile devices may open reverse streams to local port(s) {:?}",
+                self.0
+            );
+        }
+    }
+
+    /// Returns whether the mobile device may open a reverse stream on `port` at all.
+    pub(crate) fn accepts(&self, port: u16) -> bool {
+        !self.0.is_empty() && port >= FIRST_REVERSE_PORT
+    }
+
+    /// Connects to local `target_port` for a reverse stream, if it is a configured target.
+    pub(crate) async fn connect(&self, target_port: u16) -> Result<TcpStream> {
+        if !self.0.contains(&target_port) {
+            return Err(anyhow!(
+                "local port {target_port} is not a reverse stream target"
+            ));
+        }
+        let address = format!("127.0.0.1:{target_port}");
+        match timeout(CONNECT_TIMEOUT, TcpStream::connect(&address)).await {
+            Ok(Ok(stream)) => Ok(stream),
+            Ok(Err(e)) => Err(anyhow!("could not connect to {address}: {e}")),
+            Err(_) => Err(anyhow!(
+                "could not connect to {address} within {CONNECT_TIMEOUT:?}"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Source;
+    use tokio::net::TcpListener;
+
+    fn reverse_targets(ports: &str) -> Result<ReverseTargets> {
+        let mut values = Values::default();
+        values.insert(REVERSE_PORTS_ENV_VAR, ports.to_string(), Source::Cli);
+        ReverseTargets::from_values(&values)
+    }
+
+    #[test]
+    fn reverse_ports_are_parsed() {
+        assert_eq!(*reverse_targets("8080, 22,").unwrap().0, vec![8080, 22]);
+        assert!(ReverseTargets::from_values(&Values::default())
+            .unwrap()
+            .0
+            .is_empty());
+    }
+
+    #[test]
+    fn invalid_reverse_ports_are_rejected() {
+        for ports in ["0", "65536", "-1", "ssh", "22,x"] {
+            assert!(reverse_targets(ports).is_err(), "{ports}");
+        }
+    }
+
+    #[test]
+    fn reverse_streams_are_accepted_from_first_reverse_port_with_targets() {
+        let targets = reverse_targets("22").unwrap();
+        assert!(!targets.accepts(FIRST_REVERSE_PORT - 1));
+        assert!(targets.accepts(FIRST_REVERSE_PORT));
+        assert!(targets.accepts(u16::MAX));
+        assert!(!ReverseTargets::default().accepts(FIRST_REVERSE_PORT));
+    }
+
+    #[tokio::test]
+    async fn connects_only_to_targets() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let targets = reverse_targets(&port.to_string()).unwrap();
+        assert!(targets.connect(port).await.is_ok());
+        assert!(targets.connect(port.wrapping_add(1)).await.is_err());
+    }
+}
@@ -0,0 +1,387 @@
+//! Defines a small on-disk store of state that should survive restarts (mobile devices that have
+//! been served, their last PSMs, and their recent successes and failures.)
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use bluer::Address;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+/// Path to the state file.
+const STATE_FP: &str = "/var/lib/socks-forwarder/state.json";
+
+/// Number of consecutive failures after which a mobile device is no longer eligible for failover
+/// (until it registers again by writing its name.)
+const MAX_FAILOVER_FAILURES: u32 = 10;
+
+/// Persisted state of the SOCKS forwarder.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    /// Mobile devices that have registered with this forwarder, in no particular order.
+    #[serde(default)]
+    pub mobile_devices: Vec<MobileDeviceRecord>,
+}
+
+/// What is known about a mobile device that has registered with this forwarder.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MobileDeviceRecord {
+    /// Name the mobile device wrote to the mobile device name characteristic.
+    pub name: String,
+    /// Last address at which the mobile device was found.
+    pub address: Address,
+    /// Last PSM the mobile device was listening for L2CAP connections on.
+    pub last_psm: Option<u16>,
+    /// Last time (in seconds since the Unix epoch) a bridge was established with the mobile
+    /// device.
+    pub last_success: Option<u64>,
+    /// Number of failures to establish a bridge with the mobile device since the last success.
+    #[serde(default)]
+    pub failure_count: u32,
+}
+
+impl State {
+    /// Loads state from `path`. A missing or corrupted state file results in empty state.
+    pub async fn load(path: &Path) -> Self {
+        let contents = match fs::read(path).await {
+            Ok(contents) => contents,
+            Err(e) => {
+                debug!("Could not read state from {path:#?} ({e}); starting with empty state");
+                return Self::default();
+            }
+        };
+        match serde_json::from_slice(&contents) {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Contents of {path:#?} are not valid state ({e}); starting with empty state");
+                Self::default()
+            }
+        }
+    }
+
+    /// Atomically writes state to `path` (see `write_atomically`.)
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        write_atomically(path, &serde_json::to_vec_pretty(self)?).await
+    }
+
+    /// Saves state to `path` and logs (rather than returns) any error.
+    pub async fn save_or_warn(&self, path: &Path) {
+        if let Err(e) = self.save(path).await {
+            warn!("Could not save state to {path:#?}: {e}");
+        }
+    }
+
+    /// Returns names of registered mobile devices eligible for failover, most recently served
+    /// first. `previous` (the mobile device a bridge was just lost with) is left out unless it is
+    /// the only eligible mobile device.
+    pub fn failover_candidates(&self, previous: Option<&str>) -> Vec<String> {
+        let mut eligible: Vec<&MobileDeviceRecord> = self
+            .mobile_devices
+            .iter()
+            .filter(|record| record.failure_count < MAX_FAILOVER_FAILURES)
+            .collect();
+        eligible.sort_by_key(|record| std::cmp::Reverse(record.last_success));
+
+        let mut candidates: Vec<String> = eligible.iter().map(|r| r.name.clone()).collect();
+        if candidates.len() > 1 {
+            if let Some(previous) = previous {
+                candidates.retain(|name| name != previous);
+            }
+        }
+        candidates
+    }
+
+    /// Returns the address mobile device `name` was last found at, if it is known.
+    pub fn address(&self, name: &str) -> Option<Address> {
+        self.mobile_devices
+            .iter()
+            .find(|r| r.name == name)
+            .map(|r| r.address)
+    }
+
+    /// Returns whether a registered mobile device was last found at `address`.
+    pub fn is_registered(&self, address: Address) -> bool {
+        self.mobile_devices.iter().any(|r| r.address == address)
+    }
+
+    /// Records that a bridge was established with mobile device `name` at `address` on `psm`.
+    pub fn record_success(&mut self, name: &str, address: Address, psm: u16) {
+        let record = self.mobile_device_mut(name, address);
+        record.address = address;
+        record.last_psm = Some(psm);
+        record.last_success = Some(now_secs());
+        record.failure_count = 0;
+    }
+
+    /// Records that mobile device `name` at `address` registered by writing its name, which makes
+    /// it eligible for failover again.
+    pub fn record_registration(&mut self, name: &str, address: Address) {
+        let record = self.mobile_device_mut(name, address);
+        record.address = address;
+        record.failure_count = 0;
+    }
+
+    /// Records a failure to establish or keep a bridge with mobile device `name`.
+    pub fn record_failure(&mut self, name: &str) {
+        if let Some(record) = self.mobile_devices.iter_mut().find(|r| r.name == name) {
+            record.failure_count = record.failure_count.saturating_add(1);
+        }
+    }
+
+    /// Records a failure to establish or keep a bridge with the mobile device last found at
+    /// `address`, if any.
+    pub fn record_failure_at(&mut self, address: Address) {
+        for record in self
+            .mobile_devices
+            .iter_mut()
+            .filter(|r| r.address == address)
+        {
+            record.failure_count = record.failure_count.saturating_add(1);
+        }
+    }
+
+    /// Forgets any mobile device last found at `address`. Returns whether one was forgotten.
+    pub fn forget(&mut self, address: Address) -> bool {
+        let len = self.mobile_devices.len();
+        self.mobile_devices.retain(|r| r.address != address);
+        self.mobile_devices.len() != len
+    }
+
+    /// Forgets all mobile devices.
+    pub fn forget_all(&mut self) {
+        self.mobile_devices.clear();
+    }
+
+    /// Returns the record for mobile device `name`, creating one at `address` if none exists.
+    fn mobile_device_mut(&mut self, name: &str, address: Address) -> &mut MobileDeviceRecord {
+        let idx = match self.mobile_devices.iter().position(|r| r.name == name) {
+            Some(idx) => idx,
+            None => {
+                self.mobile_devices.push(MobileDeviceRecord {
+                    name: name.to_string(),
+                    address,
+                    last_psm: None,
+                    last_success: None,
+                    failure_count: 0,
+                });
+                self.mobile_devices.len() - 1
+            }
+        };
+        &mut self.mobile_devices[idx]
+    }
+}
+
+/// State shared between the main loop and the control socket, so that either can change it
+/// without the other overwriting the change.
+#[derive(Clone, Debug)]
+pub struct Store {
+    state: Arc<Mutex<State>>,
+    path: Arc<PathBuf>,
+    // Serializes saves, which all write to the same temporary file.
+    save_lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Store {
+    /// Loads state from `STATE_FP` (see `State::load`.)
+    pub async fn load() -> Self {
+        Self::load_from(Path::new(STATE_FP)).await
+    }
+
+    /// Loads state from `path`, to which it is also saved (see `State::load`.)
+    pub async fn load_from(path: &Path) -> Self {
+        Store {
+            state: Arc::new(Mutex::new(State::load(path).await)),
+            path: Arc::new(path.to_path_buf()),
+            save_lock: Default::default(),
+        }
+    }
+
+    /// Locks the state. The guard must not be held across an await.
+    pub fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Saves a snapshot of the state (see `State::save_or_warn`.)
+    pub async fn save_or_warn(&self) {
+        let _save_guard = self.save_lock.lock().await;
+        let snapshot = self.lock().clone();
+        snapshot.save_or_warn(&self.path).await;
+    }
+}
+
+/// Atomically writes `contents` to `path` through a temporary file next to it; the file is either
+/// fully replaced or left as it was, even across power loss.
+pub async fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut tmp_path = OsString::from(path);
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    fs::create_dir_all(dir).await?;
+    let mut tmp_file = fs::File::create(&tmp_path).await?;
+    tmp_file.write_all(contents).await?;
+    tmp_file.sync_all().await?;
+    drop(tmp_file);
+
+    fs::rename(&tmp_path, path).await?;
+    // Sync the directory so that the rename itself is durable.
+    fs::File::open(dir)
+        .await?
+        .sync_all()
+        .await
+        .map_err(|e| anyhow!("could not sync {dir:#?}: {e}"))
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: Address = Address::new([0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+
+    fn record(name: &str, last_success: Option<u64>, failure_count: u32) -> MobileDeviceRecord {
+        MobileDeviceRecord {
+            name: name.to_string(),
+            address: Address::any(),
+            last_psm: None,
+            last_success,
+            failure_count,
+        }
+    }
+
+    #[test]
+    fn failover_candidates_are_most_recently_served_first() {
+        let state = State {
+            mobile_devices: vec![
+                record("never", None, 0),
+                record("old", Some(100), 0),
+                record("recent", Some(200), 0),
+            ],
+        };
+        assert_eq!(
+            state.failover_candidates(None),
+            vec!["recent", "old", "never"]
+        );
+    }
+
+    #[test]
+    fn failover_candidates_leave_out_previous_unless_it_is_the_only_one() {
+        let state = State {
+            mobile_devices: vec![record("a", Some(200), 0), record("b", Some(100), 0)],
+        };
+        assert_eq!(state.failover_candidates(Some("a")), vec!["b"]);
+        assert_eq!(state.failover_candidates(Some("c")), vec!["a", "b"]);
+
+        let state = State {
+            mobile_devices: vec![record("a", Some(200), 0)],
+        };
+        assert_eq!(state.failover_candidates(Some("a")), vec!["a"]);
+    }
+
+    #[test]
+    fn failover_candidates_leave_out_repeatedly_failing_mobile_devices() {
+        let mut state = State {
+            mobile_devices: vec![
+                record("failing", Some(200), MAX_FAILOVER_FAILURES - 1),
+                record("healthy", Some(100), 0),
+            ],
+        };
+        assert_eq!(state.failover_candidates(None), vec!["failing", "healthy"]);
+        state.record_failure("failing");
+        assert_eq!(state.failover_candidates(None), vec!["healthy"]);
+        // The lost mobile device is still tried when no other one is eligible.
+        assert_eq!(state.failover_candidates(Some("healthy")), vec!["healthy"]);
+
+        state.record_registration("failing", Address::any());
+        assert_eq!(state.failover_candidates(None), vec!["failing", "healthy"]);
+    }
+
+    #[tokio::test]
+    async fn state_round_trips_through_the_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("state.json");
+        let mut state = State::default();
+        state.record_success("phone", ADDRESS, 0x80);
+        state.record_registration("tablet", Address::any());
+        state.record_failure("tablet");
+        state.save(&path).await.unwrap();
+        assert!(!dir.path().join("state").join("state.json.tmp").exists());
+
+        let loaded = State::load(&path).await;
+        assert_eq!(loaded.mobile_devices.len(), 2);
+        let phone = &loaded.mobile_devices[0];
+        assert_eq!(
+            (phone.name.as_str(), phone.address, phone.last_psm),
+            ("phone", ADDRESS, Some(0x80))
+        );
+        assert!(phone.last_success.is_some());
+        let tablet = &loaded.mobile_devices[1];
+        assert_eq!((tablet.name.as_str(), tablet.failure_count), ("tablet", 1));
+        assert_eq!(tablet.last_success, None);
+    }
+
+    #[tokio::test]
+    async fn missing_state_file_is_empty_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = State::load(&dir.path().join("state.json")).await;
+        assert!(state.mobile_devices.is_empty());
+    }
+
+    #[tokio::test]
+    async fn corrupted_state_file_is_empty_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let mut state = State::default();
+        state.record_success("phone", ADDRESS, 0x80);
+        state.save(&path).await.unwrap();
+
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &contents[..contents.len() / 2]).unwrap();
+        assert!(State::load(&path).await.mobile_devices.is_empty());
+
+        std::fs::write(&path, b"not json").unwrap();
+        assert!(State::load(&path).await.mobile_devices.is_empty());
+    }
+
+    #[tokio::test]
+    async fn store_saves_to_its_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let store = Store::load_from(&path).await;
+        store.lock().record_success("phone", ADDRESS, 0x80);
+        store.save_or_warn().await;
+
+        let reloaded = Store::load_from(&path).await;
+        assert_eq!(reloaded.lock().address("phone"), Some(ADDRESS));
+    }
+
+    #[test]
+    fn failures_are_recorded_by_address() {
+        let mut state = State::default();
+        state.record_success("phone", ADDRESS, 0x80);
+        state.record_registration("tablet", Address::any());
+        state.record_failure_at(ADDRESS);
+        state.record_failure_at(Address::new([1; 6]));
+        let failure_counts: Vec<u32> = state
+            .mobile_devices
+            .iter()
+            .map(|record| record.failure_count)
+            .collect();
+        assert_eq!(failure_counts, vec![1, 0]);
+    }
+}
@@ -0,0 +1,120 @@
+//! Defines the status of the SOCKS forwarder, which is reported to mobile devices through the
+//! status characteristic so that they can show why a bridge is not coming up.
+
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::watch;
+
+/// What the SOCKS forwarder is doing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    /// Not looking for mobile devices (e.g. waiting for the machine to be provisioned.)
+    #[default]
+    Idle,
+    /// Advertising and waiting for a mobile device to write its name.
+    Registering,
+    /// Scanning for a registered mobile device.
+    Scanning,
+    /// Forwarding SOCKS connections through one or more mobile devices.
+    Bridged,
+}
+
+impl State {
+    /// Returns the single byte the state is advertised as.
+    pub fn code(self) -> u8 {
+        match self {
+            State::Idle => 0,
+            State::Registering => 1,
+            State::Scanning => 2,
+            State::Bridged => 3,
+        }
+    }
+}
+
+/// Status of the SOCKS forwarder, serialized as JSON for the status characteristic.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Status {
+    pub version: &'static str,
+    pub state: State,
+    pub last_error: Option<String>,
+    pub client_count: usize,
+    /// RSSI in dBm of the weakest bridged mobile device, if known.
+    pub rssi: Option<i16>,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Status {
+            version: env!("CARGO_PKG_VERSION"),
+            state: State::default(),
+            last_error: None,
+            client_count: 0,
+            rssi: None,
+        }
+    }
+}
+
+impl Status {
+    /// Returns the status as JSON.
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap_or_default()
+    }
+}
+
+/// Shared, observable status of the SOCKS forwarder. Cheap to clone; clones share the status.
+#[derive(Clone, Debug)]
+pub struct StatusHandle(Arc<watch::Sender<Status>>);
+
+impl Default for StatusHandle {
+    fn default() -> Self {
+        StatusHandle(Arc::new(watch::Sender::new(Status::default())))
+    }
+}
+
+impl StatusHandle {
+    /// Returns the current status.
+    pub fn get(&self) -> Status {
+        self.0.borrow().clone()
+    }
+
+    /// Returns a receiver that is notified of every status change.
+    pub fn subscribe(&self) -> watch::Receiver<Status> {
+        self.0.subscribe()
+    }
+
+    /// Sets what the SOCKS forwarder is doing.
+    pub fn set_state(&self, state: State) {
+        self.0
+            .send_if_modified(|status| replace(&mut status.state, state));
+    }
+
+    /// Records the last error the SOCKS forwarder encountered.
+    pub fn set_error(&self, error: impl ToString) {
+        let error = Some(error.to_string());
+        self.0
+            .send_if_modified(|status| replace(&mut status.last_error, error));
+    }
+
+    /// Sets the number of local SOCKS clients being forwarded.
+    pub fn set_client_count(&self, client_count: usize) {
+        self.0
+            .send_if_modified(|status| replace(&mut status.client_count, client_count));
+    }
+
+    /// Sets the RSSI of the weakest bridged mobile device.
+    pub fn set_rssi(&self, rssi: Option<i16>) {
+        self.0
+            .send_if_modified(|status| replace(&mut status.rssi, rssi));
+    }
+}
+
+/// Sets `field` to `value` and returns whether it changed.
+fn replace<T: PartialEq>(field: &mut T, value: T) -> bool {
+    if *field == value {
+        return false;
+    }
+    *field = value;
+    true
+}